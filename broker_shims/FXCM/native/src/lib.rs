@@ -214,16 +214,28 @@ impl Broker for FXCMNative {
                     TradingAction::MarketOrder{symbol, long, size, stop, take_profit, max_range} => {
                         unimplemented!(); // TODO
                     },
+                    TradingAction::MarketOrderPct{symbol, long, pct, stop, take_profit, max_range} => {
+                        unimplemented!(); // TODO
+                    },
+                    TradingAction::MarketOrderQuote{symbol, long, amount, stop, take_profit, max_range} => {
+                        unimplemented!(); // TODO
+                    },
                     TradingAction::ModifyOrder{uuid, size, entry_price, stop, take_profit} => {
                         unimplemented!(); // TODO
                     },
                     TradingAction::CancelOrder{uuid} => {
                         unimplemented!(); // TODO
                     },
-                    TradingAction::MarketClose{uuid, size} => {
+                    TradingAction::MarketClose{uuid, size, reduce_only} => {
                         unimplemented!(); // TODO
                     },
-                    TradingAction::LimitOrder{symbol, long, size, stop, take_profit, entry_price} => {
+                    TradingAction::MarketClosePct{uuid, pct} => {
+                        unimplemented!(); // TODO
+                    },
+                    TradingAction::LimitOrder{symbol, long, size, stop, take_profit, entry_price, time_in_force} => {
+                        unimplemented!(); // TODO
+                    },
+                    TradingAction::PeggedOrder{symbol, long, size, offset} => {
                         unimplemented!(); // TODO
                     },
                     TradingAction::LimitClose{uuid, size, exit_price} => {
@@ -232,6 +244,9 @@ impl Broker for FXCMNative {
                     TradingAction::ModifyPosition{uuid, stop, take_profit} => {
                         unimplemented!(); // TODO
                     },
+                    TradingAction::BackfillOrder{symbol, long, size, stop, take_profit, timestamp} => {
+                        unimplemented!(); // backfilling positions is only possible in simulations
+                    },
                 }
             },
             BrokerAction::GetLedger{account_uuid} => {
@@ -19,6 +19,8 @@ pub struct SimBrokerClient {
     tick_recvs: HashMap<String, (BoxStream<Tick, ()>, Arc<AtomicBool>,)>,
     /// True if the simulation loop has been started
     in_loop: bool,
+    /// Handles to the threads that drive the internal tickstreams and push stream during the simulation
+    threads: Vec<thread::JoinHandle<()>>,
 }
 
 impl Broker for SimBrokerClient {
@@ -53,6 +55,7 @@ impl Broker for SimBrokerClient {
             push_stream_recv: Some((push_stream_recv, Arc::new(AtomicBool::new(false)),)),
             tick_recvs: tick_hm,
             in_loop: false,
+            threads: Vec::new(),
         };
 
         c.complete(Ok(client));
@@ -200,19 +203,19 @@ impl SimBrokerClient {
 
         // thread in which all of the tickstreams are consumed.  This drives them to completion so all of their
         // forks (which have been handed off to clients) are populated with values.
-        thread::spawn(move || {
+        self.threads.push(thread::spawn(move || {
             let tickstreams_comb = tickstream_rx.flatten();
             for _ in tickstreams_comb.wait() {
                 // do nothing; we're just consuming the streams.
             }
-        });
+        }));
 
         // thread in which the push stream is consumed.  This drives it to completion for all clients that took forks of it.
-        thread::spawn(move || {
+        self.threads.push(thread::spawn(move || {
             for _ in tail_pushstream.wait() {
                 // do nothing; we're just consuming the stream;
             }
-        });
+        }));
 
         // set out status to in simulation so that commands get processed into the queue instead of executed immediately
         self.in_loop = true;
@@ -225,6 +228,29 @@ impl SimBrokerClient {
         self.simbroker.tick_sim_loop(num_last_actions, buffer)
     }
 
+    /// Processes exactly `n` events of the inner `SimBroker`'s event loop and returns all outputs sent to the client.
+    pub fn step(&mut self, n: usize) -> Vec<TickOutput> {
+        self.simbroker.step(n)
+    }
+
+    /// Returns `true` if the inner `SimBroker` has run out of events to process.
+    pub fn is_finished(&self) -> bool {
+        self.simbroker.is_finished()
+    }
+
+    /// Shuts down the inner `SimBroker` by closing its tickstreams and push stream and waits for the threads
+    /// driving them to exit.  The forks of the streams held by clients end once they've consumed everything that
+    /// was sent before the shutdown; this blocks until they have.
+    pub fn shutdown(&mut self) {
+        self.simbroker.close_streams();
+        for handle in self.threads.drain(..) {
+            if handle.join().is_err() {
+                self.simbroker.cs.error(None, "A stream thread of the SimBroker panicked before shutdown.");
+            }
+        }
+        self.in_loop = false;
+    }
+
     /// Calls same function on inner `SimBroker`
     pub fn oneshot_price_set(
         &mut self, name: String, price: (usize, usize), is_fx: bool, decimal_precision: usize,
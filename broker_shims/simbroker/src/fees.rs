@@ -0,0 +1,60 @@
+//! Defines the interface through which the SimBroker determines the trading costs charged for positions as well
+//! as the built-in fee models.
+
+use tickgrinder_util::trading::objects::Position;
+
+/// Determines the fees charged for opening, closing, and holding positions.  All fees are in units of the
+/// SimBroker's base currency.
+pub trait FeeModel {
+    /// Returns the fee charged when the supplied position is opened.
+    fn open_fee(&self, pos: &Position) -> usize;
+
+    /// Returns the fee charged when the supplied position is closed.  For partial closes, the supplied position
+    /// only contains the units being closed.
+    fn close_fee(&self, pos: &Position) -> usize;
+
+    /// Returns the fee charged for holding the supplied position for `duration` nanoseconds.
+    fn carry_fee(&self, pos: &Position, duration: u64) -> usize;
+}
+
+/// A fee model that never charges anything.  This is the default.
+pub struct NullFeeModel;
+
+impl FeeModel for NullFeeModel {
+    #[allow(unused_variables)]
+    fn open_fee(&self, pos: &Position) -> usize {
+        0
+    }
+
+    #[allow(unused_variables)]
+    fn close_fee(&self, pos: &Position) -> usize {
+        0
+    }
+
+    #[allow(unused_variables)]
+    fn carry_fee(&self, pos: &Position, duration: u64) -> usize {
+        0
+    }
+}
+
+/// A fee model that charges a fixed commission per unit on both sides of a trade.  This is the default if the
+/// `commission` setting is set.
+pub struct CommissionFeeModel {
+    /// The commission charged per unit in units of base currency
+    pub commission: usize,
+}
+
+impl FeeModel for CommissionFeeModel {
+    fn open_fee(&self, pos: &Position) -> usize {
+        pos.size * self.commission
+    }
+
+    fn close_fee(&self, pos: &Position) -> usize {
+        pos.size * self.commission
+    }
+
+    #[allow(unused_variables)]
+    fn carry_fee(&self, pos: &Position, duration: u64) -> usize {
+        0
+    }
+}
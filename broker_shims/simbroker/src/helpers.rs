@@ -7,6 +7,7 @@ use std::intrinsics::unlikely;
 use std::slice::{Iter, IterMut};
 use std::fmt::{self, Formatter, Debug};
 use std::collections::hash_map;
+use std::str::FromStr;
 
 use futures::{Future, Sink};
 
@@ -29,8 +30,15 @@ pub struct SimBrokerSettings {
     pub ping_ns: u64,
     /// How many nanoseconds between when the broker receives an order and executes it
     pub execution_delay_ns: u64,
-    /// Buying power is leverage * balance
+    /// The ratio of position value to the margin that's reserved for it; buying power is leverage * balance.  The
+    /// part of a position's value that isn't covered by its margin is borrowed and charged `daily_financing_rate`.
+    /// Defaults to 1 so that positions are fully funded by the account and nothing is borrowed unless leverage is
+    /// asked for; the default used to be 50 when leverage only affected financing, so set it explicitly to keep
+    /// financing charges from before margin was reserved at leverage.
     pub leverage: usize,
+    /// The commission in units of base currency charged per unit of a position when it's opened and again per unit
+    /// when it's closed.  Used to create the default fee model; a model set with `set_fee_model` replaces it.
+    pub commission: usize,
     /// Contains the JSON-serialized version of the Vec<(String, TickGenerators)> containing
     /// symbol-gen pairs used to create tickstreams to power the broker.
     pub tickstreams: String,
@@ -39,11 +47,157 @@ pub struct SimBrokerSettings {
     /// Base currency in which the SimBroker is funded.  Should be in the lowest division of that
     /// currency available (e.g. cents).
     pub fx_base_currency: String,
+    /// For forex, the currency that rates are triangulated through when a currency has no pair with the base
+    /// currency.  Empty disables triangulation.
+    pub fx_cross_currency: String,
     /// For forex, the amount of units of currency in one lot.
     pub fx_lot_size: usize,
     /// For forex, if true, calculates accurate position values by dynamically converting to the base
     /// currency.  If false, the rate must be set before broker initialization.
     pub fx_accurate_pricing: bool,
+    /// If true, client actions that are still outstanding when the tickstreams run dry are executed before
+    /// the simulation ends.  If false, they are rejected with an error.
+    pub process_actions_after_end: bool,
+    /// The maximum number of nanoseconds that may pass between the submission of an action and its execution
+    /// before the broker rejects it as stale.  0 disables the check.
+    pub max_action_staleness_ns: u64,
+    /// Contains a JSON-serialized `HashMap<String, usize>` mapping symbols to the canonical decimal precision that
+    /// ticks for them are normalized to when their tickstreams are registered.
+    pub symbol_precisions: String,
+    /// If true, the profit or loss of open positions is realized at the end of every simulated day and their entry
+    /// prices are reset to the settlement price, as with futures.
+    pub daily_settlement: bool,
+    /// The fraction of the borrowed portion of open positions, their value minus the margin required for them at
+    /// `leverage`, that's charged as financing at the end of every simulated day.  0.0 disables financing.
+    pub daily_financing_rate: f64,
+    /// Determines how ticks with a zero bid or ask are handled.  If true, the invalid side is replaced with the
+    /// last valid price of the symbol.  If false, the tick is dropped entirely.
+    pub clamp_invalid_prices: bool,
+    /// How many nanoseconds after a position is filled the client is notified of it, on top of the ping
+    pub confirmation_latency_ns: u64,
+    /// The number of internal units of the base currency in one minor unit of the account currency (e.g. cents).
+    /// Reported realized PnL is rounded to a multiple of this.  1 disables rounding.
+    pub pnl_minor_unit: usize,
+    /// How many nanoseconds of processed ticks each symbol keeps in its history for looking up past prices, as
+    /// is needed for backfilling positions.  0 disables the history.
+    pub tick_history_ns: u64,
+    /// The widest spread in pips at which market opens and closes are executed; wider spreads cause them to be
+    /// rejected.  Can be overridden per symbol.  0 disables the check.
+    pub max_spread: usize,
+    /// The margin level (equity as a percentage of used margin) below which an account's open positions are
+    /// liquidated, largest loss first, until it's back above it.  0 disables margin calls.
+    pub margin_call_level: usize,
+    /// How many nanoseconds an account's margin level may stay below `margin_call_level` before its positions are
+    /// liquidated, giving it a chance to recover
+    pub margin_call_grace_ns: u64,
+    /// The fraction of their value that offsetting long and short positions in the same symbol count towards used
+    /// margin.  1.0 charges the full value of both and 0.0 only charges margin on the net position.
+    pub hedged_margin_factor: f64,
+    /// The number of ticks of a streamed symbol that must be processed before positions in it can be opened.
+    /// 0 disables the check.
+    pub warmup_ticks: usize,
+    /// The number of nanoseconds after the first tick of a streamed symbol before positions in it can be opened.
+    /// 0 disables the check.
+    pub warmup_ns: u64,
+    /// If true, every symbol keeps all of the ticks it has processed in its history regardless of
+    /// `tick_history_ns`.  This can use a lot of memory for long simulations.
+    pub retain_ticks: bool,
+    /// If true, stops and take profits are triggered by prices exactly equal to them.  If false, prices must
+    /// strictly cross them.
+    pub touch_triggers: bool,
+    /// The loss assumed for open positions without a stop when computing the worst-case loss of an account
+    pub stopless_position_risk: usize,
+    /// If true, limit orders that become fillable at a better price than their limit price are filled at the
+    /// market price.  If false, they're always filled at their limit price.
+    pub allow_price_improvement: bool,
+    /// The maximum number of pips the price of a market order may move between its submission and execution
+    /// before the broker rejects it with a requote instead of filling it.  0 disables requotes.
+    pub requote_threshold: usize,
+    /// If true, the unrealized PnL of an account's open positions counts towards the funds available for opening
+    /// new positions.  The part of a position's cost covered by unrealized profit isn't deducted from buying power.
+    pub use_unrealized_for_margin: bool,
+    /// The maximum age of the last price of a pair used to convert between currencies.  Conversions using older
+    /// prices fail instead.  Symbols with static prices never go stale.  0 disables the check.
+    pub max_rate_age_ns: u64,
+    /// The number of events processed by the simulation loop between progress messages, which are logged at debug
+    /// level.  0 disables progress messages.
+    pub progress_interval: u64,
+    /// How sizes computed from a percentage of buying power are snapped to whole lots of a symbol
+    pub lot_rounding: LotRounding,
+    /// The maximum number of open positions and pending orders that an account can have at once.  0 disables the
+    /// limit.
+    pub max_positions: usize,
+    /// The maximum number of pending orders that an account can have resting at once.  Orders that are filled
+    /// immediately count against it too since they're checked before being placed.  0 disables the limit.
+    pub max_pending_orders: usize,
+    /// The maximum number of units of a position that can be filled by a single tick when it's stopped out.  The
+    /// rest is filled by the following ticks at their prices.  0 disables the limit.
+    pub fill_size_per_tick: usize,
+    /// The minimum number of nanoseconds between trading actions from the same account.  Actions received by the
+    /// broker sooner than this after the account's last accepted one are rejected.  0 disables the limit.
+    pub min_order_interval_ns: u64,
+    /// How the delay added to actions by congestion grows with the number of events in the simulation queue when
+    /// they're received
+    pub congestion_scaling: CongestionScaling,
+    /// The number of nanoseconds of congestion delay per unit of scaled queue depth.  0 disables congestion delays.
+    pub congestion_delay_ns: u64,
+    /// The largest market order that can be executed in a symbol with a volume model, as a fraction of the volume
+    /// of its last tick.  Larger orders are rejected.  0.0 disables the check.
+    pub max_liquidity_fraction: f64,
+    /// The seed of the PRNG used to generate UUIDs, making them the same for every run.  0 uses the fuzzer's seed
+    /// if deterministic RNG is enabled in the config and a random seed otherwise.  The seed that was used is
+    /// available from `SimBroker::seed`.
+    pub rng_seed: u32,
+    /// Which side of the market has to reach the price of a limit order for it to be filled
+    pub limit_fill_side: LimitFillSide,
+    /// Contains a JSON-serialized `HashMap<String, i64>` mapping symbols to a number of nanoseconds that's added to
+    /// the timestamps of their ticks when their tickstreams are registered, skewing their clocks against each other.
+    pub clock_skews: String,
+    /// The number of ticks at the start of a tickstream that are inspected to infer its decimal precision when it's
+    /// registered rather than using the supplied one.  See `detect_precision`.  0 disables inference.
+    pub precision_detection_ticks: usize,
+    /// Whether the spreads of processed ticks are taken from the data or replaced with `fixed_spread`
+    pub spread_mode: SpreadMode,
+    /// The spread in pips that ticks are given around their mid prices if `spread_mode` is `Fixed`
+    pub fixed_spread: usize,
+    /// Which slippage model market orders and closes are filled with by default.  A model set with
+    /// `set_slippage_model` replaces it.
+    pub slippage_mode: SlippageMode,
+    /// The slippage in pips of every market fill if `slippage_mode` is `Fixed` or per `slippage_units` units
+    /// filled if it's `Proportional`
+    pub slippage_pips: usize,
+    /// The number of units filled per `slippage_pips` pips of slippage if `slippage_mode` is `Proportional`
+    pub slippage_units: usize,
+    /// If true, positions and orders whose stops and take profits aren't on the correct sides of their entry prices
+    /// and each other are rejected.  If false, they're accepted and their exits fire as soon as prices allow.
+    pub strict_brackets: bool,
+    /// How many nanoseconds of simulation time pass between samples of the equity of every account.  0 disables
+    /// sampling.
+    pub equity_sample_interval_ns: u64,
+    /// If set, equity samples are appended to the file at this path as `timestamp,account_uuid,equity` lines rather
+    /// than kept in memory.  The file is created or truncated when the broker is created.
+    pub equity_curve_file: String,
+    /// The lifecycle events that `Notice` push messages are sent for as a comma-separated list of `start`,
+    /// `halfway`, `end`, and `margin_warning`.  Empty sends none.
+    pub notices: Notices,
+    /// The timestamp that the data is expected to end at, used to determine when the halfway notice is sent.  0
+    /// disables the halfway notice.
+    pub expected_end_timestamp: u64,
+    /// What happens to injected actions that are dated before the current time of the simulation, which indicates
+    /// lookahead bias in the strategy that submitted them
+    pub lookahead_handling: LookaheadHandling,
+    /// If true, push messages generated at the same timestamp are collected and sent as a single `Batch` once the
+    /// simulation moves on to a later timestamp or runs out of events.
+    pub batch_push_messages: bool,
+    /// What happens to modifications of positions whose symbols have no price to validate the new stop and take
+    /// profit against
+    pub unpriced_modify_handling: UnpricedModifyHandling,
+    /// The timestamp after which new positions and orders can't be opened or added to, though existing ones can
+    /// still be modified and closed.  Can be overridden per symbol.  0 disables the cutoff.
+    pub open_cutoff_timestamp: u64,
+    /// If true, partial closes of positions are reported with `PositionPartiallyClosed` messages containing the
+    /// PnL realized on the closed units and the unrealized PnL of the rest instead of `PositionModified`.
+    pub report_partial_closes: bool,
 }
 
 impl Default for SimBrokerSettings {
@@ -54,21 +208,289 @@ impl Default for SimBrokerSettings {
             starting_balance: 50 * 1000 * 100, // $50,000
             ping_ns: 0,
             execution_delay_ns: 0,
-            leverage: 50,
+            leverage: 1,
+            commission: 0,
             tickstreams: tickstreams,
             fx: true,
             fx_base_currency: String::from("USD"),
+            fx_cross_currency: String::new(),
             fx_lot_size: 1000,
             fx_accurate_pricing: false,
+            process_actions_after_end: true,
+            max_action_staleness_ns: 0,
+            symbol_precisions: String::from("{}"),
+            daily_settlement: false,
+            daily_financing_rate: 0.0,
+            clamp_invalid_prices: false,
+            confirmation_latency_ns: 0,
+            pnl_minor_unit: 1,
+            tick_history_ns: 0,
+            max_spread: 0,
+            margin_call_level: 0,
+            margin_call_grace_ns: 0,
+            hedged_margin_factor: 1.0,
+            warmup_ticks: 0,
+            warmup_ns: 0,
+            retain_ticks: false,
+            touch_triggers: true,
+            stopless_position_risk: 0,
+            allow_price_improvement: true,
+            requote_threshold: 0,
+            use_unrealized_for_margin: false,
+            max_rate_age_ns: 0,
+            progress_interval: 100000,
+            lot_rounding: LotRounding::Floor,
+            max_positions: 0,
+            max_pending_orders: 0,
+            fill_size_per_tick: 0,
+            min_order_interval_ns: 0,
+            congestion_scaling: CongestionScaling::Linear,
+            congestion_delay_ns: 0,
+            max_liquidity_fraction: 0.0,
+            rng_seed: 0,
+            limit_fill_side: LimitFillSide::Cross,
+            clock_skews: String::from("{}"),
+            precision_detection_ticks: 0,
+            spread_mode: SpreadMode::Floating,
+            fixed_spread: 0,
+            slippage_mode: SlippageMode::Null,
+            slippage_pips: 0,
+            slippage_units: 1,
+            strict_brackets: true,
+            equity_sample_interval_ns: 0,
+            equity_curve_file: String::new(),
+            notices: Notices::default(),
+            expected_end_timestamp: 0,
+            lookahead_handling: LookaheadHandling::Warn,
+            batch_push_messages: false,
+            unpriced_modify_handling: UnpricedModifyHandling::Allow,
+            open_cutoff_timestamp: 0,
+            report_partial_closes: false,
+        }
+    }
+}
+
+/// Determines how position sizes that aren't a whole number of lots are handled.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum LotRounding {
+    /// Round down to the next whole lot
+    Floor,
+    /// Round to the closest whole lot
+    Nearest,
+    /// Reject the order
+    Reject,
+}
+
+impl FromStr for LotRounding {
+    type Err = ();
+
+    fn from_str(raw: &str) -> Result<LotRounding, ()> {
+        match raw {
+            "floor" => Ok(LotRounding::Floor),
+            "nearest" => Ok(LotRounding::Nearest),
+            "reject" => Ok(LotRounding::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Determines which side of the market has to reach the price of a limit order for it to be filled.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum LimitFillSide {
+    /// The side the order would be filled against has to cross the limit price: the ask for buys and the bid for
+    /// sells.
+    Cross,
+    /// The opposite side has to reach the limit price as it would for a trade to take place there: the bid for buys
+    /// and the ask for sells.
+    Trade,
+}
+
+impl FromStr for LimitFillSide {
+    type Err = ();
+
+    fn from_str(raw: &str) -> Result<LimitFillSide, ()> {
+        match raw {
+            "cross" => Ok(LimitFillSide::Cross),
+            "trade" => Ok(LimitFillSide::Trade),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Determines where the spreads of the prices that positions are opened and closed at come from.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum SpreadMode {
+    /// Use the spread of each tick as it is
+    Floating,
+    /// Replace the spread of each tick with a fixed one centered around its mid price
+    Fixed,
+}
+
+impl FromStr for SpreadMode {
+    type Err = ();
+
+    fn from_str(raw: &str) -> Result<SpreadMode, ()> {
+        match raw {
+            "floating" => Ok(SpreadMode::Floating),
+            "fixed" => Ok(SpreadMode::Fixed),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Determines which of the built-in slippage models market fills are subject to.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum SlippageMode {
+    /// Fill at the quoted price
+    Null,
+    /// Fill every market order `slippage_pips` pips worse than the quoted price
+    Fixed,
+    /// Fill market orders worse than the quoted price in proportion to their size
+    Proportional,
+}
+
+impl FromStr for SlippageMode {
+    type Err = ();
+
+    fn from_str(raw: &str) -> Result<SlippageMode, ()> {
+        match raw {
+            "null" => Ok(SlippageMode::Null),
+            "fixed" => Ok(SlippageMode::Fixed),
+            "proportional" => Ok(SlippageMode::Proportional),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Determines how the broker handles injected actions that are dated before the current time of the simulation.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum LookaheadHandling {
+    /// Log a warning and execute the action anyway
+    Warn,
+    /// Log a warning and reject the action with `LookaheadBias`
+    Reject,
+}
+
+impl FromStr for LookaheadHandling {
+    type Err = ();
+
+    fn from_str(raw: &str) -> Result<LookaheadHandling, ()> {
+        match raw {
+            "warn" => Ok(LookaheadHandling::Warn),
+            "reject" => Ok(LookaheadHandling::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Determines how the broker handles modifications of positions whose symbols don't have a price yet.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum UnpricedModifyHandling {
+    /// Log a warning and apply the modification without validating it
+    Allow,
+    /// Reject the modification with `NoDataAvailable`
+    Reject,
+}
+
+impl FromStr for UnpricedModifyHandling {
+    type Err = ();
+
+    fn from_str(raw: &str) -> Result<UnpricedModifyHandling, ()> {
+        match raw {
+            "allow" => Ok(UnpricedModifyHandling::Allow),
+            "reject" => Ok(UnpricedModifyHandling::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The lifecycle events of a simulation that the broker sends `Notice` push messages for.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Notices {
+    /// The first event of the simulation is processed
+    pub start: bool,
+    /// The simulation reaches the midpoint between its start and `expected_end_timestamp`
+    pub halfway: bool,
+    /// The simulation runs out of events
+    pub end: bool,
+    /// An account falls below the margin call level
+    pub margin_warning: bool,
+}
+
+impl FromStr for Notices {
+    type Err = ();
+
+    fn from_str(raw: &str) -> Result<Notices, ()> {
+        let mut notices = Notices::default();
+        for name in raw.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+            match name {
+                "start" => notices.start = true,
+                "halfway" => notices.halfway = true,
+                "end" => notices.end = true,
+                "margin_warning" => notices.margin_warning = true,
+                _ => return Err(()),
+            }
+        }
+
+        Ok(notices)
+    }
+}
+
+/// Determines how the congestion delay of actions scales with the depth of the simulation queue.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum CongestionScaling {
+    /// Grows in proportion to the queue depth
+    Linear,
+    /// Grows with the square of the queue depth
+    Quadratic,
+    /// Grows with the base-2 logarithm of the queue depth
+    Logarithmic,
+}
+
+impl CongestionScaling {
+    /// Returns the number of units of congestion delay incurred by an action received with `depth` events queued.
+    pub fn scale(&self, depth: usize) -> u64 {
+        let depth = depth as u64;
+        match *self {
+            CongestionScaling::Linear => depth,
+            CongestionScaling::Quadratic => depth * depth,
+            CongestionScaling::Logarithmic => 64 - depth.leading_zeros() as u64,
+        }
+    }
+}
+
+impl FromStr for CongestionScaling {
+    type Err = ();
+
+    fn from_str(raw: &str) -> Result<CongestionScaling, ()> {
+        match raw {
+            "linear" => Ok(CongestionScaling::Linear),
+            "quadratic" => Ok(CongestionScaling::Quadratic),
+            "logarithmic" => Ok(CongestionScaling::Logarithmic),
+            _ => Err(()),
         }
     }
 }
 
 impl SimBrokerSettings {
-    /// Returns the delay in ns for executing a particular `BrokerAction`.
-    pub fn get_delay(&self, action: &BrokerAction) -> u64 {
+    /// Returns the delay in ns for executing a particular `BrokerAction`.  `queue_depth` is the number of events
+    /// in the simulation queue when the action is received and adds a congestion delay scaled by
+    /// `congestion_scaling`.
+    pub fn get_delay(&self, action: &BrokerAction, queue_depth: usize) -> u64 {
         // TODO: implement delays for each of the `BrokerAction`s
-        self.execution_delay_ns
+        self.execution_delay_ns + self.congestion_delay_ns * self.congestion_scaling.scale(queue_depth)
+    }
+
+    /// Returns the tick with its spread replaced by `fixed_spread` around its mid price if `spread_mode` is `Fixed`
+    /// and unchanged otherwise.
+    pub fn apply_spread(&self, tick: Tick) -> Tick {
+        match self.spread_mode {
+            SpreadMode::Floating => tick,
+            SpreadMode::Fixed => {
+                let bid = tick.mid().saturating_sub(self.fixed_spread / 2);
+                Tick {bid: bid, ask: bid + self.fixed_spread, timestamp: tick.timestamp}
+            },
+        }
     }
 }
 
@@ -80,6 +502,114 @@ fn simbroker_settings_hashmap_population() {
     assert_eq!(settings.ping_ns, 2000);
 }
 
+/// The state of a `SimBroker` that `dump_to_file` writes and `restore_from_file` creates a new broker from.
+#[derive(Serialize, Deserialize)]
+pub struct SimBrokerSnapshot {
+    pub settings: SimBrokerSettings,
+    pub timestamp: u64,
+    pub accounts: Vec<Account>,
+    /// The name, metadata, and current price of every symbol
+    pub symbols: Vec<(String, SymbolData, (usize, usize))>,
+    /// Trading statistics for each account that has realized any profit or loss
+    pub stats: HashMap<Uuid, AccountStats>,
+    /// The timestamps at which accounts that are below the margin call level fell below it
+    pub margin_breaches: HashMap<Uuid, u64>,
+    /// Positions whose stops have been triggered but that haven't been completely filled yet
+    pub stop_fills: HashSet<Uuid>,
+    /// Accounts that have had trading disabled
+    pub disabled_accounts: HashSet<Uuid>,
+    /// The offsets in pips of the trigger prices of pegged orders from the market
+    pub pegs: HashMap<Uuid, usize>,
+    /// The profits in pips at which the stops of open positions are moved to their entry prices
+    pub breakevens: HashMap<Uuid, usize>,
+    /// The sizes and prices of the resting exit orders attached to open positions
+    pub limit_closes: HashMap<Uuid, (usize, usize)>,
+}
+
+/// A snapshot of how an account's funds are being used as margin.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MarginSummary {
+    /// Funds reserved as margin by open positions
+    pub used_margin: usize,
+    /// Funds not reserved by open positions or pending orders; the account's buying power plus any margin freed
+    /// up by hedging
+    pub free_margin: usize,
+    /// All funds in the account including reserved ones plus the unrealized PnL of open positions
+    pub equity: i64,
+    /// Equity as a percentage of used margin or `None` if no margin is used
+    pub margin_level: Option<f64>,
+}
+
+/// Statistics about the trading activity of an account.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AccountStats {
+    /// The sum of all realized profit and loss as reported, rounded to the account currency's minor unit
+    pub realized_pnl: i64,
+    /// The number of positions that have been fully closed
+    pub closed_trades: usize,
+    /// Realized PnL that hasn't been reported yet because it's smaller than one minor unit.  It's carried into the
+    /// next realization so that rounding errors don't accumulate.
+    pub pnl_remainder: i64,
+    /// The sum of the slippage in pips of all market orders that have been filled
+    pub total_slippage: u64,
+    /// The number of market orders that have been filled
+    pub market_fills: usize,
+    /// The longest time in nanoseconds that the equity curve has spent below a previous high, measured from the
+    /// sample at the high to the first sample that reached it again or to the latest sample if it hasn't yet
+    pub max_drawdown_duration_ns: u64,
+    /// The timestamp and value of the current high of the equity curve
+    pub equity_high: Option<(u64, i64)>,
+}
+
+impl AccountStats {
+    /// Records a realized profit or loss and returns the amount to report for it: the PnL plus any carried
+    /// remainder, rounded to a multiple of `minor_unit` with `round_to_unit`.
+    pub fn record_pnl(&mut self, pnl: i64, minor_unit: usize, closed_trade: bool) -> i64 {
+        let reported = self.reported_pnl(pnl, minor_unit);
+        self.pnl_remainder += pnl - reported;
+        self.realized_pnl += reported;
+        if closed_trade {
+            self.closed_trades += 1;
+        }
+
+        reported
+    }
+
+    /// Returns the amount that `record_pnl` would report for a realized profit or loss without recording it.
+    pub fn reported_pnl(&self, pnl: i64, minor_unit: usize) -> i64 {
+        round_to_unit(pnl + self.pnl_remainder, minor_unit as i64)
+    }
+
+    /// Records the slippage in pips of a filled market order.
+    pub fn record_slippage(&mut self, slippage: usize) {
+        self.total_slippage += slippage as u64;
+        self.market_fills += 1;
+    }
+
+    /// Records a sample of the account's equity curve, updating the maximum drawdown duration.  Samples must be
+    /// recorded in order of their timestamps.
+    pub fn record_equity(&mut self, timestamp: u64, equity: i64) {
+        match self.equity_high {
+            Some((high_timestamp, high)) if equity < high => {
+                let duration = timestamp - high_timestamp;
+                if duration > self.max_drawdown_duration_ns {
+                    self.max_drawdown_duration_ns = duration;
+                }
+            },
+            _ => self.equity_high = Some((timestamp, equity)),
+        }
+    }
+
+    /// Returns the average slippage in pips of filled market orders or `None` if none have been filled.
+    pub fn average_slippage(&self) -> Option<f64> {
+        if self.market_fills == 0 {
+            None
+        } else {
+            Some(self.total_slippage as f64 / self.market_fills as f64)
+        }
+    }
+}
+
 /// An item to be communicated to the client.
 #[derive(Clone)]
 pub enum TickOutput {
@@ -89,12 +619,27 @@ pub enum TickOutput {
 
 /// Contains metadata about a particular tickstream and the symbol of the ticks
 /// that it holds
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SymbolData {
     /// `true` if the ticks are an exchange rate
     /// The symbol must be six characters like "EURUSD"
     pub is_fx: bool,
     /// Decimal precision of the input ticks
     pub decimal_precision: usize,
+    /// For non-FX symbols, the amount the price is multiplied by to get the notional value of one unit
+    pub contract_multiplier: usize,
+    /// Timestamp ranges `[start, end)` during which trading in the symbol is halted
+    pub halts: Vec<(u64, u64)>,
+    /// Overrides the `max_spread` setting for this symbol if set
+    pub max_spread: Option<usize>,
+    /// `false` if short positions can't be opened in the symbol
+    pub allow_short: bool,
+    /// The number of units in one lot of the symbol; sizes computed by the broker are multiples of this
+    pub lot_step: usize,
+    /// The largest number of units that a single position in the symbol can hold.  0 disables the limit.
+    pub max_position_size: usize,
+    /// Overrides the `open_cutoff_timestamp` setting for this symbol if set
+    pub open_cutoff: Option<u64>,
 }
 
 /// Represents a BrokerAction submitted by a client that's waiting to be processed by
@@ -120,8 +665,9 @@ pub enum WorkUnit {
     NewTick(usize, Tick),
     /// Simulates a Tick arriving at the client
     ClientTick(usize, Tick),
-    /// Simulates an action being processed by the Broker (after processing time).
-    ActionComplete(Complete<BrokerResult>, BrokerAction),
+    /// Simulates an action being processed by the Broker (after processing time).  Market orders submitted while
+    /// requotes are enabled also carry the price that was quoted to the client when they were submitted.
+    ActionComplete(Complete<BrokerResult>, BrokerAction, Option<usize>),
     /// Simulates a message from the broker being received by a client.
     Response(Complete<BrokerResult>, BrokerResult),
     /// A message from the broker without a corresponding action
@@ -147,10 +693,10 @@ impl PartialEq for WorkUnit {
                     _ => false,
                 }
             },
-            WorkUnit::ActionComplete(_, ref self_action) => {
+            WorkUnit::ActionComplete(_, ref self_action, self_quote) => {
                 match *other {
-                    WorkUnit::ActionComplete(_, ref other_action) => {
-                        self_action == other_action
+                    WorkUnit::ActionComplete(_, ref other_action, other_quote) => {
+                        self_action == other_action && self_quote == other_quote
                     },
                     _ => false,
                 }
@@ -184,8 +730,8 @@ impl Debug for WorkUnit {
             WorkUnit::ClientTick(self_ix, self_tick) => {
                 write!(f, "ClientTick({}, {:?})", self_ix, self_tick)
             },
-            WorkUnit::ActionComplete(_, ref self_action) => {
-                write!(f, "ActionComplete(_, {:?})", self_action)
+            WorkUnit::ActionComplete(_, ref self_action, self_quote) => {
+                write!(f, "ActionComplete(_, {:?}, {:?})", self_action, self_quote)
             },
             WorkUnit::Response(_, ref self_res) => {
                 write!(f, "Response(_, {:?})", self_res)
@@ -199,18 +745,41 @@ impl Debug for WorkUnit {
 
 impl Eq for WorkUnit {}
 
+/// Hook into the simulation loop that is notified of every `WorkUnit` as it is popped off of the queue and
+/// before it is processed.  Useful for debugging the exact order in which events occur.
+pub trait SimObserver {
+    /// Called with the timestamp of the event and the `WorkUnit` that is about to be processed.
+    fn observe(&mut self, timestamp: u64, unit: &WorkUnit);
+
+    /// Called with every debug-level message the broker logs to its `CommandServer`, such as progress messages.
+    fn debug(&mut self, _message: &str) {}
+}
+
 /// A timestamped unit of data for the priority queue.
 #[derive(PartialEq, Eq)]
 pub struct QueueItem {
     pub timestamp: u64,
+    /// The order in which the item was pushed into the queue, used to process items with the same timestamp in the
+    /// order they were queued.  Assigned by `SimulationQueue::push`.
+    pub seq: u64,
     pub unit: WorkUnit,
 }
 
+impl QueueItem {
+    /// Creates an item to be pushed into the queue.  Its `seq` is a placeholder until `SimulationQueue::push`
+    /// assigns it.
+    pub fn new(timestamp: u64, unit: WorkUnit) -> QueueItem {
+        QueueItem {
+            timestamp: timestamp,
+            seq: 0,
+            unit: unit,
+        }
+    }
+}
+
 impl PartialOrd for QueueItem {
     fn partial_cmp(&self, other: &QueueItem) -> Option<::std::cmp::Ordering> {
-        // Returns the OPPOSITE of the actual order because the `BinaryHeap` is a MAX-heap and
-        // we want to pop off the events with the smallest timestamps first.
-        Some(other.timestamp.cmp(&self.timestamp))
+        Some(self.cmp(other))
     }
 }
 
@@ -218,7 +787,7 @@ impl Ord for QueueItem {
     fn cmp(&self, other: &Self) -> ::std::cmp::Ordering {
         // Returns the OPPOSITE of the actual order because the `BinaryHeap` is a MAX-heap and
         // we want to pop off the events with the smallest timestamps first.
-        other.timestamp.cmp(&self.timestamp)
+        (other.timestamp, other.seq).cmp(&(self.timestamp, self.seq))
     }
 }
 
@@ -237,6 +806,24 @@ pub struct Symbol {
     pub price: (usize, usize),
     /// The next tick for this stream; used for ordering in SimBroker's internal queue
     pub next_tick: Option<Tick>,
+    /// Ticks that have been processed by the broker, oldest first
+    pub history: Vec<Tick>,
+    /// The number of ticks that have been processed by the broker
+    pub ticks_processed: usize,
+    /// The timestamp of the first tick processed by the broker
+    pub first_tick_time: Option<u64>,
+    /// The timestamp of the last tick processed by the broker
+    pub last_tick_time: Option<u64>,
+    /// Assigns synthetic volumes to processed ticks if set
+    pub volume_gen: Option<VolumeGenerator>,
+    /// The volume assigned to the last processed tick
+    pub last_volume: usize,
+    /// The sum of the volumes assigned to processed ticks
+    pub total_volume: u64,
+    /// The sum of the mid prices of processed ticks multiplied by their volumes
+    pub price_volume: u64,
+    /// `true` if the symbol's ticks arrive in real time through the broker's live tickstreams
+    pub live: bool,
 }
 
 impl Symbol {
@@ -250,9 +837,25 @@ impl Symbol {
             metadata: SymbolData {
                 is_fx: is_fx,
                 decimal_precision: decimals,
+                contract_multiplier: 1,
+                halts: Vec::new(),
+                max_spread: None,
+                allow_short: true,
+                lot_step: 1,
+                max_position_size: 0,
+                open_cutoff: None,
             },
             price: price,
             next_tick: None,
+            history: Vec::new(),
+            ticks_processed: 0,
+            first_tick_time: None,
+            last_tick_time: None,
+            volume_gen: None,
+            last_volume: 0,
+            total_volume: 0,
+            price_volume: 0,
+            live: false,
         }
     }
 
@@ -270,12 +873,39 @@ impl Symbol {
             metadata: SymbolData {
                 is_fx: is_fx,
                 decimal_precision: decimals,
+                contract_multiplier: 1,
+                halts: Vec::new(),
+                max_spread: None,
+                allow_short: true,
+                lot_step: 1,
+                max_position_size: 0,
+                open_cutoff: None,
             },
             price: (0, 0),
             next_tick: Some(future_tick),
+            history: Vec::new(),
+            ticks_processed: 0,
+            first_tick_time: None,
+            last_tick_time: None,
+            volume_gen: None,
+            last_volume: 0,
+            total_volume: 0,
+            price_volume: 0,
+            live: false,
         }
     }
 
+    /// Constructs a new Symbol whose ticks are received in real time rather than read ahead from a stream.  Its
+    /// price is unset until the first of them is processed.
+    pub fn new_live(is_fx: bool, decimals: usize, name: String) -> Symbol {
+        let (client_tx, client_rx) = channel(0);
+        let mut sym = Symbol::new_oneshot((0, 0), is_fx, decimals, name);
+        sym.client_sender = Some(client_tx);
+        sym.client_receiver = Some(client_rx.boxed());
+        sym.live = true;
+        sym
+    }
+
     /// Returns `true` if this symbol is an exchange rate.
     pub fn is_fx(&self) -> bool {
         self.metadata.is_fx
@@ -290,6 +920,65 @@ impl Symbol {
         mem::replace(&mut self.client_sender, Some(new_sender));
     }
 
+    /// Returns `true` if the symbol has received a real price.  Streamed symbols have their price initialized
+    /// to (0, 0) until the first tick for them arrives at the broker.
+    pub fn has_price(&self) -> bool {
+        self.price != (0, 0)
+    }
+
+    /// Returns `true` if trading in the symbol is halted at the supplied timestamp.
+    pub fn is_halted(&self, timestamp: u64) -> bool {
+        self.metadata.halts.iter().any(|&(start, end)| timestamp >= start && timestamp < end)
+    }
+
+    /// Records that a tick has been processed, adding it to the symbol's history and dropping ticks older than
+    /// `history_ns` before it.
+    pub fn record_tick(&mut self, tick: Tick, history_ns: u64) {
+        self.ticks_processed += 1;
+        if self.first_tick_time.is_none() {
+            self.first_tick_time = Some(tick.timestamp);
+        }
+        self.last_tick_time = Some(tick.timestamp);
+        if let Some(ref mut volume_gen) = self.volume_gen {
+            let volume = volume_gen.volume(&tick);
+            self.last_volume = volume;
+            self.total_volume += volume as u64;
+            self.price_volume += ((tick.bid + tick.ask) / 2) as u64 * volume as u64;
+        }
+        if history_ns == 0 {
+            return;
+        }
+
+        self.history.push(tick);
+        let cutoff = tick.timestamp.saturating_sub(history_ns);
+        let expired = self.history.iter().take_while(|t| t.timestamp < cutoff).count();
+        if expired > 0 {
+            self.history.drain(..expired);
+        }
+    }
+
+    /// Returns the volume-weighted average mid price of the processed ticks that were assigned volumes or `None`
+    /// if there is no volume.
+    pub fn vwap(&self) -> Option<usize> {
+        if self.total_volume == 0 {
+            None
+        } else {
+            Some((self.price_volume / self.total_volume) as usize)
+        }
+    }
+
+    /// Returns the (bid, ask) of the symbol as of the supplied timestamp according to its history or `None` if
+    /// the history doesn't reach back that far.
+    pub fn price_at(&self, timestamp: u64) -> Option<(usize, usize)> {
+        if self.history.first().map(|t| t.timestamp > timestamp).unwrap_or(true) {
+            return None;
+        }
+
+        self.history.iter().rev()
+            .find(|t| t.timestamp <= timestamp)
+            .map(|t| (t.bid, t.ask))
+    }
+
     /// Returns (bid, ask, decimal_precision)
     pub fn get_price(&self) -> (usize, usize, usize) {
         (self.price.0, self.price.1, self.metadata.decimal_precision)
@@ -396,6 +1085,10 @@ impl Symbols {
 
     /// Returns the index and `Tick` of the future tick with the smallest timestamp.
     pub fn next_tick(&mut self) -> Option<(usize, Tick)> {
+        if self.data.is_empty() {
+            return None;
+        }
+
         let mtick2;
         let mindex2;
         // because I want you to drop that borrow THIS MUCH
@@ -416,6 +1109,11 @@ impl Symbols {
             mindex2 = mindex;
         }
 
+        // none of the symbols have any ticks left to read ahead
+        if mtick2.is_none() {
+            return None;
+        }
+
         // Get the next future tick for that symbol and return the old one
         let next_future_opt = self.data[mindex2].next().map(|tick_res| tick_res.unwrap()).clone();
         self.data[mindex2].next_tick = next_future_opt;
@@ -429,6 +1127,8 @@ impl Symbols {
 pub struct SimulationQueue {
     /// The `BinaryHeap` itself, forming the core of the priority queue
     pub q: BinaryHeap<QueueItem>,
+    /// The sequence number assigned to the next item pushed into the queue
+    next_seq: u64,
 }
 
 impl SimulationQueue {
@@ -436,6 +1136,7 @@ impl SimulationQueue {
     pub fn new() -> SimulationQueue {
         SimulationQueue {
             q: BinaryHeap::new(),
+            next_seq: 0,
         }
     }
 
@@ -444,16 +1145,22 @@ impl SimulationQueue {
     pub fn init(&mut self, symbols: &mut Symbols) {
         // Add n+1 ticks to the queue where n is the number of symbols in `Symbols`.
         // update min and max values manually
+        // live and oneshot symbols don't have any ticks to read ahead
         for _ in 0..symbols.len() {
-            let (ix, tick) = symbols.next_tick().unwrap();
-            self.push(QueueItem {
-                timestamp: tick.timestamp as u64,
-                unit: WorkUnit::NewTick(ix, tick)
-            });
+            let (ix, tick) = match symbols.next_tick() {
+                Some(next) => next,
+                None => break,
+            };
+            self.push(QueueItem::new(tick.timestamp as u64, WorkUnit::NewTick(ix, tick)));
         }
     }
 
-    pub fn push(&mut self, item: QueueItem) {
+    /// Pushes an item into the queue.  Items with the same timestamp are popped in the order they were pushed, so
+    /// an action that's executed at the same time as a tick is processed after it if the tick was queued first
+    /// and is never skipped because the tick was the last one.
+    pub fn push(&mut self, mut item: QueueItem) {
+        item.seq = self.next_seq;
+        self.next_seq += 1;
         self.q.push(item)
     }
 
@@ -461,13 +1168,15 @@ impl SimulationQueue {
         self.q.pop()
     }
 
+    /// Returns the number of events currently in the queue.
+    pub fn len(&self) -> usize {
+        self.q.len()
+    }
+
     /// Convenience function to push the next future tick into the queue.
     pub fn push_next_tick(&mut self, symbols: &mut Symbols) {
         match symbols.next_tick() {
-            Some((ix, tick)) => self.push(QueueItem {
-                timestamp: tick.timestamp as u64,
-                unit: WorkUnit::NewTick(ix, tick),
-            }),
+            Some((ix, tick)) => self.push(QueueItem::new(tick.timestamp as u64, WorkUnit::NewTick(ix, tick))),
             None => (),
         }
     }
@@ -531,7 +1240,7 @@ impl Accounts {
         self.data.entry(k)
     }
 
-    pub fn get(&mut self, k: &Uuid) -> Option<&Account> {
+    pub fn get(&self, k: &Uuid) -> Option<&Account> {
         self.data.get(k)
     }
 
@@ -649,6 +1358,58 @@ impl Accounts {
     }
 }
 
+/// Adds a possibly negative number of nanoseconds to a timestamp, saturating at the bounds of a `u64`.
+pub fn skew_timestamp(timestamp: u64, skew: i64) -> u64 {
+    if skew < 0 {
+        timestamp.saturating_sub((-skew) as u64)
+    } else {
+        timestamp.saturating_add(skew as u64)
+    }
+}
+
+/// Returns the beta of a series of returns against the returns of a benchmark over the same intervals or `None` if
+/// there are fewer than two returns or the benchmark's returns don't vary.
+pub fn beta(returns: &[f64], benchmark_returns: &[f64]) -> Option<f64> {
+    let n = returns.len();
+    if n < 2 || n != benchmark_returns.len() {
+        return None;
+    }
+
+    let mean = returns.iter().sum::<f64>() / n as f64;
+    let benchmark_mean = benchmark_returns.iter().sum::<f64>() / n as f64;
+    let mut covariance = 0.;
+    let mut variance = 0.;
+    for (r, b) in returns.iter().zip(benchmark_returns.iter()) {
+        covariance += (r - mean) * (b - benchmark_mean);
+        variance += (b - benchmark_mean) * (b - benchmark_mean);
+    }
+    if variance == 0. {
+        return None;
+    }
+
+    Some(covariance / variance)
+}
+
+/// Infers the decimal precision of a symbol from the magnitudes of its ticks' prices, assuming that it's quoted
+/// between 1 and 10 like most currency pairs; 110000 implies 5 decimals, for example.  Returns `None` if there are
+/// no ticks or they imply different precisions.
+pub fn detect_precision(ticks: &[Tick]) -> Option<usize> {
+    let mut detected = None;
+    for tick in ticks {
+        let mid = tick.mid();
+        if mid == 0 {
+            return None;
+        }
+        let decimals = (mid as f64).log10().floor() as usize;
+        match detected {
+            Some(prev) if prev != decimals => return None,
+            _ => detected = Some(decimals),
+        }
+    }
+
+    detected
+}
+
 /// Given a price with a specified decimal precision, converts the price to one with
 /// a different decimal precision, rounding if necessary.
 pub fn convert_decimals(in_price: usize, in_decimals: usize, out_decimals: usize) -> usize {
@@ -663,6 +1424,61 @@ pub fn convert_decimals(in_price: usize, in_decimals: usize, out_decimals: usize
     }
 }
 
+/// Rounds an amount to the nearest multiple of `unit`.  Amounts exactly halfway between two multiples are rounded
+/// to the one that's an even multiple (banker's rounding) so that rounding isn't biased in either direction.
+pub fn round_to_unit(amount: i64, unit: i64) -> i64 {
+    if unit <= 1 {
+        return amount;
+    }
+
+    // floor division so that the remainder is always positive
+    let mut quotient = amount / unit;
+    let mut remainder = amount % unit;
+    if remainder < 0 {
+        quotient -= 1;
+        remainder += unit;
+    }
+    if remainder * 2 > unit || (remainder * 2 == unit && quotient % 2 != 0) {
+        quotient += 1;
+    }
+
+    quotient * unit
+}
+
+/// Returns `true` if a progress message should be logged after the supplied number of events have been processed.
+pub fn progress_due(events_processed: u64, interval: u64) -> bool {
+    interval != 0 && events_processed % interval == 0
+}
+
+/// Adds a profit or loss to an amount of currency, flooring the result at zero.
+pub fn apply_pnl(amount: usize, pnl: i64) -> usize {
+    let res = amount as i64 + pnl;
+    if res < 0 {
+        0
+    } else {
+        res as usize
+    }
+}
+
+/// Returns the stop that a position with a trailing stop should have given the best price it's reached, or `None`
+/// if that wouldn't be an improvement on its current stop.
+pub fn trailed_stop(pos: &Position, best_price: usize) -> Option<usize> {
+    let distance = match pos.trailing_stop {
+        Some(distance) => distance,
+        None => return None,
+    };
+    let stop = if pos.long { best_price.saturating_sub(distance) } else { best_price + distance };
+    match pos.stop {
+        Some(cur_stop) if (pos.long && stop <= cur_stop) || (!pos.long && stop >= cur_stop) => None,
+        _ => Some(stop),
+    }
+}
+
+/// Returns the distance between two prices.
+pub fn abs_diff(a: usize, b: usize) -> usize {
+    if a > b { a - b } else { b - a }
+}
+
 /// Creates a new deterministly random byte given a PRNG source.
 pub fn rand_byte(prng: *mut c_void) -> u8 {
     unsafe { rand_int_range(prng, 0, 255) as u8 }
@@ -19,7 +19,7 @@ extern crate from_hashmap;
 extern crate libc;
 extern crate rand;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
 use std::collections::BinaryHeap;
 use std::sync::{Arc, mpsc};
@@ -27,11 +27,13 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::ops::{Index, IndexMut};
 use std::mem;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
 use libc::c_void;
 
-use futures::{Stream, oneshot, Oneshot, Complete};
+use futures::{Future, Stream, oneshot, Oneshot, Complete};
 use futures::stream::BoxStream;
-use futures::sync::mpsc::{channel, Sender};
+use futures::sync::mpsc::{channel, Sender, UnboundedReceiver};
 use uuid::Uuid;
 use rand::Rng;
 
@@ -47,9 +49,26 @@ mod helpers;
 pub use self::helpers::*;
 mod client;
 pub use self::client::*;
+mod fees;
+pub use self::fees::*;
+mod slippage;
+pub use self::slippage::*;
+mod parallel;
+pub use self::parallel::*;
+mod volume;
+pub use self::volume::*;
 mod superlog;
 use superlog::SuperLogger;
 
+/// The number of nanoseconds in a simulated day
+const NS_PER_DAY: u64 = 24 * 60 * 60 * 1000 * 1000 * 1000;
+/// The number of nanoseconds in an hour
+const NS_PER_HOUR: u64 = NS_PER_DAY / 24;
+/// Size of the buffer that client outputs are collected into for each event processed by `step`
+const STEP_BUFFER_SIZE: usize = 512;
+/// The number of equity samples written to the equity curve file between flushes
+const EQUITY_FLUSH_SAMPLES: usize = 1000;
+
 // link with the libboost_random wrapper
 #[link(name="rand_bindings")]
 extern {
@@ -82,20 +101,108 @@ pub struct SimBroker {
     logger: SuperLogger,
     /// A source of deterministic PRNG to be used to generating Uuids.
     prng: *mut c_void,
+    /// The seed that `prng` was initialized with
+    seed: u32,
+    /// Optional hook that is shown every `WorkUnit` processed by the simulation loop
+    observer: Option<Box<SimObserver>>,
+    /// Determines the fees charged for opening, closing, and holding positions
+    fee_model: Box<FeeModel>,
+    /// Determines how much worse than the quoted price market orders are filled
+    slippage_model: Box<SlippageModel>,
+    /// The canonical decimal precisions of symbols that ticks are normalized to when registered
+    precisions: HashMap<String, usize>,
+    /// The offsets added to the timestamps of the ticks of symbols when registered
+    clock_skews: HashMap<String, i64>,
+    /// Trading statistics for each account that has realized any profit or loss
+    stats: HashMap<Uuid, AccountStats>,
+    /// The timestamps at which accounts that are currently below the margin call level fell below it
+    margin_breaches: HashMap<Uuid, u64>,
+    /// The number of events that have been processed by the simulation loop
+    events_processed: u64,
+    /// Optional callback that is given a view of the broker once the simulation runs out of events
+    on_complete: Option<Box<FnMut(&SimBroker)>>,
+    /// Positions whose stops have been triggered but that haven't been completely filled yet
+    stop_fills: HashSet<Uuid>,
+    /// The time at which the broker last accepted a trading action from each account
+    last_action_times: HashMap<Uuid, u64>,
+    /// All of the live tickstreams merged together, yielding ticks along with the index of their symbol
+    live_ticks: Option<BoxStream<(usize, Tick), ()>>,
+    /// Accounts that have had trading disabled
+    disabled_accounts: HashSet<Uuid>,
+    /// `true` once the client has disconnected, after which no more actions are accepted or ticks are queued
+    disconnected: bool,
+    /// The offsets in pips of the trigger prices of pegged orders from the market
+    pegs: HashMap<Uuid, usize>,
+    /// The profits in pips at which the stops of open positions are automatically moved to their entry prices
+    breakevens: HashMap<Uuid, usize>,
+    /// The sizes and prices of the resting exit orders attached to open positions
+    limit_closes: HashMap<Uuid, (usize, usize)>,
+    /// The sampled `(timestamp, equity)` of every account if they aren't being written to a file
+    equity_curves: HashMap<Uuid, Vec<(u64, i64)>>,
+    /// The file that equity samples are written to if the `equity_curve_file` setting is set
+    equity_writer: Option<BufWriter<File>>,
+    /// The number of equity samples written to `equity_writer` since it was last flushed
+    unflushed_samples: usize,
+    /// The earliest timestamp at which the next equity sample is taken
+    next_equity_sample: u64,
+    /// The timestamp of the first event processed by the simulation loop
+    start_timestamp: Option<u64>,
+    /// Set once the halfway notice has been sent
+    halfway_notice_sent: bool,
+    /// Set once the end notice has been sent
+    end_notice_sent: bool,
+    /// The number of injected actions that have been dated before the current time of the simulation
+    lookahead_violations: usize,
+    /// Push messages held back to be sent in a `Batch` along with the timestamp they were generated at
+    push_batch: Vec<BrokerResult>,
+    /// The timestamp that the messages in `push_batch` were generated at
+    push_batch_timestamp: u64,
 }
 
-// .-.
+// The PRNG behind `prng` is created for and only ever used by a single `SimBroker`, so moving a broker to another
+// thread is fine as long as it's only used from one thread at a time.  The boxed tick iterators, fee model, and
+// observer aren't required to be `Send` though, so `run_parallel` creates each `SimBroker` on the thread that runs it
+// rather than relying on this.
 unsafe impl Send for SimBroker {}
 
 impl SimBroker {
     pub fn new(
         settings: SimBrokerSettings, cs: CommandServer, client_rx: mpsc::Receiver<(BrokerAction, Complete<BrokerResult>)>,
+    ) -> Result<SimBroker, BrokerError> {
+        // try to deserialize the "tickstreams" parameter of the input settings to get a list of tickstreams register
+        let tickstreams: Vec<(String, TickGenerators, bool, usize)> = serde_json::from_str(&settings.tickstreams)
+            .map_err(|_| BrokerError::Message{message: String::from("Unable to deserialize the input tickstreams into a vector!")})?;
+
+        let symbols = Symbols::new(cs.clone());
+        let mut sim = SimBroker::with_symbols(settings, cs, client_rx, symbols)?;
+
+        // create an actual tickstream for each of the definitions and subscribe to all of them
+        for (name, def, is_fx, decimals) in tickstreams {
+            let mut gen: Box<TickGenerator> = def.get();
+            let strm = gen.get_raw().map_err(|s| BrokerError::Message{message: s})?;
+            sim.register_tickstream(name, strm, is_fx, decimals)?;
+        }
+
+        Ok(sim)
+    }
+
+    /// Creates a new `SimBroker` containing the supplied symbols rather than an empty set.  The `tickstreams`
+    /// setting is ignored; more symbols can still be added with `register_tickstream` and `oneshot_price_set`.
+    pub fn with_symbols(
+        settings: SimBrokerSettings, cs: CommandServer, client_rx: mpsc::Receiver<(BrokerAction, Complete<BrokerResult>)>,
+        symbols: Symbols,
     ) -> Result<SimBroker, BrokerError> {
         let logger = SuperLogger::new();
         let mut accounts = Accounts::new(logger.clone());
+        // allocate space for open positions of each of the supplied symbols
+        for _ in 0..symbols.len() {
+            accounts.add_symbol();
+        }
 
-        // set up the deterministicly random data generator if it's enabled in the config
-        let seed: u32 = if CONF.fuzzer_deterministic_rng {
+        // set up the deterministicly random data generator if it's enabled in the settings or config
+        let seed: u32 = if settings.rng_seed != 0 {
+            settings.rng_seed
+        } else if CONF.fuzzer_deterministic_rng {
             let mut sum = 0;
             // convert the seed string into an integer for seeding the fuzzer
             for c in CONF.fuzzer_seed.chars() {
@@ -103,8 +210,9 @@ impl SimBroker {
             }
             sum
         } else {
+            // never generate 0 so that the seed can be passed back in through `rng_seed` to reproduce the run
             let mut rng = rand::thread_rng();
-            rng.gen()
+            rng.gen_range(1, std::u32::MAX)
         };
         let rng = unsafe { init_rng(seed) };
         let uuid = gen_uuid(rng);
@@ -119,14 +227,35 @@ impl SimBroker {
         // TODO: Make sure that 0 is the right buffer size for this channel
         let (client_push_tx, client_push_rx) = channel::<(u64, BrokerResult)>(0);
 
-        // try to deserialize the "tickstreams" parameter of the input settings to get a list of tickstreams register
-        let tickstreams: Vec<(String, TickGenerators, bool, usize)> = serde_json::from_str(&settings.tickstreams)
-            .map_err(|_| BrokerError::Message{message: String::from("Unable to deserialize the input tickstreams into a vector!")})?;
+        let precisions: HashMap<String, usize> = serde_json::from_str(&settings.symbol_precisions)
+            .map_err(|_| BrokerError::Message{message: String::from("Unable to deserialize the input symbol precisions into a map!")})?;
+        let clock_skews: HashMap<String, i64> = serde_json::from_str(&settings.clock_skews)
+            .map_err(|_| BrokerError::Message{message: String::from("Unable to deserialize the input clock skews into a map!")})?;
+        let equity_writer = if settings.equity_curve_file.is_empty() {
+            None
+        } else {
+            let file = File::create(&settings.equity_curve_file)
+                .map_err(|err| BrokerError::Message{message: format!("Unable to create equity curve file: {}", err)})?;
+            Some(BufWriter::new(file))
+        };
+        let fee_model: Box<FeeModel> = if settings.commission == 0 {
+            Box::new(NullFeeModel)
+        } else {
+            Box::new(CommissionFeeModel {commission: settings.commission})
+        };
+        let slippage_model: Box<SlippageModel> = match settings.slippage_mode {
+            SlippageMode::Null => Box::new(NullSlippageModel),
+            SlippageMode::Fixed => Box::new(FixedSlippageModel {pips: settings.slippage_pips}),
+            SlippageMode::Proportional => Box::new(ProportionalSlippageModel {
+                pips: settings.slippage_pips,
+                units: settings.slippage_units,
+            }),
+        };
 
-        let mut sim = SimBroker {
+        Ok(SimBroker {
             accounts: accounts,
             settings: settings,
-            symbols: Symbols::new(cs.clone()),
+            symbols: symbols,
             pq: SimulationQueue::new(),
             timestamp: 0,
             client_rx: Some(client_rx),
@@ -135,16 +264,35 @@ impl SimBroker {
             cs: cs,
             logger: logger,
             prng: rng,
-        };
-
-        // create an actual tickstream for each of the definitions and subscribe to all of them
-        for (name, def, is_fx, decimals) in tickstreams {
-            let mut gen: Box<TickGenerator> = def.get();
-            let strm = gen.get_raw().map_err(|s| BrokerError::Message{message: s})?;
-            sim.register_tickstream(name, strm, is_fx, decimals)?;
-        }
-
-        Ok(sim)
+            seed: seed,
+            observer: None,
+            fee_model: fee_model,
+            slippage_model: slippage_model,
+            precisions: precisions,
+            clock_skews: clock_skews,
+            stats: HashMap::new(),
+            margin_breaches: HashMap::new(),
+            events_processed: 0,
+            on_complete: None,
+            stop_fills: HashSet::new(),
+            last_action_times: HashMap::new(),
+            live_ticks: None,
+            disabled_accounts: HashSet::new(),
+            disconnected: false,
+            pegs: HashMap::new(),
+            breakevens: HashMap::new(),
+            limit_closes: HashMap::new(),
+            equity_curves: HashMap::new(),
+            equity_writer: equity_writer,
+            unflushed_samples: 0,
+            next_equity_sample: 0,
+            start_timestamp: None,
+            halfway_notice_sent: false,
+            end_notice_sent: false,
+            lookahead_violations: 0,
+            push_batch: Vec::new(),
+            push_batch_timestamp: 0,
+        })
     }
 
     /// Starts the simulation process.  Ticks are read in from the inputs and processed internally into
@@ -159,62 +307,144 @@ impl SimBroker {
         // initialize the internal queue with values from attached tickstreams
         // all tickstreams should be added by this point
         self.pq.init(&mut self.symbols);
-        self.cs.debug(None, "Internal simulation queue has been initialized.");
+        self.debug_log("Internal simulation queue has been initialized.");
         self.logger.event_log(self.timestamp, "Starting the great simulation loop...");
     }
 
     /// Called by the fuzzer executor to drive progress on the simulation.  Returns the number of client
     /// actions (tickstream ticks + pushstream messages) that were sent to the client during this tick.
+    ///
+    /// If the `batch_push_messages` setting is enabled, push messages are held back until the simulation moves
+    /// past the timestamp they were generated at and then written to the buffer as a single `Batch`.
     pub fn tick_sim_loop(&mut self, num_last_actions: usize, buffer: &mut Vec<TickOutput>) -> usize {
-        // first check if we have any messages from the client to process into the queue
-        { // borrow-b-gone
-            let rx = self.client_rx.as_mut().unwrap();
-            for _ in 0..num_last_actions {
-                // get the next message from the client receiver
-                // println!("Blocking for message from client...");
-                let (action, complete) = rx.recv().expect("Error from client receiver!");
-                // println!("Got message from client: {:?}", action);
-                // determine how long it takes the broker to process this message internally
-                let execution_delay = self.settings.get_delay(&action);
-                // insert this message into the internal queue adding on processing time
-                let qi = QueueItem {
-                    timestamp: self.timestamp + execution_delay,
-                    unit: WorkUnit::ActionComplete(complete, action),
-                };
-                self.logger.event_log(self.timestamp, &format!("Pushing new ActionComplete into pq: {:?}", qi.unit));
-                self.pq.push(qi);
+        if !self.settings.batch_push_messages {
+            return self.process_next_event(num_last_actions, buffer);
+        }
+
+        let count = self.process_next_event(num_last_actions, buffer);
+        let mut outputs = Vec::with_capacity(count + 1);
+        // the event may have advanced the timestamp, completing the batch of the previous one
+        if self.timestamp != self.push_batch_timestamp {
+            outputs.extend(self.flush_push_batch());
+            self.push_batch_timestamp = self.timestamp;
+        }
+        for i in 0..count {
+            match mem::replace(&mut buffer[i], TickOutput::Tick(0, Tick::null())) {
+                TickOutput::Pushstream(_, res) => self.push_batch.push(res),
+                tick => outputs.push(tick),
             }
         }
+        if self.is_finished() {
+            outputs.extend(self.flush_push_batch());
+        }
+
+        let output_count = outputs.len();
+        for (i, output) in outputs.into_iter().enumerate() {
+            buffer[i] = output;
+        }
+        output_count
+    }
+
+    /// Returns a `Batch` of all of the push messages that have been held back, if there are any, and clears them.
+    fn flush_push_batch(&mut self) -> Option<TickOutput> {
+        if self.push_batch.is_empty() {
+            return None;
+        }
+
+        let batch = mem::replace(&mut self.push_batch, Vec::new());
+        let msg = Ok(BrokerMessage::Batch(batch));
+        self.push_msg(msg.clone());
+        Some(TickOutput::Pushstream(self.push_batch_timestamp, msg))
+    }
+
+    /// Processes the next event in the simulation queue, writing everything sent to the client to the buffer.
+    /// Returns the number of outputs written.
+    fn process_next_event(&mut self, num_last_actions: usize, buffer: &mut Vec<TickOutput>) -> usize {
+        // first check if we have any messages from the client to process into the queue
+        for _ in 0..num_last_actions {
+            // get the next message from the client receiver
+            // println!("Blocking for message from client...");
+            let (action, complete) = self.client_rx.as_mut().unwrap().recv().expect("Error from client receiver!");
+            // println!("Got message from client: {:?}", action);
+            self.queue_action(action, complete);
+        }
 
-        if self.timestamp % 100000 == 0 {
-            self.cs.notice(None, &format!("{} ticks processed", self.timestamp));
+        // If the queue is empty, wait for the next tick from the live tickstreams if there are any.  Otherwise, the
+        // tickstreams have run dry and the simulation is over.  Make sure that no client is left waiting on an
+        // action that was submitted but never accounted for before stopping.
+        if self.pq.q.is_empty() && (self.disconnected || !self.wait_live_tick()) {
+            self.drain_client_actions();
+            if self.pq.q.is_empty() {
+                self.run_on_complete();
+                return self.send_end_notice(0, buffer);
+            }
         }
 
         let item = self.pq.pop().unwrap();
+        // settle open positions at the last prices of the day before any events from the next day are processed
+        let days_elapsed = (item.timestamp / NS_PER_DAY).saturating_sub(self.timestamp / NS_PER_DAY);
+        if days_elapsed > 0 {
+            if self.settings.daily_settlement {
+                self.settle_positions();
+            }
+            if self.settings.daily_financing_rate != 0. {
+                self.charge_financing(days_elapsed);
+            }
+        }
         self.timestamp = item.timestamp;
-        let mut client_event_count = 0;
+        let mut client_event_count = self.send_progress_notices(0, buffer);
+
+        let sample_interval = self.settings.equity_sample_interval_ns;
+        if sample_interval != 0 && self.timestamp >= self.next_equity_sample {
+            self.sample_equity();
+            self.next_equity_sample = (self.timestamp / sample_interval + 1) * sample_interval;
+        }
+
+        self.events_processed += 1;
+        if progress_due(self.events_processed, self.settings.progress_interval) {
+            let msg = format!("{} events processed; simulation time is {}", self.events_processed, self.timestamp);
+            self.debug_log(&msg);
+        }
+
+        if let Some(ref mut observer) = self.observer {
+            observer.observe(item.timestamp, &item.unit);
+        }
 
         // then process the new item we took out of the queue
         match item.unit {
             // A tick arriving at the broker.  The client doesn't get to know until after network delay.
             WorkUnit::NewTick(symbol_ix, tick) => {
+                // make sure that the tick's prices are usable before doing anything with it
+                let tick = match self.validate_tick(symbol_ix, tick) {
+                    Some(tick) => tick,
+                    None => {
+                        if !self.symbols[symbol_ix].live && !self.disconnected {
+                            self.pq.push_next_tick(&mut self.symbols);
+                        }
+                        return client_event_count + self.send_end_notice(client_event_count, buffer);
+                    },
+                };
+                let tick = self.settings.apply_spread(tick);
                 // update the price for the popped tick's symbol
                 let price = (tick.bid, tick.ask);
                 self.symbols[symbol_ix].price = price;
+                let history_ns = if self.settings.retain_ticks { u64::max_value() } else { self.settings.tick_history_ns };
+                self.symbols[symbol_ix].record_tick(tick, history_ns);
                 // push the ClientTick event back into the queue + network delay
-                self.pq.push(QueueItem {
-                    timestamp: tick.timestamp as u64 + self.settings.ping_ns,
-                    unit: WorkUnit::ClientTick(symbol_ix, tick),
-                });
+                let client_timestamp = tick.timestamp as u64 + self.settings.ping_ns;
+                self.pq.push(QueueItem::new(client_timestamp, WorkUnit::ClientTick(symbol_ix, tick)));
                 // check to see if we have any actions to take on open positions and take them if we do
                 self.logger.event_log(
                     self.timestamp,
                     &format!("Ticking positions in response to new tick: ({}, {:?})", symbol_ix, tick)
                 );
                 client_event_count += self.tick_positions(symbol_ix, (tick.bid, tick.ask,), client_event_count, buffer);
-                // push the next future tick into the queue
+                // push the next future tick into the queue; live ticks are queued as they arrive instead and no more
+                // are queued once the client has disconnected
                 self.logger.event_log(self.timestamp, &format!("Pushing ClientTick into queue: ({}, {:?})", symbol_ix, tick));
-                self.pq.push_next_tick(&mut self.symbols);
+                if !self.symbols[symbol_ix].live && !self.disconnected {
+                    self.pq.push_next_tick(&mut self.symbols);
+                }
             },
             // A tick arriving at the client.  We now send it down the Client's channels and block
             // until it is consumed.
@@ -230,17 +460,23 @@ impl SimBroker {
             },
             // The moment the broker finishes processing an action and the action takes place.
             // Begins the network delay for the trip back to the client.
-            WorkUnit::ActionComplete(future, action) => {
+            WorkUnit::ActionComplete(future, action, quote) => {
                 // process the message and re-insert the response into the queue
                 assert_eq!(self.timestamp, item.timestamp);
-                let res = self.exec_action(&action);
-                // calculate when the response would be recieved by the client
-                // then re-insert the response into the queue
-                let res_time = item.timestamp + self.settings.ping_ns;
-                let item = QueueItem {
-                    timestamp: res_time,
-                    unit: WorkUnit::Response(future, res),
+                let res = match (quote, self.market_order_price(&action)) {
+                    (Some(quote), Some(price)) if abs_diff(price, quote) > self.settings.requote_threshold => {
+                        self.logger.event_log(self.timestamp, &format!("Requoting market order: {:?}", action));
+                        Err(BrokerError::Requote{price: price})
+                    },
+                    _ => self.exec_action(&action),
                 };
+                // calculate when the response would be recieved by the client
+                // then re-insert the response into the queue.  Fills are confirmed after an extra delay.
+                let mut res_time = item.timestamp + self.settings.ping_ns;
+                if let Ok(BrokerMessage::PositionOpened{..}) = res {
+                    res_time += self.settings.confirmation_latency_ns;
+                }
+                let item = QueueItem::new(res_time, WorkUnit::Response(future, res));
                 self.pq.push(item);
             },
             // The moment a response reaches the client.
@@ -276,7 +512,301 @@ impl SimBroker {
             }
         }
 
-        client_event_count
+        client_event_count + self.send_end_notice(client_event_count, buffer)
+    }
+
+    /// Sends a `Notice` push message with the supplied text to the client.  Returns the number of push messages
+    /// generated.
+    fn send_notice(&mut self, message: String, cur_index: usize, buffer: &mut Vec<TickOutput>) -> usize {
+        let notice = Ok(BrokerMessage::Notice{message: message});
+        self.push_msg(notice.clone());
+        buffer[cur_index] = TickOutput::Pushstream(self.timestamp, notice);
+        1
+    }
+
+    /// Sends the start and halfway notices if they're enabled and due.  Should be called after the timestamp has
+    /// been advanced to that of the event being processed.  Returns the number of push messages generated.
+    fn send_progress_notices(&mut self, cur_index: usize, buffer: &mut Vec<TickOutput>) -> usize {
+        let mut push_msg_count = 0;
+        let start_timestamp = match self.start_timestamp {
+            Some(start_timestamp) => start_timestamp,
+            None => {
+                self.start_timestamp = Some(self.timestamp);
+                if self.settings.notices.start {
+                    let msg = format!("Simulation started at {}", self.timestamp);
+                    push_msg_count += self.send_notice(msg, cur_index, buffer);
+                }
+                self.timestamp
+            },
+        };
+
+        let end_timestamp = self.settings.expected_end_timestamp;
+        if self.settings.notices.halfway && !self.halfway_notice_sent && end_timestamp != 0 &&
+            self.timestamp >= start_timestamp + end_timestamp.saturating_sub(start_timestamp) / 2
+        {
+            self.halfway_notice_sent = true;
+            let msg = format!("Simulation is halfway done at {}", self.timestamp);
+            push_msg_count += self.send_notice(msg, cur_index + push_msg_count, buffer);
+        }
+
+        push_msg_count
+    }
+
+    /// Sends the end notice if it's enabled and the simulation has run out of events.  Returns the number of push
+    /// messages generated.
+    fn send_end_notice(&mut self, cur_index: usize, buffer: &mut Vec<TickOutput>) -> usize {
+        if !self.settings.notices.end || self.end_notice_sent || !self.is_finished() {
+            return 0;
+        }
+
+        self.end_notice_sent = true;
+        let msg = format!("Simulation ended at {}", self.timestamp);
+        self.send_notice(msg, cur_index, buffer)
+    }
+
+    /// Checks a tick that has arrived at the broker for zero prices, which would break margin and PnL calculations.
+    /// Depending on the `clamp_invalid_prices` setting, invalid prices are either replaced with the symbol's last
+    /// valid ones or the tick is dropped.  Returns `None` if the tick should be dropped.
+    fn validate_tick(&mut self, symbol_ix: usize, tick: Tick) -> Option<Tick> {
+        if tick.bid != 0 && tick.ask != 0 {
+            return Some(tick);
+        }
+
+        let ts_string = self.timestamp.to_string();
+        let (last_bid, last_ask) = self.symbols[symbol_ix].price;
+        let clamped = Tick {
+            bid: if tick.bid != 0 { tick.bid } else { last_bid },
+            ask: if tick.ask != 0 { tick.ask } else { last_ask },
+            timestamp: tick.timestamp,
+        };
+        if self.settings.clamp_invalid_prices && clamped.bid != 0 && clamped.ask != 0 {
+            self.cs.warning(
+                Some(&ts_string),
+                &format!("Clamping invalid prices of tick for {}: {:?}", self.symbols[symbol_ix].name, tick)
+            );
+            Some(clamped)
+        } else {
+            self.cs.warning(
+                Some(&ts_string),
+                &format!("Dropping tick for {} with invalid prices: {:?}", self.symbols[symbol_ix].name, tick)
+            );
+            None
+        }
+    }
+
+    /// Inserts an action submitted by the client into the internal queue, adding on the time it takes the broker
+    /// to process it.  If the action would be executed further in the future than `max_action_staleness_ns`
+    /// allows, the broker rejects it at the time it would have been executed instead.
+    fn queue_action(&mut self, action: BrokerAction, complete: Complete<BrokerResult>) {
+        if self.disconnected {
+            self.logger.event_log(self.timestamp, &format!("Rejecting action after disconnect: {:?}", action));
+            complete.complete(Err(BrokerError::SimulationEnded));
+            return;
+        }
+
+        // determine how long it takes the broker to process this message internally
+        let execution_delay = self.settings.get_delay(&action, self.pq.len());
+        let max_staleness = self.settings.max_action_staleness_ns;
+        let qi = if max_staleness != 0 && execution_delay > max_staleness {
+            self.logger.event_log(self.timestamp, &format!("Rejecting stale action: {:?}", action));
+            let err = BrokerError::StaleAction{delay_ns: execution_delay};
+            let res_time = self.timestamp + execution_delay + self.settings.ping_ns;
+            QueueItem::new(res_time, WorkUnit::Response(complete, Err(err)))
+        } else {
+            let quote = if self.settings.requote_threshold != 0 {
+                self.market_order_price(&action)
+            } else {
+                None
+            };
+            QueueItem::new(self.timestamp + execution_delay, WorkUnit::ActionComplete(complete, action, quote))
+        };
+        self.logger.event_log(self.timestamp, &format!("Pushing new ActionComplete into pq: {:?}", qi.unit));
+        self.pq.push(qi);
+    }
+
+    /// Injects an action that the client decided on at `timestamp` into the internal queue.  Actions dated before
+    /// the current time of the simulation could only have been decided on with knowledge of the future, so they're
+    /// logged as lookahead bias and either executed anyway or rejected depending on the `lookahead_handling`
+    /// setting.
+    pub fn queue_action_at(&mut self, timestamp: u64, action: BrokerAction, complete: Complete<BrokerResult>) {
+        if timestamp < self.timestamp {
+            self.lookahead_violations += 1;
+            let ts_string = self.timestamp.to_string();
+            self.cs.warning(
+                Some(&ts_string),
+                &format!("Possible lookahead bias: action dated {} received at {}: {:?}", timestamp, self.timestamp, action)
+            );
+
+            if self.settings.lookahead_handling == LookaheadHandling::Reject {
+                let err = BrokerError::LookaheadBias{action_timestamp: timestamp, current_timestamp: self.timestamp};
+                let res_time = self.timestamp + self.settings.ping_ns;
+                self.pq.push(QueueItem::new(res_time, WorkUnit::Response(complete, Err(err))));
+                return;
+            }
+        }
+
+        self.queue_action(action, complete);
+    }
+
+    /// Returns the number of injected actions that have been dated before the current time of the simulation.
+    pub fn lookahead_violations(&self) -> usize {
+        self.lookahead_violations
+    }
+
+    /// If the supplied action is a market order, returns the price it would be filled at right now.  Returns `None`
+    /// for all other actions or if the order's symbol doesn't have a price yet.
+    fn market_order_price(&self, action: &BrokerAction) -> Option<usize> {
+        match action {
+            &BrokerAction::TradingAction{action: TradingAction::MarketOrder{ref symbol, long, ..}, ..} => {
+                let ix = match self.symbols.get_index(symbol) {
+                    Some(ix) => ix,
+                    None => return None,
+                };
+                match self.get_price(ix) {
+                    Ok((bid, ask)) => Some(if long { ask } else { bid }),
+                    Err(_) => None,
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// Called once the simulation has run out of events.  Takes all actions that the client has submitted but
+    /// that haven't yet been queued and either queues them to be executed or rejects them immediately depending
+    /// on the `process_actions_after_end` setting.
+    fn drain_client_actions(&mut self) {
+        loop {
+            let (action, complete) = match self.client_rx.as_mut().unwrap().try_recv() {
+                Ok(msg) => msg,
+                Err(_) => return,
+            };
+
+            if self.settings.process_actions_after_end {
+                self.queue_action(action, complete);
+            } else {
+                self.logger.event_log(self.timestamp, &format!("Rejecting action after end of simulation: {:?}", action));
+                complete.complete(Err(BrokerError::SimulationEnded));
+            }
+        }
+    }
+
+    /// Processes exactly `n` events from the internal queue, or fewer if the simulation runs out of events first,
+    /// and returns all outputs that were sent to the client while doing so.  State persists between calls, so
+    /// this can be used to step through a simulation event by event.
+    pub fn step(&mut self, n: usize) -> Vec<TickOutput> {
+        let mut buffer = Vec::with_capacity(STEP_BUFFER_SIZE);
+        buffer.resize(STEP_BUFFER_SIZE, TickOutput::Tick(0, Tick::null()));
+        let mut outputs = Vec::new();
+
+        for _ in 0..n {
+            if self.is_finished() {
+                self.drain_client_actions();
+                if self.is_finished() {
+                    self.run_on_complete();
+                    break;
+                }
+            }
+
+            let count = self.tick_sim_loop(0, &mut buffer);
+            outputs.extend_from_slice(&buffer[..count]);
+        }
+
+        outputs
+    }
+
+    /// Registers a callback that is given a read-only view of the broker once the simulation runs out of events,
+    /// replacing any existing one.  It's called at most once.
+    pub fn set_on_complete<F>(&mut self, on_complete: F) where F: FnOnce(&SimBroker) + 'static {
+        let mut on_complete = Some(on_complete);
+        self.on_complete = Some(Box::new(move |sim: &SimBroker| {
+            if let Some(f) = on_complete.take() {
+                f(sim);
+            }
+        }));
+    }
+
+    /// Calls the completion callback if one is registered and hasn't been called yet.
+    fn run_on_complete(&mut self) {
+        if let Some(mut on_complete) = self.on_complete.take() {
+            on_complete(self);
+        }
+    }
+
+    /// Blocks until the next tick arrives from one of the live tickstreams and queues it to be processed.  Returns
+    /// `false` if there are no live tickstreams or all of them have been closed.
+    fn wait_live_tick(&mut self) -> bool {
+        let live_ticks = match self.live_ticks.take() {
+            Some(live_ticks) => live_ticks,
+            None => return false,
+        };
+
+        match live_ticks.into_future().wait() {
+            Ok((Some((ix, tick)), live_ticks)) => {
+                self.live_ticks = Some(live_ticks);
+                // ticks can't be processed before events that have already happened
+                let timestamp = ::std::cmp::max(tick.timestamp, self.timestamp);
+                self.pq.push(QueueItem::new(timestamp, WorkUnit::NewTick(ix, tick)));
+                true
+            },
+            _ => {
+                self.debug_log("All live tickstreams have been closed.");
+                false
+            },
+        }
+    }
+
+    /// Returns the seed that the broker's PRNG was initialized with.  Setting `rng_seed` to it reproduces the run.
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    /// Returns `true` if the simulation has run out of events to process and there are no live tickstreams that
+    /// could yield more or the client has disconnected.
+    pub fn is_finished(&self) -> bool {
+        self.pq.q.is_empty() && (self.live_ticks.is_none() || self.disconnected)
+    }
+
+    /// Closes the streams through which ticks and push messages are sent to the client, ending them once their
+    /// remaining contents have been consumed.  No more messages can be sent to the client afterwards.
+    pub fn close_streams(&mut self) {
+        self.push_stream_handle = None;
+        for sym in self.symbols.iter_mut() {
+            sym.client_sender = None;
+        }
+    }
+
+    /// Sets the `FeeModel` used to determine the fees charged for positions, replacing the existing one.
+    pub fn set_fee_model(&mut self, fee_model: Box<FeeModel>) {
+        self.fee_model = fee_model;
+    }
+
+    /// Sets the `SlippageModel` used to determine the fill prices of market orders, replacing the existing one.
+    pub fn set_slippage_model(&mut self, slippage_model: Box<SlippageModel>) {
+        self.slippage_model = slippage_model;
+    }
+
+    /// Returns the fees charged for closing the supplied position at the current time: the close fee plus
+    /// the carry fee for the time it was held.
+    fn get_close_fees(&self, pos: &Position) -> usize {
+        let held = match pos.execution_time {
+            Some(execution_time) if self.timestamp > execution_time => self.timestamp - execution_time,
+            _ => 0,
+        };
+        self.fee_model.close_fee(pos) + self.fee_model.carry_fee(pos, held)
+    }
+
+    /// Sets a `SimObserver` that will be shown every `WorkUnit` processed by the simulation loop, replacing
+    /// any existing one.
+    pub fn set_observer(&mut self, observer: Box<SimObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Logs a debug-level message to the `CommandServer` and shows it to the observer, if there is one.
+    fn debug_log(&mut self, message: &str) {
+        self.cs.debug(None, message);
+        if let Some(ref mut observer) = self.observer {
+            observer.debug(message);
+        }
     }
 
     /// Immediately sends a message over the broker's push channel.  Should only be called from within
@@ -298,26 +828,57 @@ impl SimBroker {
                 Ok(BrokerMessage::Pong{time_received: self.timestamp})
             },
             &BrokerAction::TradingAction{account_uuid, ref action} => {
-                match action {
+                self.check_rate_limit(account_uuid)?;
+                let res = match action {
                     &TradingAction::MarketOrder{ref symbol, long, size, stop, take_profit, max_range} => {
                         match self.symbols.get_index(symbol) {
                             Some(ix) => self.market_open(account_uuid, ix, long, size, stop, take_profit, max_range),
                             None => Err(BrokerError::NoSuchSymbol),
                         }
                     },
-                    &TradingAction::MarketClose{uuid, size} => {
-                        self.market_close(account_uuid, uuid, size)
+                    &TradingAction::MarketOrderPct{ref symbol, long, pct, stop, take_profit, max_range} => {
+                        match self.symbols.get_index(symbol) {
+                            Some(ix) => match self.pct_size(account_uuid, ix, long, pct) {
+                                Ok(size) => self.market_open(account_uuid, ix, long, size, stop, take_profit, max_range),
+                                Err(err) => Err(err),
+                            },
+                            None => Err(BrokerError::NoSuchSymbol),
+                        }
+                    },
+                    &TradingAction::MarketOrderQuote{ref symbol, long, amount, stop, take_profit, max_range} => {
+                        match self.symbols.get_index(symbol) {
+                            Some(ix) => match self.quote_size(ix, long, amount) {
+                                Ok(size) => self.market_open(account_uuid, ix, long, size, stop, take_profit, max_range),
+                                Err(err) => Err(err),
+                            },
+                            None => Err(BrokerError::NoSuchSymbol),
+                        }
+                    },
+                    &TradingAction::MarketClose{uuid, size, reduce_only} => {
+                        self.market_close(account_uuid, uuid, size, reduce_only)
+                    },
+                    &TradingAction::MarketClosePct{uuid, pct} => {
+                        match self.pct_close_size(account_uuid, uuid, pct) {
+                            Ok(size) => self.market_close(account_uuid, uuid, size, true),
+                            Err(err) => Err(err),
+                        }
+                    },
+                    &TradingAction::LimitOrder{ref symbol, long, size, stop, take_profit, entry_price, time_in_force} => {
+                        match self.symbols.get_index(symbol) {
+                            Some(ix) => self.place_order(
+                                account_uuid, ix, entry_price, long, size, stop, take_profit, time_in_force
+                            ),
+                            None => Err(BrokerError::NoSuchSymbol),
+                        }
                     },
-                    &TradingAction::LimitOrder{ref symbol, long, size, stop, take_profit, entry_price} => {
+                    &TradingAction::PeggedOrder{ref symbol, long, size, offset} => {
                         match self.symbols.get_index(symbol) {
-                            Some(ix) => self.place_order(account_uuid, ix, entry_price, long, size, stop, take_profit),
+                            Some(ix) => self.place_pegged_order(account_uuid, ix, long, size, offset),
                             None => Err(BrokerError::NoSuchSymbol),
                         }
                     },
-                    // no support for partial closes at this time
                     &TradingAction::LimitClose{uuid, size, exit_price} => {
-                        // limit close just means to take profit when we hit a certain price, so just adjust the TP
-                        self.modify_position(account_uuid, uuid, None, Some(Some(exit_price)))
+                        self.limit_close(account_uuid, uuid, size, exit_price)
                     },
                     &TradingAction::ModifyOrder{uuid, size, entry_price, stop, take_profit} => {
                         self.modify_order(account_uuid, uuid, size, entry_price, stop, take_profit)
@@ -326,9 +887,22 @@ impl SimBroker {
                         self.cancel_order(account_uuid, uuid)
                     }
                     &TradingAction::ModifyPosition{uuid, stop, take_profit} => {
+                        self.check_trading_enabled(account_uuid)?;
+                        self.check_modify_prices(account_uuid, uuid, stop, take_profit)?;
                         self.modify_position(account_uuid, uuid, Some(stop), Some(take_profit))
                     },
+                    &TradingAction::BackfillOrder{ref symbol, long, size, stop, take_profit, timestamp} => {
+                        match self.symbols.get_index(symbol) {
+                            Some(ix) => self.backfill_open(account_uuid, ix, long, size, stop, take_profit, timestamp),
+                            None => Err(BrokerError::NoSuchSymbol),
+                        }
+                    },
+                };
+                // rejected actions don't restart the interval
+                if res.is_ok() {
+                    self.last_action_times.insert(account_uuid, self.timestamp);
                 }
+                res
             },
             &BrokerAction::GetLedger{account_uuid} => {
                 match self.accounts.get(&account_uuid) {
@@ -343,32 +917,80 @@ impl SimBroker {
                 }
                 Ok(BrokerMessage::AccountListing{accounts: res})
             }
-            &BrokerAction::Disconnect => unimplemented!(),
+            // events that are already queued are still processed so that pending messages reach the client, but
+            // the simulation ends once they run out
+            &BrokerAction::Disconnect => {
+                self.debug_log("Client disconnected; ending the simulation once queued events are processed.");
+                self.disconnected = true;
+                Ok(BrokerMessage::Success)
+            },
+        }
+    }
+
+    /// Returns an error if the supplied account's last trading action was accepted less than `min_order_interval_ns`
+    /// ago.  The time of the action is recorded by `exec_action` once it's been accepted.
+    fn check_rate_limit(&self, account_uuid: Uuid) -> Result<(), BrokerError> {
+        let min_interval = self.settings.min_order_interval_ns;
+        if min_interval == 0 {
+            return Ok(());
+        }
+
+        if let Some(&last_time) = self.last_action_times.get(&account_uuid) {
+            let elapsed = self.timestamp - last_time;
+            if elapsed < min_interval {
+                return Err(BrokerError::RateLimited{wait_ns: min_interval - elapsed});
+            }
+        }
+        Ok(())
+    }
+
+    /// Enables or disables trading for an account.  While disabled, opening positions and placing or modifying
+    /// orders and positions is rejected, but positions can still be closed and stops and take profits still fire.
+    pub fn set_trading_enabled(&mut self, account_uuid: Uuid, enabled: bool) -> BrokerResult {
+        if !self.accounts.data.contains_key(&account_uuid) {
+            return Err(BrokerError::NoSuchAccount);
+        }
+
+        if enabled {
+            self.disabled_accounts.remove(&account_uuid);
+        } else {
+            self.disabled_accounts.insert(account_uuid);
+        }
+        Ok(BrokerMessage::Success)
+    }
+
+    /// Returns an error if trading has been disabled for the supplied account.
+    fn check_trading_enabled(&self, account_uuid: Uuid) -> Result<(), BrokerError> {
+        if self.disabled_accounts.contains(&account_uuid) {
+            return Err(BrokerError::TradingDisabled{account_uuid: account_uuid});
         }
+        Ok(())
     }
 
     /// Called when the balance of a ledger has been changed.  Automatically takes into account ping.
     fn buying_power_changed(&mut self, account_uuid: Uuid, new_buying_power: usize) {
-        self.pq.push(QueueItem{
-            timestamp: self.timestamp + self.settings.ping_ns,
-            unit: WorkUnit::Notification(Ok(BrokerMessage::LedgerBalanceChange{
-                account_uuid: account_uuid,
-                new_buying_power: new_buying_power,
-            })),
-        });
+        let notification = BrokerMessage::LedgerBalanceChange{
+            account_uuid: account_uuid,
+            new_buying_power: new_buying_power,
+        };
+        let res_time = self.timestamp + self.settings.ping_ns;
+        self.pq.push(QueueItem::new(res_time, WorkUnit::Notification(Ok(notification))));
     }
 
-    /// Creates a new pending position on the `SimBroker`.
+    /// Creates a new pending position on the `SimBroker`.  If the order can't be filled right away, it is either
+    /// left pending or cancelled depending on its time in force.  Marketable orders are always filled in full, so
+    /// immediate-or-cancel orders are all-or-nothing.  Orders cancelled without ever resting are reported with a
+    /// newly generated `order_id` that doesn't refer to anything in the ledger.
     fn place_order(
         &mut self, account_uuid: Uuid, symbol_ix: usize, limit_price: usize, long: bool, size: usize,
-        stop: Option<usize>, take_profit: Option<usize>,
-
+        stop: Option<usize>, take_profit: Option<usize>, time_in_force: TimeInForce,
     ) -> BrokerResult {
-        let opt = self.get_price(symbol_ix);
-        if opt.is_none() {
-            return Err(BrokerError::NoSuchSymbol)
-        }
-        let (bid, ask) = opt.unwrap();
+        let (bid, ask) = self.get_price(symbol_ix)?;
+        self.check_trading_enabled(account_uuid)?;
+        self.check_warmup(symbol_ix)?;
+        self.check_short(symbol_ix, long)?;
+        self.check_open_cutoff(symbol_ix)?;
+        self.check_positions_remaining(account_uuid)?;
 
         let order = Position {
             creation_time: self.timestamp,
@@ -382,16 +1004,30 @@ impl SimBroker {
             execution_price: None,
             exit_price: None,
             exit_time: None,
+            time_in_force: time_in_force,
+            fees: 0,
+            realized_pnl: None,
+            trailing_stop: None,
+            best_price: None,
         };
 
         // make sure the supplied parameters are sane
-        let _ = order.check_sanity()?;
+        self.check_position(&order)?;
 
-        // check if we're able to open this position right away at market price
-        match order.is_open_satisfied(bid, ask) {
+        // check if we're able to open this position right away at market price.  Orders placed during a trading
+        // halt are left pending until it lifts.
+        let fill_price = if self.symbols[symbol_ix].is_halted(self.timestamp) {
+            None
+        } else {
+            self.limit_fill_price(&order, bid, ask)
+        };
+        match fill_price {
             // if this order is fillable right now, open it.
-            Some(entry_price) => {
-                let res = self.market_open(account_uuid, symbol_ix, long, size, stop, take_profit, Some(0));
+            Some(fill_price) => {
+                let mut pos = order.clone();
+                pos.execution_time = Some(self.timestamp);
+                pos.execution_price = Some(fill_price);
+                let res = self.open_position(account_uuid, pos);
                 // this should always succeed
                 if res.is_err() {
                     self.logger.error_log(&format!("Error while trying to place order: {:?}, {:?}", &order, res));
@@ -402,7 +1038,19 @@ impl SimBroker {
             None => (),
         }
 
-        let pos_value = self.get_position_value(&order)?;
+        // orders that are immediate-or-cancel are never left pending, and orders that would expire immediately
+        // never make it onto the book.
+        if order.time_in_force == TimeInForce::ImmediateOrCancel || order.is_expired(self.timestamp) {
+            return Ok(BrokerMessage::OrderCancelled{
+                order_id: gen_uuid(self.prng),
+                order: order,
+                timestamp: self.timestamp,
+            });
+        }
+
+        // only orders that rest on the book count towards the limit
+        self.check_pending_orders_remaining(account_uuid)?;
+        let pos_value = self.get_position_margin(&order)?;
 
         // if we're not able to open it, try to place the order.
         let res = match self.accounts.entry(account_uuid) {
@@ -434,54 +1082,579 @@ impl SimBroker {
         res
     }
 
-    /// Attempts to open a position at the current market price with options for settings stop loss, or take profit.
-    /// Right now, this assumes that the order is filled as soon as it is placed (after the processing delay is taken
-    /// into account) and that it is filled fully.
-    fn market_open(
-        &mut self, account_uuid: Uuid, symbol_ix: usize, long: bool, size: usize, stop: Option<usize>,
-        take_profit: Option<usize>, max_range: Option<usize>
+    /// Places an order whose trigger price is pegged `offset` pips from the market, following it as it moves in the
+    /// order's favor until the market moves back through it.  Pegged orders are never filled when placed.
+    fn place_pegged_order(
+        &mut self, account_uuid: Uuid, symbol_ix: usize, long: bool, size: usize, offset: usize,
     ) -> BrokerResult {
-        let opt = self.get_price(symbol_ix);
-        if opt.is_none() {
-            return Err(BrokerError::NoSuchSymbol)
-        }
-        let (bid, ask) = opt.unwrap();
+        let (bid, ask) = self.get_price(symbol_ix)?;
+        self.check_trading_enabled(account_uuid)?;
+        self.check_warmup(symbol_ix)?;
+        self.check_short(symbol_ix, long)?;
+        self.check_open_cutoff(symbol_ix)?;
+        self.check_positions_remaining(account_uuid)?;
 
-        let cur_price = if long { ask } else { bid };
-
-        let pos = Position {
+        let order = Position {
             creation_time: self.timestamp,
             symbol_id: symbol_ix,
             size: size,
-            price: Some(cur_price),
+            price: Some(if long { ask + offset } else { bid.saturating_sub(offset) }),
             long: long,
-            stop: stop,
-            take_profit: take_profit,
-            execution_time: Some(self.timestamp + self.settings.execution_delay_ns),
-            execution_price: Some(cur_price),
+            stop: None,
+            take_profit: None,
+            execution_time: None,
+            execution_price: None,
             exit_price: None,
             exit_time: None,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            fees: 0,
+            realized_pnl: None,
+            trailing_stop: None,
+            best_price: None,
         };
+        self.check_pending_orders_remaining(account_uuid)?;
+        let pos_value = self.get_position_margin(&order)?;
 
-        // make sure the supplied parameters are sane
-        let _ = pos.check_sanity()?;
-
-        let pos_value = self.get_position_value(&pos)?;
-        let pos_uuid = gen_uuid(self.prng);
+        let order_uuid = gen_uuid(self.prng);
+        let res = match self.accounts.data.get_mut(&account_uuid) {
+            Some(account) => account.ledger.place_order(order.clone(), pos_value, order_uuid),
+            None => Err(BrokerError::NoSuchAccount),
+        };
+        if res.is_ok() {
+            self.pegs.insert(order_uuid, offset);
+            self.accounts.order_placed(&order, order_uuid, account_uuid);
+            let new_buying_power = self.accounts.get(&account_uuid).unwrap().ledger.buying_power;
+            self.buying_power_changed(account_uuid, new_buying_power);
+        }
 
-        let new_buying_power;
-        let res = {
-            let acct_entry = self.accounts.entry(account_uuid);
-            match acct_entry {
-                Entry::Occupied(mut occ) => {
-                    let mut account = occ.get_mut();
-                    // manually subtract the cost of the position from the account balance
-                    if account.ledger.buying_power < pos_value {
-                        return Err(BrokerError::InsufficientBuyingPower);
-                    } else {
-                        account.ledger.buying_power -= pos_value;
-                        new_buying_power = account.ledger.buying_power;
-                    }
+        res
+    }
+
+    /// Sets the stop of an open position to trail `distance` pips behind the best price the position could have
+    /// been closed at since the trailing stop was set.  The stop is only ever moved in the position's favor, so a
+    /// manually set stop that's tighter than the trail is kept.  A `distance` of 0 stops the stop from trailing, as
+    /// does manually changing the stop of the position, so it has to be set again to trail the new stop.
+    pub fn set_trailing_stop(&mut self, account_uuid: Uuid, position_uuid: Uuid, distance: usize) -> BrokerResult {
+        let pos = match self.accounts.data.get(&account_uuid) {
+            Some(acct) => match acct.ledger.open_positions.get(&position_uuid) {
+                Some(pos) => pos.clone(),
+                None => return Err(acct.ledger.missing_position_error(position_uuid)),
+            },
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+
+        let (trailing_stop, best_price) = if distance == 0 {
+            (None, None)
+        } else {
+            let (bid, ask) = self.get_price(pos.symbol_id)?;
+            (Some(distance), Some(if pos.long { bid } else { ask }))
+        };
+        let updated_pos = {
+            let ledger_pos = self.accounts.data.get_mut(&account_uuid).unwrap()
+                .ledger.open_positions.get_mut(&position_uuid).unwrap();
+            ledger_pos.trailing_stop = trailing_stop;
+            ledger_pos.best_price = best_price;
+            ledger_pos.clone()
+        };
+        self.accounts.position_modified(&updated_pos, position_uuid);
+
+        let stop = best_price.and_then(|best_price| trailed_stop(&updated_pos, best_price));
+        match stop {
+            Some(stop) => self.set_position_exits(account_uuid, position_uuid, Some(Some(stop)), None),
+            None => Ok(BrokerMessage::PositionModified{
+                position: updated_pos,
+                position_id: position_uuid,
+                timestamp: self.timestamp,
+            }),
+        }
+    }
+
+    /// Records new best prices for open positions in a symbol with trailing stops and moves their stops to follow
+    /// them.  Returns the number of push messages generated.
+    fn update_trailing_stops(
+        &mut self, symbol_id: usize, bid: usize, ask: usize, cur_index: usize, buffer: &mut Vec<TickOutput>
+    ) -> usize {
+        let improved: Vec<(Uuid, Uuid, usize)> = self.accounts.positions[symbol_id].open.iter()
+            .filter_map(|&CachedPosition { pos_uuid, acct_uuid, ref pos }| {
+                let best_price = match pos.best_price {
+                    Some(best_price) => best_price,
+                    None => return None,
+                };
+                if pos.long && bid > best_price {
+                    Some((pos_uuid, acct_uuid, bid))
+                } else if !pos.long && ask < best_price {
+                    Some((pos_uuid, acct_uuid, ask))
+                } else {
+                    None
+                }
+            }).collect();
+
+        let mut push_msg_count = 0;
+        for (pos_uuid, acct_uuid, best_price) in improved {
+            let updated_pos = {
+                let ledger_pos = self.accounts.data.get_mut(&acct_uuid).unwrap()
+                    .ledger.open_positions.get_mut(&pos_uuid).unwrap();
+                ledger_pos.best_price = Some(best_price);
+                ledger_pos.clone()
+            };
+            self.accounts.position_modified(&updated_pos, pos_uuid);
+
+            let stop = match trailed_stop(&updated_pos, best_price) {
+                Some(stop) => stop,
+                None => continue,
+            };
+            let push_msg = self.set_position_exits(acct_uuid, pos_uuid, Some(Some(stop)), None);
+            // this should always succeed
+            assert!(push_msg.is_ok());
+            self.push_msg(push_msg.clone());
+            buffer[cur_index + push_msg_count] = TickOutput::Pushstream(self.timestamp, push_msg);
+            push_msg_count += 1;
+        }
+        push_msg_count
+    }
+
+    /// Returns the price that a pending order would be filled at given the current prices or `None` if it can't be
+    /// filled.  Pegged orders are filled at market once the market reaches their trigger price; all other orders are
+    /// filled according to `limit_fill_price`.
+    fn pending_fill_price(&self, order_uuid: Uuid, order: &Position, bid: usize, ask: usize) -> Option<usize> {
+        if !self.pegs.contains_key(&order_uuid) {
+            return self.limit_fill_price(order, bid, ask);
+        }
+
+        let trigger = order.price.unwrap();
+        if order.long && ask >= trigger {
+            Some(ask)
+        } else if !order.long && bid <= trigger {
+            Some(bid)
+        } else {
+            None
+        }
+    }
+
+    /// Moves the trigger prices of the pegged orders in a symbol along with the market if it has moved in their
+    /// favor since they were last updated.
+    fn update_pegs(&mut self, symbol_id: usize, bid: usize, ask: usize) {
+        if self.pegs.is_empty() {
+            return;
+        }
+
+        for cached_pos in self.accounts.positions[symbol_id].pending.iter_mut() {
+            let offset = match self.pegs.get(&cached_pos.pos_uuid) {
+                Some(&offset) => offset,
+                None => continue,
+            };
+            let trigger = cached_pos.pos.price.unwrap();
+            let new_trigger = if cached_pos.pos.long {
+                ::std::cmp::min(trigger, ask + offset)
+            } else {
+                ::std::cmp::max(trigger, bid.saturating_sub(offset))
+            };
+            if new_trigger == trigger {
+                continue;
+            }
+
+            cached_pos.pos.price = Some(new_trigger);
+            let ledger = &mut self.accounts.data.get_mut(&cached_pos.acct_uuid).unwrap().ledger;
+            if let Some(order) = ledger.pending_positions.get_mut(&cached_pos.pos_uuid) {
+                order.price = Some(new_trigger);
+            }
+        }
+    }
+
+    /// Sets the stop of an open position to be moved to its entry price once it's `threshold` pips in profit.  The
+    /// stop is moved at most once; a `threshold` of 0 removes the automation from the position.  Manually changing
+    /// the stop of the position also removes it, so it has to be set again to move the new stop.
+    pub fn set_breakeven_stop(&mut self, account_uuid: Uuid, position_uuid: Uuid, threshold: usize) -> BrokerResult {
+        match self.accounts.data.get(&account_uuid) {
+            Some(acct) => if !acct.ledger.open_positions.contains_key(&position_uuid) {
+                return Err(acct.ledger.missing_position_error(position_uuid));
+            },
+            None => return Err(BrokerError::NoSuchAccount),
+        }
+
+        if threshold == 0 {
+            self.breakevens.remove(&position_uuid);
+        } else {
+            self.breakevens.insert(position_uuid, threshold);
+        }
+        Ok(BrokerMessage::Success)
+    }
+
+    /// Moves the stops of open positions in a symbol to their entry prices if they've reached their break-even
+    /// thresholds.  Returns the number of push messages generated.
+    fn update_breakevens(
+        &mut self, symbol_id: usize, bid: usize, ask: usize, cur_index: usize, buffer: &mut Vec<TickOutput>
+    ) -> usize {
+        if self.breakevens.is_empty() {
+            return 0;
+        }
+
+        let triggered: Vec<(Uuid, Uuid, usize)> = self.accounts.positions[symbol_id].open.iter()
+            .filter_map(|&CachedPosition { pos_uuid, acct_uuid, ref pos }| {
+                let threshold = match self.breakevens.get(&pos_uuid) {
+                    Some(&threshold) => threshold,
+                    None => return None,
+                };
+                let entry_price = match pos.execution_price {
+                    Some(price) => price,
+                    None => return None,
+                };
+                let profit = if pos.long { bid.saturating_sub(entry_price) } else { entry_price.saturating_sub(ask) };
+                if profit >= threshold { Some((pos_uuid, acct_uuid, entry_price)) } else { None }
+            }).collect();
+
+        let mut push_msg_count = 0;
+        for (pos_uuid, acct_uuid, entry_price) in triggered {
+            self.breakevens.remove(&pos_uuid);
+            let push_msg = self.set_position_exits(acct_uuid, pos_uuid, Some(Some(entry_price)), None);
+            // this should always succeed
+            assert!(push_msg.is_ok());
+            self.push_msg(push_msg.clone());
+            buffer[cur_index + push_msg_count] = TickOutput::Pushstream(self.timestamp, push_msg);
+            push_msg_count += 1;
+        }
+        push_msg_count
+    }
+
+    /// Returns the price that a pending order would be filled at given the current prices or `None` if it can't be
+    /// filled.  If prices have moved past the order's limit price, it's filled at the better market price if the
+    /// `allow_price_improvement` setting is enabled and at the limit price otherwise.  Which side of the market
+    /// has to reach the limit price is determined by the `limit_fill_side` setting.
+    fn limit_fill_price(&self, order: &Position, bid: usize, ask: usize) -> Option<usize> {
+        let limit_price = order.price.unwrap();
+        let market_price = match self.settings.limit_fill_side {
+            LimitFillSide::Cross => order.is_open_satisfied(bid, ask),
+            // check the opposite side against the limit price; the order is never filled at worse than it
+            LimitFillSide::Trade => order.is_open_satisfied(ask, bid).map(|_| {
+                if order.long { ::std::cmp::min(ask, limit_price) } else { ::std::cmp::max(bid, limit_price) }
+            }),
+        };
+
+        market_price.map(|market_price| {
+            if self.settings.allow_price_improvement {
+                market_price
+            } else {
+                limit_price
+            }
+        })
+    }
+
+    /// Attempts to open a position at the current market price with options for settings stop loss, or take profit.
+    /// Right now, this assumes that the order is filled as soon as it is placed (after the processing delay is taken
+    /// into account) and that it is filled fully.
+    fn market_open(
+        &mut self, account_uuid: Uuid, symbol_ix: usize, long: bool, size: usize, stop: Option<usize>,
+        take_profit: Option<usize>, max_range: Option<usize>
+    ) -> BrokerResult {
+        let (bid, ask) = self.get_price(symbol_ix)?;
+        self.check_halt(symbol_ix)?;
+        self.check_warmup(symbol_ix)?;
+        self.check_short(symbol_ix, long)?;
+        self.check_open_cutoff(symbol_ix)?;
+        self.check_spread(symbol_ix, bid, ask)?;
+        self.check_liquidity(symbol_ix, size)?;
+        self.check_position_size(symbol_ix, size)?;
+
+        let cur_price = if long { ask } else { bid };
+        let slippage = self.slippage_model.slippage(long, size, cur_price);
+        let fill_price = if long { cur_price + slippage } else { cur_price.saturating_sub(slippage) };
+
+        let pos = Position {
+            creation_time: self.timestamp,
+            symbol_id: symbol_ix,
+            size: size,
+            price: Some(cur_price),
+            long: long,
+            stop: stop,
+            take_profit: take_profit,
+            execution_time: Some(self.timestamp + self.settings.execution_delay_ns),
+            execution_price: Some(fill_price),
+            exit_price: None,
+            exit_time: None,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            fees: 0,
+            realized_pnl: None,
+            trailing_stop: None,
+            best_price: None,
+        };
+
+        let res = self.open_position(account_uuid, pos);
+        if res.is_ok() {
+            self.stats.entry(account_uuid).or_insert_with(AccountStats::default)
+                .record_slippage(abs_diff(fill_price, cur_price));
+        }
+        res
+    }
+
+    /// Returns the size of a position in a symbol that would use `pct` percent of an account's buying power at the
+    /// current price, snapped to the symbol's `lot_step` according to the `lot_rounding` setting.
+    fn pct_size(&self, account_uuid: Uuid, symbol_ix: usize, long: bool, pct: f64) -> Result<usize, BrokerError> {
+        let buying_power = match self.accounts.get(&account_uuid) {
+            Some(acct) => acct.ledger.buying_power,
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+        let (bid, ask) = self.get_price(symbol_ix)?;
+        let cur_price = if long { ask } else { bid };
+
+        // position values scale linearly with size, so find the value of a single unit
+        let unit_pos = Position {
+            creation_time: self.timestamp,
+            symbol_id: symbol_ix,
+            size: 1,
+            price: Some(cur_price),
+            long: long,
+            stop: None,
+            take_profit: None,
+            execution_time: None,
+            execution_price: None,
+            exit_price: None,
+            exit_time: None,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            fees: 0,
+            realized_pnl: None,
+            trailing_stop: None,
+            best_price: None,
+        };
+        let unit_value = self.get_position_margin(&unit_pos)?;
+
+        self.snap_to_lots(symbol_ix, buying_power as f64 * pct / 100.0 / unit_value as f64)
+    }
+
+    /// Returns the largest position in a symbol that an account could afford to open at market right now.  The
+    /// position's margin and opening fee are compared against the account's buying power the same way they are when
+    /// it's opened.  Other limits such as `max_position_size` or the symbol's liquidity aren't taken into account.
+    pub fn max_affordable_size(&self, account_uuid: Uuid, symbol: &String, long: bool) -> Result<usize, BrokerError> {
+        let symbol_ix = match self.symbols.get_index(symbol) {
+            Some(ix) => ix,
+            None => return Err(BrokerError::NoSuchSymbol),
+        };
+        let buying_power = match self.accounts.get(&account_uuid) {
+            Some(acct) => acct.ledger.buying_power as i64,
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+        let unrealized_pnl = if self.settings.use_unrealized_for_margin {
+            self.account_unrealized_pnl(account_uuid)
+        } else {
+            0
+        };
+        let available = buying_power + unrealized_pnl;
+        let (bid, ask) = self.get_price(symbol_ix)?;
+        let cur_price = if long { ask } else { bid };
+
+        // the position that `market_open` would create for the given size.  Slippage only affects its execution
+        // price, which its value doesn't depend on.
+        let market_pos = |size: usize| Position {
+            creation_time: self.timestamp,
+            symbol_id: symbol_ix,
+            size: size,
+            price: Some(cur_price),
+            long: long,
+            stop: None,
+            take_profit: None,
+            execution_time: Some(self.timestamp + self.settings.execution_delay_ns),
+            execution_price: Some(cur_price),
+            exit_price: None,
+            exit_time: None,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            fees: 0,
+            realized_pnl: None,
+            trailing_stop: None,
+            best_price: None,
+        };
+        let cost = |size: usize| -> Result<i64, BrokerError> {
+            let pos = market_pos(size);
+            Ok((self.get_position_margin(&pos)? + self.fee_model.open_fee(&pos)) as i64)
+        };
+
+        // margin scales linearly with size, so start from the size that the margin alone allows and then correct for
+        // fees
+        let unit_value = ::std::cmp::max(self.get_position_margin(&market_pos(1))?, 1) as i64;
+        let mut size = if available > 0 { (available / unit_value) as usize } else { 0 };
+        while size > 0 && cost(size)? > available {
+            size -= 1;
+        }
+        while cost(size + 1)? <= available {
+            size += 1;
+        }
+
+        Ok(size)
+    }
+
+    /// Returns the size of a position in a symbol entered at `entry` that would lose approximately `risk_amount`
+    /// units of base currency if it were closed at `stop`.  The position is long if the stop is below the entry
+    /// and short if it's above.  The loss is converted into the base currency the same way realized PnL is and the
+    /// size is snapped to the symbol's `lot_step` according to the `lot_rounding` setting.  Fees aren't included.
+    pub fn units_for_risk(
+        &self, account_uuid: Uuid, symbol: &String, entry: usize, stop: usize, risk_amount: usize
+    ) -> Result<usize, BrokerError> {
+        let symbol_ix = match self.symbols.get_index(symbol) {
+            Some(ix) => ix,
+            None => return Err(BrokerError::NoSuchSymbol),
+        };
+        if !self.accounts.data.contains_key(&account_uuid) {
+            return Err(BrokerError::NoSuchAccount);
+        }
+        if stop == entry {
+            return Err(BrokerError::InvalidStopValue);
+        }
+
+        // the loss of a single unit stopped out
+        let unit_pos = Position {
+            creation_time: self.timestamp,
+            symbol_id: symbol_ix,
+            size: 1,
+            price: Some(entry),
+            long: stop < entry,
+            stop: Some(stop),
+            take_profit: None,
+            execution_time: Some(self.timestamp),
+            execution_price: Some(entry),
+            exit_price: None,
+            exit_time: None,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            fees: 0,
+            realized_pnl: None,
+            trailing_stop: None,
+            best_price: None,
+        };
+        let unit_loss = -self.get_position_pnl(&unit_pos, stop)?;
+        if unit_loss <= 0 {
+            return Err(BrokerError::InvalidStopValue);
+        }
+
+        self.snap_to_lots(symbol_ix, risk_amount as f64 / unit_loss as f64)
+    }
+
+    /// Returns the size of a position in a symbol that's worth `amount` of its quote currency at the current price,
+    /// snapped to the symbol's `lot_step` according to the `lot_rounding` setting.
+    fn quote_size(&self, symbol_ix: usize, long: bool, amount: usize) -> Result<usize, BrokerError> {
+        let (bid, ask) = self.get_price(symbol_ix)?;
+        let cur_price = if long { ask } else { bid };
+        let sym = &self.symbols[symbol_ix];
+        let unit_value = if sym.is_fx() {
+            cur_price * self.settings.fx_lot_size
+        } else {
+            cur_price * sym.metadata.contract_multiplier
+        };
+
+        self.snap_to_lots(symbol_ix, amount as f64 / unit_value as f64)
+    }
+
+    /// Returns the number of units of a position that closing `pct` percent of it would close, snapped to the
+    /// symbol's `lot_step` according to the `lot_rounding` setting.
+    fn pct_close_size(&self, account_uuid: Uuid, pos_uuid: Uuid, pct: f64) -> Result<usize, BrokerError> {
+        let pos = match self.accounts.get(&account_uuid) {
+            Some(acct) => match acct.ledger.open_positions.get(&pos_uuid) {
+                Some(pos) => pos,
+                None => return Err(acct.ledger.missing_position_error(pos_uuid)),
+            },
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+
+        self.snap_to_lots(pos.symbol_id, pos.size as f64 * pct / 100.0)
+    }
+
+    /// Rounds a fractional number of units of a symbol to a whole number of its lots according to the
+    /// `lot_rounding` setting and returns the resulting number of units.
+    fn snap_to_lots(&self, symbol_ix: usize, units: f64) -> Result<usize, BrokerError> {
+        let sym = &self.symbols[symbol_ix];
+        let lot_step = sym.metadata.lot_step;
+
+        let lots = units / lot_step as f64;
+        let lots = match self.settings.lot_rounding {
+            LotRounding::Floor => lots.floor(),
+            LotRounding::Nearest => lots.round(),
+            LotRounding::Reject => {
+                if (lots - lots.round()).abs() > 1e-9 {
+                    return Err(BrokerError::FractionalLot{symbol: sym.name.clone()});
+                }
+                lots.round()
+            },
+        };
+
+        Ok(lots as usize * lot_step)
+    }
+
+    /// Sets the number of units in one lot of a symbol.
+    pub fn set_lot_step(&mut self, name: &String, lot_step: usize) -> BrokerResult {
+        if !self.symbols.contains(name) {
+            return Err(BrokerError::NoSuchSymbol);
+        }
+        self.symbols[name].metadata.lot_step = lot_step;
+        Ok(BrokerMessage::Success)
+    }
+
+    /// Opens a position as of a past timestamp at the price the symbol had at that time.  Only prices from the last
+    /// `tick_history_ns` of processed ticks are available.  The position's stop and take profit are only checked
+    /// against ticks that arrive after it is backfilled.
+    fn backfill_open(
+        &mut self, account_uuid: Uuid, symbol_ix: usize, long: bool, size: usize, stop: Option<usize>,
+        take_profit: Option<usize>, timestamp: u64,
+    ) -> BrokerResult {
+        if timestamp > self.timestamp {
+            return Err(BrokerError::Message{
+                message: String::from("Positions can't be backfilled at timestamps in the future."),
+            });
+        }
+        let (bid, ask) = match self.symbols[symbol_ix].price_at(timestamp) {
+            Some(price) => price,
+            None => return Err(BrokerError::NoDataAvailable),
+        };
+
+        let price = if long { ask } else { bid };
+        let pos = Position {
+            creation_time: timestamp,
+            symbol_id: symbol_ix,
+            size: size,
+            price: Some(price),
+            long: long,
+            stop: stop,
+            take_profit: take_profit,
+            execution_time: Some(timestamp),
+            execution_price: Some(price),
+            exit_price: None,
+            exit_time: None,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            fees: 0,
+            realized_pnl: None,
+            trailing_stop: None,
+            best_price: None,
+        };
+
+        self.open_position(account_uuid, pos)
+    }
+
+    /// Opens the supplied position immediately, charging the account for its margin and opening fee.
+    fn open_position(&mut self, account_uuid: Uuid, mut pos: Position) -> BrokerResult {
+        // make sure the supplied parameters are sane
+        self.check_position(&pos)?;
+        self.check_trading_enabled(account_uuid)?;
+        self.check_positions_remaining(account_uuid)?;
+
+        let pos_value = self.get_position_margin(&pos)?;
+        pos.fees = self.fee_model.open_fee(&pos);
+        let pos_uuid = gen_uuid(self.prng);
+        let unrealized_pnl = if self.settings.use_unrealized_for_margin {
+            self.account_unrealized_pnl(account_uuid)
+        } else {
+            0
+        };
+
+        let new_buying_power;
+        let res = {
+            let acct_entry = self.accounts.entry(account_uuid);
+            match acct_entry {
+                Entry::Occupied(mut occ) => {
+                    let mut account = occ.get_mut();
+                    // manually subtract the cost of the position and its opening fee from the account balance
+                    let cost = pos_value + pos.fees;
+                    if (account.ledger.buying_power as i64) + unrealized_pnl < cost as i64 {
+                        return Err(BrokerError::InsufficientBuyingPower);
+                    } else {
+                        account.ledger.charge_fee(pos.fees);
+                        account.ledger.reserve_margin(pos_uuid, pos_value);
+                        new_buying_power = account.ledger.buying_power;
+                    }
 
                     // create the position in the `Ledger`
                     account.ledger.open_position(pos_uuid, pos.clone())
@@ -504,7 +1677,10 @@ impl SimBroker {
 
     /// Attempts to close part of a position at market price.  Right now, this assumes that the order is
     /// fully filled as soon as it is placed (after the processing delay is taken into account).
-    fn market_close(&mut self, account_id: Uuid, position_uuid: Uuid, size: usize) -> BrokerResult {
+    ///
+    /// Closes can never open a position in the opposite direction.  If `reduce_only` is set, requesting to close
+    /// more units than the position holds closes it fully; otherwise it's an error.
+    fn market_close(&mut self, account_id: Uuid, position_uuid: Uuid, size: usize, reduce_only: bool) -> BrokerResult {
         if size == 0 {
             let ts_string = self.timestamp.to_string();
             self.cs.warning(
@@ -522,30 +1698,42 @@ impl SimBroker {
                 },
             };
 
-            match account.ledger.open_positions.entry(position_uuid) {
-                Entry::Occupied(o) => o.get().clone(),
-                Entry::Vacant(_) => {
-                    return Err(BrokerError::NoSuchPosition);
-                }
+            match account.ledger.open_positions.get(&position_uuid) {
+                Some(pos) => pos.clone(),
+                None => return Err(account.ledger.missing_position_error(position_uuid)),
             }
         };
 
-        let pos_value = self.get_position_value(&pos)?;
+        self.check_halt(pos.symbol_id)?;
+        let (bid, ask) = self.get_price(pos.symbol_id)?;
+        self.check_spread(pos.symbol_id, bid, ask)?;
 
-        let new_buying_power;
-        let res = {
-            let account = self.accounts.get_mut(&account_id).unwrap();
-            let modification_cost = (pos_value / pos.size) * size;
-            let res = account.ledger.resize_position(position_uuid, (-1 * size as isize), modification_cost, self.timestamp);
-            new_buying_power = account.ledger.buying_power;
-            res
+        let size = if size <= pos.size {
+            size
+        } else if reduce_only {
+            pos.size
+        } else {
+            return Err(BrokerError::InvalidModificationAmount);
         };
 
-        // if the position was fully closed, remove it from the cache and send notification of ledger buying power change
+        // closing a long position sells at the bid and closing a short one buys at the ask
+        let quoted_price = if pos.long { bid } else { ask };
+        let slippage = self.slippage_model.slippage(!pos.long, size, quoted_price);
+        let fill_price = if pos.long { quoted_price.saturating_sub(slippage) } else { quoted_price + slippage };
+        let res = self.reduce_position_at(account_id, position_uuid, size, fill_price);
+        let new_buying_power = self.accounts.get(&account_id).unwrap().ledger.buying_power;
+
+        // if the position was fully closed, remove it from the cache and if it was partially closed, update the
+        // cached size.  Either way, send notification of ledger buying power change.
         match res {
             Ok(ref message) => match message {
-                &BrokerMessage::PositionClosed{position: ref pos, position_id: pos_uuid, reason: _, timestamp: _} => {
-                    self.accounts.position_closed(pos, pos_uuid);
+                &BrokerMessage::PositionClosed{position: ref pos, position_id: pos_uuid, ..} => {
+                    self.position_closed(pos, pos_uuid);
+                    self.buying_power_changed(account_id, new_buying_power);
+                },
+                &BrokerMessage::PositionModified{position: ref pos, position_id: pos_uuid, ..} |
+                &BrokerMessage::PositionPartiallyClosed{position: ref pos, position_id: pos_uuid, ..} => {
+                    self.accounts.position_modified(pos, pos_uuid);
                     self.buying_power_changed(account_id, new_buying_power);
                 },
                 _ => (),
@@ -555,63 +1743,295 @@ impl SimBroker {
         res
     }
 
-    /// Modifies an order, setting the parameters of the contained `Position` equal to those supplied.
-    fn modify_order(
-        &mut self, account_uuid: Uuid, pos_uuid: Uuid, size: usize, entry_price: usize,
-        stop: Option<usize>, take_profit: Option<usize>,
-    ) -> BrokerResult {
-        let res = {
-            let order = {
-                let account = match self.accounts.entry(account_uuid) {
-                    Entry::Occupied(o) => o.into_mut(),
-                    Entry::Vacant(_) => {
-                        return Err(BrokerError::NoSuchAccount);
-                    },
-                };
-
-                // pull it out of the pending hashmap while we modify it
-                match account.ledger.pending_positions.get(&pos_uuid) {
-                    Some(pos) => pos,
-                    None => {
-                        return Err(BrokerError::NoSuchPosition);
-                    },
-                }.clone()
-            };
-            let opt = self.get_price(order.symbol_id);
-            if opt.is_none() {
-                return Err(BrokerError::NoSuchSymbol)
-            }
-            let (bid, ask) = opt.unwrap();
-            match order.is_open_satisfied(bid, ask) {
-                // if the new entry price makes the order marketable, go ahead and open the position.
-                Some(entry_price) => {
-                    let res = {
-                        let account = self.accounts.get_mut(&account_uuid).unwrap();
-                        // remove the position from the pending hashmap
-                        let mut hm_order = account.ledger.pending_positions.remove(&pos_uuid).unwrap();
-                        hm_order.execution_time = Some(self.timestamp);
-                        hm_order.execution_price = Some(entry_price);
-                        // add it to the open hashmap
-                        account.ledger.open_position(pos_uuid, order.clone())
-                    };
-                    // that should always succeed
-                    if res.is_err() {
-                        self.logger.error_log(&format!("Error while trying to modify order: {:?}, {:?}", &order, res));
-                    }
-                    // assert!(res.is_ok());
-                    // notify the cache that the position was opened
-                    self.accounts.position_opened(&order, pos_uuid);
-                    return res;
-                },
-                // if it's not marketable, perform the modification on the ledger
-                None => {
-                    let mut account = self.accounts.get_mut(&account_uuid).unwrap();
-                    account.ledger.modify_order(pos_uuid, size, entry_price, stop, take_profit, self.timestamp)
-                },
+    /// Adds `units` units to an open position at the current market price.  The position's entry price becomes the
+    /// average of the prices all of its units were bought or sold at, and the account is charged for the value of
+    /// the added units.
+    pub fn increase_position(&mut self, account_uuid: Uuid, position_uuid: Uuid, units: usize) -> BrokerResult {
+        self.check_trading_enabled(account_uuid)?;
+        let pos = match self.accounts.data.get(&account_uuid) {
+            Some(acct) => match acct.ledger.open_positions.get(&position_uuid) {
+                Some(pos) => pos.clone(),
+                None => return Err(acct.ledger.missing_position_error(position_uuid)),
+            },
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+        self.check_halt(pos.symbol_id)?;
+        self.check_open_cutoff(pos.symbol_id)?;
+        let (bid, ask) = self.get_price(pos.symbol_id)?;
+        self.check_spread(pos.symbol_id, bid, ask)?;
+        self.check_position_size(pos.symbol_id, pos.size + units)?;
+
+        let cur_price = if pos.long { ask } else { bid };
+        let entry_price = pos.execution_price.unwrap_or(cur_price);
+        let new_size = pos.size + units;
+        let avg_price = (entry_price * pos.size + cur_price * units) / new_size;
+        let mut grown_pos = pos.clone();
+        grown_pos.size = new_size;
+        grown_pos.price = Some(avg_price);
+        grown_pos.execution_price = Some(avg_price);
+        // charge the difference in margin so that the funds reserved for the position always match its margin
+        let cost = self.get_position_margin(&grown_pos)?.saturating_sub(self.get_position_margin(&pos)?);
+
+        let (res, new_buying_power) = {
+            let ledger = &mut self.accounts.data.get_mut(&account_uuid).unwrap().ledger;
+            let res = ledger.resize_position(position_uuid, units as isize, cost, 0, self.timestamp);
+            if res.is_ok() {
+                let ledger_pos = ledger.open_positions.get_mut(&position_uuid).unwrap();
+                ledger_pos.price = Some(avg_price);
+                ledger_pos.execution_price = Some(avg_price);
             }
+            (res, ledger.buying_power)
         };
 
-        // as of now, the modification operation always succeeds so we should always update the cache
+        match res {
+            Ok(BrokerMessage::PositionModified{position_id, timestamp, ..}) => {
+                self.accounts.position_modified(&grown_pos, position_id);
+                self.buying_power_changed(account_uuid, new_buying_power);
+                Ok(BrokerMessage::PositionModified{position: grown_pos, position_id: position_id, timestamp: timestamp})
+            },
+            res => res,
+        }
+    }
+
+    /// Closes `size` units of an open position at the supplied price, crediting the account with the funds released
+    /// plus the realized profit or loss.  Closing all of the position's units closes it entirely.  Doesn't touch
+    /// the position cache.
+    fn reduce_position_at(&mut self, account_id: Uuid, position_uuid: Uuid, size: usize, price: usize) -> BrokerResult {
+        let pos = match self.accounts.data.get(&account_id) {
+            Some(acct) => match acct.ledger.open_positions.get(&position_uuid) {
+                Some(pos) => pos.clone(),
+                None => return Err(acct.ledger.missing_position_error(position_uuid)),
+            },
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+
+        let released = self.released_value(account_id, position_uuid, &pos, pos.size - size)?;
+        // the profit or loss of the closed units is realized on top of that
+        let mut closed_pos = pos.clone();
+        closed_pos.size = size;
+        let pnl = self.get_position_pnl(&closed_pos, price)?;
+        let close_fees = self.get_close_fees(&closed_pos);
+        let modification_cost = apply_pnl(released, pnl - close_fees as i64);
+        let mut remaining_pos = pos.clone();
+        remaining_pos.size = pos.size - size;
+        let unrealized_pnl = self.get_position_pnl(&remaining_pos, price)?;
+        let closes_fully = size == pos.size;
+        let minor_unit = self.settings.pnl_minor_unit;
+        let reported_pnl = match self.stats.get(&account_id) {
+            Some(stats) => stats.reported_pnl(pnl, minor_unit),
+            None => AccountStats::default().reported_pnl(pnl, minor_unit),
+        };
+        let report_partial_closes = self.settings.report_partial_closes;
+        let timestamp = self.timestamp;
+
+        let res = {
+            let ledger = &mut self.accounts.get_mut(&account_id).unwrap().ledger;
+            let mut res = ledger.resize_position(
+                position_uuid, (-1 * size as isize), modification_cost, reported_pnl, timestamp
+            );
+            // only charge the closing fees and mark the exit once the resize has gone through
+            let record_close = |pos: &mut Position| {
+                pos.fees += close_fees;
+                if closes_fully {
+                    pos.exit_price = Some(price);
+                    pos.exit_time = Some(timestamp);
+                }
+            };
+            match res {
+                Ok(BrokerMessage::PositionModified{ref mut position, ..}) => {
+                    record_close(position);
+                    record_close(ledger.open_positions.get_mut(&position_uuid).unwrap());
+                },
+                Ok(BrokerMessage::PositionClosed{ref mut position, ..}) => {
+                    record_close(position);
+                    record_close(ledger.closed_positions.get_mut(&position_uuid).unwrap());
+                },
+                _ => (),
+            }
+            res
+        };
+        if res.is_ok() {
+            self.stats.entry(account_id).or_insert_with(AccountStats::default)
+                .record_pnl(pnl, minor_unit, closes_fully);
+        }
+        if !report_partial_closes {
+            return res;
+        }
+        match res {
+            Ok(BrokerMessage::PositionModified{position, position_id, timestamp}) => {
+                Ok(BrokerMessage::PositionPartiallyClosed{
+                    position_id: position_id,
+                    position: position,
+                    closed_size: size,
+                    realized_pnl: reported_pnl,
+                    unrealized_pnl: unrealized_pnl,
+                    timestamp: timestamp,
+                })
+            },
+            res => res,
+        }
+    }
+
+    /// Attaches a resting exit order to an open position that closes `size` units of it at `exit_price` once the
+    /// market reaches that price.  Closing all of the position's units closes it entirely.  Replaces any exit order
+    /// that the position already had; a `size` of 0 removes it.
+    fn limit_close(&mut self, account_uuid: Uuid, position_uuid: Uuid, size: usize, exit_price: usize) -> BrokerResult {
+        self.check_trading_enabled(account_uuid)?;
+        let pos_size = match self.accounts.data.get(&account_uuid) {
+            Some(acct) => match acct.ledger.open_positions.get(&position_uuid) {
+                Some(pos) => pos.size,
+                None => return Err(acct.ledger.missing_position_error(position_uuid)),
+            },
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+        if size > pos_size {
+            return Err(BrokerError::InvalidModificationAmount);
+        }
+
+        if size == 0 {
+            self.limit_closes.remove(&position_uuid);
+        } else {
+            self.limit_closes.insert(position_uuid, (size, exit_price));
+        }
+        Ok(BrokerMessage::Success)
+    }
+
+    /// Closes the units of open positions in a symbol that are covered by resting exit orders whose prices the
+    /// market has reached.  Returns the number of push messages generated.
+    fn fill_limit_closes(
+        &mut self, symbol_id: usize, bid: usize, ask: usize, cur_index: usize, buffer: &mut Vec<TickOutput>
+    ) -> usize {
+        if self.limit_closes.is_empty() {
+            return 0;
+        }
+
+        let mut triggered: Vec<(u64, Uuid, Uuid, usize, usize)> = self.accounts.positions[symbol_id].open.iter()
+            .filter_map(|&CachedPosition { pos_uuid, acct_uuid, ref pos }| {
+                let (size, exit_price) = match self.limit_closes.get(&pos_uuid) {
+                    Some(&limit_close) => limit_close,
+                    None => return None,
+                };
+                let reached = if pos.long { bid >= exit_price } else { ask <= exit_price };
+                if reached {
+                    // the position may have been partially closed since the exit order was placed
+                    Some((pos.creation_time, pos_uuid, acct_uuid, ::std::cmp::min(size, pos.size), exit_price))
+                } else {
+                    None
+                }
+            }).collect();
+        triggered.sort_by_key(|&(creation_time, pos_uuid, _, _, _)| (creation_time, pos_uuid));
+
+        let mut push_msg_count = 0;
+        for (_, pos_uuid, acct_uuid, size, exit_price) in triggered {
+            self.limit_closes.remove(&pos_uuid);
+            let push_msg = self.reduce_position_at(acct_uuid, pos_uuid, size, exit_price);
+            match push_msg {
+                Ok(BrokerMessage::PositionClosed{ref position, ..}) => {
+                    self.position_closed(position, pos_uuid);
+                },
+                Ok(BrokerMessage::PositionModified{ref position, ..}) |
+                Ok(BrokerMessage::PositionPartiallyClosed{ref position, ..}) => {
+                    self.accounts.position_modified(position, pos_uuid);
+                },
+                _ => (),
+            }
+            // this should always succeed
+            assert!(push_msg.is_ok());
+            // send notification of ledger buying power change to client
+            let buying_power_notification = BrokerMessage::LedgerBalanceChange{
+                account_uuid: acct_uuid,
+                new_buying_power: self.accounts.data[&acct_uuid].ledger.buying_power,
+            };
+            buffer[cur_index + push_msg_count] = TickOutput::Pushstream(self.timestamp, Ok(buying_power_notification));
+            push_msg_count += 1;
+            self.push_msg(push_msg.clone());
+            buffer[cur_index + push_msg_count] = TickOutput::Pushstream(self.timestamp, push_msg);
+            push_msg_count += 1;
+        }
+        push_msg_count
+    }
+
+    /// Returns the funds released by shrinking an open position to `remaining_size` units.
+    ///
+    /// Positions release their share of the funds that were actually reserved when they were opened, which can be
+    /// less than their margin if they were partly funded by unrealized PnL.  The share is the difference between
+    /// the reservation before and after the close rather than a per-unit amount multiplied out, so the credits from
+    /// any sequence of partial closes sum to exactly the funds reserved and no funds are lost or created through
+    /// rounding.  FX positions aren't revalued at the current exchange rate; the effect of the rate on them is
+    /// realized through their PnL, which is converted at the rate at the time of the close.  Positions without a
+    /// reservation release the difference in their margin instead.
+    fn released_value(
+        &self, account_id: Uuid, position_uuid: Uuid, pos: &Position, remaining_size: usize
+    ) -> Result<usize, BrokerError> {
+        let reserved = match self.accounts.data.get(&account_id) {
+            Some(acct) => acct.ledger.reserved_margin.get(&position_uuid).cloned(),
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+        if let Some(reserved) = reserved {
+            return Ok(reserved - reserved * remaining_size / pos.size);
+        }
+
+        let pos_value = self.get_position_margin(pos)?;
+        let mut remaining_pos = pos.clone();
+        remaining_pos.size = remaining_size;
+        let remaining_value = self.get_position_margin(&remaining_pos)?;
+        Ok(pos_value - remaining_value)
+    }
+
+    /// Modifies an order, setting the parameters of the contained `Position` equal to those supplied.
+    fn modify_order(
+        &mut self, account_uuid: Uuid, pos_uuid: Uuid, size: usize, entry_price: usize,
+        stop: Option<usize>, take_profit: Option<usize>,
+    ) -> BrokerResult {
+        self.check_trading_enabled(account_uuid)?;
+        let res = {
+            let order = {
+                let account = match self.accounts.entry(account_uuid) {
+                    Entry::Occupied(o) => o.into_mut(),
+                    Entry::Vacant(_) => {
+                        return Err(BrokerError::NoSuchAccount);
+                    },
+                };
+
+                // pull it out of the pending hashmap while we modify it
+                match account.ledger.pending_positions.get(&pos_uuid) {
+                    Some(pos) => pos,
+                    None => {
+                        return Err(BrokerError::NoSuchPosition);
+                    },
+                }.clone()
+            };
+            let (bid, ask) = self.get_price(order.symbol_id)?;
+            match self.limit_fill_price(&order, bid, ask) {
+                // if the new entry price makes the order marketable, go ahead and open the position.
+                Some(fill_price) => {
+                    let (res, opened) = {
+                        let account = self.accounts.get_mut(&account_uuid).unwrap();
+                        // remove the position from the pending hashmap
+                        let mut hm_order = account.ledger.pending_positions.remove(&pos_uuid).unwrap();
+                        hm_order.execution_time = Some(self.timestamp);
+                        hm_order.execution_price = Some(fill_price);
+                        // add it to the open hashmap
+                        (account.ledger.open_position(pos_uuid, hm_order.clone()), hm_order)
+                    };
+                    // that should always succeed
+                    if res.is_err() {
+                        self.logger.error_log(&format!("Error while trying to modify order: {:?}, {:?}", &order, res));
+                    }
+                    // assert!(res.is_ok());
+                    // notify the cache that the position was opened
+                    self.accounts.position_opened(&opened, pos_uuid);
+                    return res;
+                },
+                // if it's not marketable, perform the modification on the ledger
+                None => {
+                    let mut account = self.accounts.get_mut(&account_uuid).unwrap();
+                    account.ledger.modify_order(pos_uuid, size, entry_price, stop, take_profit, self.timestamp)
+                },
+            }
+        };
+
+        // as of now, the modification operation always succeeds so we should always update the cache
         match res.as_ref().unwrap() {
             &BrokerMessage::OrderModified{ ref order, order_id: _, timestamp: _ } => {
                 self.accounts.order_modified(order, pos_uuid);
@@ -619,123 +2039,865 @@ impl SimBroker {
             _ => unreachable!(),
         }
 
-        res
+        res
+    }
+
+    /// Cancels the pending position.
+    pub fn cancel_order(&mut self, account_uuid: Uuid, order_uuid: Uuid) -> BrokerResult {
+        let new_buying_power;
+        let res = {
+            let account = match self.accounts.entry(account_uuid) {
+                Entry::Occupied(o) => o.into_mut(),
+                Entry::Vacant(_) => {
+                    return Err(BrokerError::NoSuchAccount);
+                },
+            };
+            // attempt to cancel the order and remove it from the hashmaps
+            let res = account.ledger.cancel_order(order_uuid, self.timestamp);
+            new_buying_power = account.ledger.buying_power;
+            res
+        };
+
+        // if it was successful, remove the position from the `pending` cache
+        // also send notification of ledger buying power change
+        match res {
+            Ok(ref msg) => {
+                match msg {
+                    &BrokerMessage::OrderCancelled{ ref order, order_id: _, timestamp: _ } => {
+                        self.pegs.remove(&order_uuid);
+                        self.accounts.order_cancelled(order_uuid, order.symbol_id);
+                        self.buying_power_changed(account_uuid, new_buying_power);
+                    },
+                    _ => unreachable!(),
+                }
+            },
+            Err(_) => (),
+        }
+
+        res
+    }
+
+    /// Modifies the stop loss or take profit of a position.  SL and TP are double option-wrapped; the outer
+    /// option indicates if they should be changed and the inner option indicates if the value should be set
+    /// or not (`Some(None)` indicates that the current SL should be removed, for example).  Changing the SL
+    /// disables any break-even or trailing automation of the position so that it doesn't overwrite the new SL.
+    fn modify_position(
+        &mut self, account_id: Uuid, position_uuid: Uuid, sl: Option<Option<usize>>, tp: Option<Option<usize>>
+    ) -> BrokerResult {
+        if sl.is_some() {
+            self.breakevens.remove(&position_uuid);
+            if let Some(pos) = self.accounts.data.get_mut(&account_id)
+                .and_then(|acct| acct.ledger.open_positions.get_mut(&position_uuid))
+            {
+                pos.trailing_stop = None;
+                pos.best_price = None;
+            }
+        }
+
+        self.set_position_exits(account_id, position_uuid, sl, tp)
+    }
+
+    /// Changes the stop loss or take profit of a position the same way as `modify_position` but leaves its
+    /// automations in place.  Used by the automations themselves to move the stop.
+    fn set_position_exits(
+        &mut self, account_id: Uuid, position_uuid: Uuid, sl: Option<Option<usize>>, tp: Option<Option<usize>>
+    ) -> BrokerResult {
+        let res = {
+            let account = match self.accounts.entry(account_id) {
+                Entry::Occupied(o) => o.into_mut(),
+                Entry::Vacant(_) => {
+                    return Err(BrokerError::NoSuchAccount);
+                },
+            };
+            account.ledger.modify_position(position_uuid, sl, tp, self.timestamp)
+        };
+
+        // TODO: Check if the new SL/TP make the position meet closure conditions and if they do, close it
+
+        // if the position was actually modified, remove it from the cache
+        match res {
+            Ok(ref message) => match message {
+                &BrokerMessage::PositionModified{position: ref pos, position_id: pos_uuid, timestamp: _} => {
+                    self.accounts.position_modified(pos, pos_uuid);
+                },
+                _ => (),
+            },
+            Err(_) => (),
+        }
+        res
+    }
+
+    /// Removes a closed position from the open position cache along with the state kept for it by the stop fills
+    /// and the position automations.
+    fn position_closed(&mut self, pos: &Position, pos_uuid: Uuid) {
+        self.accounts.position_closed(pos, pos_uuid);
+        self.stop_fills.remove(&pos_uuid);
+        self.breakevens.remove(&pos_uuid);
+        self.limit_closes.remove(&pos_uuid);
+    }
+
+    /// Dumps the accounts, settings, and current time of the broker along with the metadata and prices of its
+    /// symbols, the trading statistics of its accounts, and the state of its position automations to a JSON file
+    /// that the simulation can be resumed from with `restore_from_file`.  The snapshot is written to a temporary
+    /// file that's then moved into place so that `filename` never holds a partial one.
+    pub fn dump_to_file(&self, filename: &str) -> Result<(), BrokerError> {
+        let snapshot = SimBrokerSnapshot {
+            settings: self.settings.clone(),
+            timestamp: self.timestamp,
+            accounts: self.accounts.iter().map(|(_, acct)| acct.clone()).collect(),
+            symbols: self.symbols.iter().map(|sym| (sym.name.clone(), sym.metadata.clone(), sym.price)).collect(),
+            stats: self.stats.clone(),
+            margin_breaches: self.margin_breaches.clone(),
+            stop_fills: self.stop_fills.clone(),
+            disabled_accounts: self.disabled_accounts.clone(),
+            pegs: self.pegs.clone(),
+            breakevens: self.breakevens.clone(),
+            limit_closes: self.limit_closes.clone(),
+        };
+        let json = serde_json::to_string(&snapshot)
+            .map_err(|err| BrokerError::Message{message: format!("Unable to serialize the broker state: {}", err)})?;
+
+        let tmp_filename = format!("{}.tmp", filename);
+        {
+            let mut file = File::create(&tmp_filename)
+                .map_err(|err| BrokerError::Message{message: format!("Unable to create snapshot file: {}", err)})?;
+            file.write_all(json.as_bytes()).and_then(|_| file.sync_all())
+                .map_err(|err| BrokerError::Message{message: format!("Unable to write snapshot file: {}", err)})?;
+        }
+        fs::rename(&tmp_filename, filename)
+            .map_err(|err| BrokerError::Message{message: format!("Unable to move snapshot file into place: {}", err)})
+    }
+
+    /// Creates a `SimBroker` from a snapshot written by `dump_to_file`.  Symbols are restored with their last prices
+    /// but without tickstreams, which have to be registered again before the simulation is resumed.
+    pub fn restore_from_file(
+        filename: &str, cs: CommandServer, client_rx: mpsc::Receiver<(BrokerAction, Complete<BrokerResult>)>,
+    ) -> Result<SimBroker, BrokerError> {
+        let mut json = String::new();
+        File::open(filename).and_then(|mut file| file.read_to_string(&mut json))
+            .map_err(|err| BrokerError::Message{message: format!("Unable to read snapshot file: {}", err)})?;
+        let snapshot: SimBrokerSnapshot = serde_json::from_str(&json)
+            .map_err(|err| BrokerError::Message{message: format!("Unable to deserialize snapshot: {}", err)})?;
+
+        let mut symbols = Symbols::new(cs.clone());
+        for (name, metadata, price) in snapshot.symbols {
+            let mut symbol = Symbol::new_oneshot(price, metadata.is_fx, metadata.decimal_precision, name.clone());
+            symbol.metadata = metadata;
+            symbols.add(name, symbol)?;
+        }
+        let mut sim = SimBroker::with_symbols(snapshot.settings, cs, client_rx, symbols)?;
+        sim.timestamp = snapshot.timestamp;
+        sim.stats = snapshot.stats;
+        sim.margin_breaches = snapshot.margin_breaches;
+        sim.stop_fills = snapshot.stop_fills;
+        sim.disabled_accounts = snapshot.disabled_accounts;
+        sim.pegs = snapshot.pegs;
+        sim.breakevens = snapshot.breakevens;
+        sim.limit_closes = snapshot.limit_closes;
+
+        // replace the default account with the restored ones and rebuild the caches of their positions in the order
+        // they were created in
+        sim.accounts.data.clear();
+        for account in snapshot.accounts {
+            let mut orders: Vec<(&Uuid, &Position)> = account.ledger.pending_positions.iter().collect();
+            orders.sort_by_key(|&(&uuid, pos)| (pos.creation_time, uuid));
+            for (&order_uuid, order) in orders {
+                sim.accounts.order_placed(order, order_uuid, account.uuid);
+            }
+            let mut positions: Vec<(&Uuid, &Position)> = account.ledger.open_positions.iter().collect();
+            positions.sort_by_key(|&(&uuid, pos)| (pos.creation_time, uuid));
+            for (&pos_uuid, pos) in positions {
+                sim.accounts.position_opened_immediate(pos, pos_uuid, account.uuid);
+            }
+            sim.accounts.insert(account.uuid, account);
+        }
+
+        Ok(sim)
+    }
+
+    /// Used for Forex exchange rate conversions.  The cost to open a position is determined
+    /// by the exchange rate between the base currency and the primary currency of the pair.
+    /// A decimal precision of 10 is used for all returned results.
+    ///
+    /// Gets the conversion rate (in pips) between the base currency of the simbroker and
+    /// the supplied currency.  If the base currency is USD and AUD is provided, the exchange
+    /// rate for AUD/USD will be returned.  If there's no pair between the currency and the base
+    /// currency, the rate is triangulated through `fx_cross_currency` as the product of the rates
+    /// of the two legs.  Returns Err if we lack the data to do that.  Results are returned with
+    /// the specified decimal precision.
+    fn get_base_rate(&self, currency: &str, desired_decimals: usize) -> Result<usize, BrokerError> {
+        if !self.settings.fx {
+            return Err(BrokerError::Message{
+                message: String::from("Can only convert to base rate when in FX mode.")
+            });
+        }
+
+        let base_currency = &self.settings.fx_base_currency;
+        let cross_currency = &self.settings.fx_cross_currency;
+        match self.get_pair_rate(currency, base_currency, desired_decimals) {
+            Err(BrokerError::NoDataAvailable)
+                if !cross_currency.is_empty() && cross_currency != currency && cross_currency != base_currency =>
+            {
+                let first_leg = self.get_pair_rate(currency, cross_currency, desired_decimals)?;
+                let second_leg = self.get_pair_rate(cross_currency, base_currency, desired_decimals)?;
+                Ok(first_leg * second_leg / 10usize.pow(desired_decimals as u32))
+            },
+            res => res,
+        }
+    }
+
+    /// Returns the rate between two currencies from the pair between them, or the reversed pair if it doesn't
+    /// exist, with the specified decimal precision.
+    fn get_pair_rate(&self, currency: &str, base_currency: &str, desired_decimals: usize) -> Result<usize, BrokerError> {
+        let base_pair = format!("{}{}", currency, base_currency);
+
+        let sym = if !self.symbols.contains(&base_pair) {
+            // try reversing the order or the pairs
+            let base_pair_reverse = format!("{}{}", base_currency, currency);
+            if !self.symbols.contains(&base_pair_reverse) {
+                return Err(BrokerError::NoDataAvailable);
+            } else {
+                &self.symbols[&base_pair_reverse]
+            }
+        } else {
+            &self.symbols[&base_pair]
+        };
+
+        // don't convert using prices that are too old to be trusted
+        let max_rate_age = self.settings.max_rate_age_ns;
+        if let Some(last_tick_time) = sym.last_tick_time {
+            let age = self.timestamp.saturating_sub(last_tick_time);
+            if max_rate_age != 0 && age > max_rate_age {
+                return Err(BrokerError::StaleRate{symbol: sym.name.clone(), age_ns: age});
+            }
+        }
+
+        let (_, ask, decimals) = sym.get_price();
+        Ok(convert_decimals(ask, decimals, desired_decimals))
+    }
+
+    /// Returns the value of a position in units of base currency, not taking into account leverage.
+    fn get_position_value(&self, pos: &Position) -> Result<usize, BrokerError> {
+        let ix = pos.symbol_id;
+
+        let sym = &self.symbols[ix];
+        if sym.is_fx() {
+            let base_rate: usize = self.get_base_rate(&sym.name[0..3], sym.metadata.decimal_precision)?;
+            Ok(pos.size * base_rate * self.settings.fx_lot_size)
+        } else {
+            // Non-FX positions are valued at the price they were ordered at so that the funds released when they're
+            // closed are exactly those that were reserved when they were opened.  Profit and loss is separate.
+            let price = match pos.price {
+                Some(price) => price,
+                None => return Err(BrokerError::MissingExecutionData),
+            };
+            Ok(pos.size * price * sym.metadata.contract_multiplier)
+        }
+    }
+
+    /// Returns the margin that must be reserved to hold a position in units of base currency: its value divided by
+    /// `leverage`.  Profit and loss are still calculated on the position's full value.
+    fn get_position_margin(&self, pos: &Position) -> Result<usize, BrokerError> {
+        let leverage = ::std::cmp::max(self.settings.leverage, 1);
+        Ok(self.get_position_value(pos)? / leverage)
+    }
+
+    /// Returns the profit or loss that would be realized by closing a position at the supplied price in units of
+    /// base currency.  For FX positions, it's converted from the quote currency at the current exchange rate.
+    fn get_position_pnl(&self, pos: &Position, exit_price: usize) -> Result<i64, BrokerError> {
+        let entry_price = match pos.execution_price {
+            Some(price) => price,
+            None => return Err(BrokerError::MissingExecutionData),
+        };
+        let diff = if pos.long {
+            exit_price as i64 - entry_price as i64
+        } else {
+            entry_price as i64 - exit_price as i64
+        };
+
+        let sym = &self.symbols[pos.symbol_id];
+        if sym.is_fx() {
+            // the price difference is in units of the pair's quote currency so convert it into the base currency
+            let decimals = sym.metadata.decimal_precision;
+            let quote_pnl = diff * (pos.size * self.settings.fx_lot_size) as i64;
+            let quote_currency = &sym.name[3..6];
+            if quote_currency == self.settings.fx_base_currency {
+                Ok(quote_pnl)
+            } else {
+                let rate = self.get_base_rate(quote_currency, decimals)?;
+                Ok(quote_pnl * rate as i64 / 10i64.pow(decimals as u32))
+            }
+        } else {
+            Ok(diff * (pos.size * sym.metadata.contract_multiplier) as i64)
+        }
+    }
+
+    /// Returns the price at which closing the specified open position would exactly cover the fees that have been
+    /// charged for it plus those that would be charged for closing it now.  Since prices are discrete, the
+    /// returned price is rounded away from the entry price.
+    pub fn break_even_price(&self, account_uuid: Uuid, position_id: Uuid) -> Result<usize, BrokerError> {
+        let pos = match self.accounts.get(&account_uuid) {
+            Some(acct) => match acct.ledger.open_positions.get(&position_id) {
+                Some(pos) => pos.clone(),
+                None => return Err(acct.ledger.missing_position_error(position_id)),
+            },
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+        let entry_price = match pos.execution_price {
+            Some(price) => price,
+            None => return Err(BrokerError::MissingExecutionData),
+        };
+
+        // PnL is linear in price, so find how much a one pip move in our favor is worth and scale from there
+        let favorable_price = if pos.long { entry_price + 1 } else { entry_price - 1 };
+        let pip_value = self.get_position_pnl(&pos, favorable_price)?;
+        if pip_value <= 0 {
+            return Err(BrokerError::NoDataAvailable);
+        }
+        let pip_value = pip_value as usize;
+        let fees = pos.fees + self.get_close_fees(&pos);
+        let offset = (fees + pip_value - 1) / pip_value;
+
+        if pos.long {
+            Ok(entry_price + offset)
+        } else {
+            Ok(entry_price - offset)
+        }
+    }
+
+    /// Returns the profit or loss that would be realized by closing a position at the current market price.
+    fn get_unrealized_pnl(&self, pos: &Position) -> Result<i64, BrokerError> {
+        let (bid, ask) = self.get_price(pos.symbol_id)?;
+        self.get_position_pnl(pos, if pos.long { bid } else { ask })
+    }
+
+    /// Returns the total unrealized PnL of all of an account's open positions at current prices.  Positions whose
+    /// PnL can't be determined are ignored.
+    fn account_unrealized_pnl(&self, account_uuid: Uuid) -> i64 {
+        match self.accounts.get(&account_uuid) {
+            Some(acct) => acct.ledger.open_positions.values()
+                .map(|pos| self.get_unrealized_pnl(pos).unwrap_or(0))
+                .sum(),
+            None => 0,
+        }
+    }
+
+    /// Returns a summary of how much of an account's funds are being used as margin for its open positions.
+    ///
+    /// Where an account holds both long and short positions in the same symbol, the offsetting portion of them only
+    /// counts towards used margin at the rate of `hedged_margin_factor` and the margin this frees up is counted as
+    /// free margin.
+    pub fn margin_summary(&self, account_uuid: Uuid) -> Result<MarginSummary, BrokerError> {
+        let ledger = match self.accounts.data.get(&account_uuid) {
+            Some(acct) => &acct.ledger,
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+
+        // (long value, short value) of the open positions in each symbol
+        let mut symbol_values = vec![(0, 0); self.symbols.len()];
+        let mut position_values = 0;
+        let mut unrealized_pnl = 0;
+        for pos in ledger.open_positions.values() {
+            let value = self.get_position_margin(pos)?;
+            if pos.long {
+                symbol_values[pos.symbol_id].0 += value;
+            } else {
+                symbol_values[pos.symbol_id].1 += value;
+            }
+            position_values += value;
+            unrealized_pnl += self.get_unrealized_pnl(pos)?;
+        }
+        let mut reserved = 0;
+        for order in ledger.pending_positions.values() {
+            reserved += self.get_position_margin(order)?;
+        }
+
+        let mut used_margin = 0;
+        for &(long_value, short_value) in symbol_values.iter() {
+            let hedged = if long_value < short_value { long_value } else { short_value };
+            let hedged_margin = (2 * hedged) as f64 * self.settings.hedged_margin_factor;
+            used_margin += long_value + short_value - 2 * hedged + hedged_margin.round() as usize;
+        }
+
+        let equity = (ledger.buying_power + reserved + position_values) as i64 + unrealized_pnl;
+        let margin_level = if used_margin == 0 {
+            None
+        } else {
+            Some((equity as f64 / used_margin as f64) * 100.)
+        };
+
+        Ok(MarginSummary {
+            used_margin: used_margin,
+            free_margin: ledger.buying_power + position_values - used_margin,
+            equity: equity,
+            margin_level: margin_level,
+        })
+    }
+
+    /// Returns the bid price that a symbol would have to move to, keeping its current spread, for an account's equity
+    /// to reach `target_equity` given its open positions.  Returns `None` if the account or symbol doesn't exist,
+    /// the account has no exposure to the symbol, or the price would have to be negative.  The exchange rates used
+    /// to convert PnL into the base currency are assumed to stay where they are.
+    pub fn price_for_equity(&self, account_uuid: Uuid, symbol: &String, target_equity: i64) -> Option<usize> {
+        let symbol_ix = match self.symbols.get_index(symbol) {
+            Some(ix) => ix,
+            None => return None,
+        };
+        let equity = match self.margin_summary(account_uuid) {
+            Ok(summary) => summary.equity,
+            Err(_) => return None,
+        };
+        let (bid, ask) = match self.get_price(symbol_ix) {
+            Ok(price) => price,
+            Err(_) => return None,
+        };
+
+        // equity moves linearly with the price, so find how much it changes for a one pip move
+        let mut pip_value = 0;
+        for pos in self.accounts.data[&account_uuid].ledger.open_positions.values() {
+            if pos.symbol_id != symbol_ix {
+                continue;
+            }
+            let exit_price = if pos.long { bid } else { ask };
+            match (self.get_position_pnl(pos, exit_price + 1), self.get_position_pnl(pos, exit_price)) {
+                (Ok(moved_pnl), Ok(pnl)) => pip_value += moved_pnl - pnl,
+                _ => return None,
+            }
+        }
+        if pip_value == 0 {
+            return None;
+        }
+
+        let pips = ((target_equity - equity) as f64 / pip_value as f64).round() as i64;
+        let price = bid as i64 + pips;
+        if price < 0 {
+            None
+        } else {
+            Some(price as usize)
+        }
+    }
+
+    /// Returns the total loss that an account would incur if the stops of all of its open positions were hit.
+    /// Positions without a stop are assumed to lose `stopless_position_risk` and positions whose stops lock in a
+    /// profit don't count towards the total.
+    pub fn max_open_risk(&self, account_uuid: Uuid) -> Result<i64, BrokerError> {
+        let ledger = match self.accounts.data.get(&account_uuid) {
+            Some(acct) => &acct.ledger,
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+
+        let mut risk = 0;
+        for pos in ledger.open_positions.values() {
+            risk += match pos.stop {
+                Some(stop) => {
+                    let loss = -self.get_position_pnl(pos, stop)?;
+                    if loss > 0 { loss } else { 0 }
+                },
+                None => self.settings.stopless_position_risk as i64,
+            };
+        }
+
+        Ok(risk)
+    }
+
+    /// Returns the trading statistics of an account.  Realized PnL is reported rounded to the account currency's minor
+    /// unit as configured by the `pnl_minor_unit` setting.
+    pub fn get_stats(&self, account_uuid: Uuid) -> Result<AccountStats, BrokerError> {
+        if !self.accounts.data.contains_key(&account_uuid) {
+            return Err(BrokerError::NoSuchAccount);
+        }
+
+        Ok(self.stats.get(&account_uuid).cloned().unwrap_or_default())
+    }
+
+    /// Returns how many more positions and orders an account can open before reaching the `max_positions` limit.
+    /// Returns `usize::max_value()` if there is no limit.
+    pub fn positions_remaining(&self, account_uuid: Uuid) -> Result<usize, BrokerError> {
+        let ledger = match self.accounts.get(&account_uuid) {
+            Some(acct) => &acct.ledger,
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+        if self.settings.max_positions == 0 {
+            return Ok(usize::max_value());
+        }
+
+        let used = ledger.open_positions.len() + ledger.pending_positions.len();
+        Ok(self.settings.max_positions.saturating_sub(used))
+    }
+
+    /// Returns an error if an account can't open any more positions or orders.
+    fn check_positions_remaining(&self, account_uuid: Uuid) -> Result<(), BrokerError> {
+        if self.positions_remaining(account_uuid)? == 0 {
+            return Err(BrokerError::MaxPositionsReached{max_positions: self.settings.max_positions});
+        }
+        Ok(())
+    }
+
+    /// Returns an error if an account already has as many pending orders as the `max_pending_orders` limit.
+    fn check_pending_orders_remaining(&self, account_uuid: Uuid) -> Result<(), BrokerError> {
+        let max_pending_orders = self.settings.max_pending_orders;
+        if max_pending_orders == 0 {
+            return Ok(());
+        }
+
+        match self.accounts.get(&account_uuid) {
+            Some(acct) if acct.ledger.pending_positions.len() >= max_pending_orders => {
+                Err(BrokerError::MaxPendingOrdersReached{max_pending_orders: max_pending_orders})
+            },
+            Some(_) => Ok(()),
+            None => Err(BrokerError::NoSuchAccount),
+        }
+    }
+
+    /// Returns the realized profit or loss of an account's closed positions summed up by symbol, including PnL
+    /// realized by partially closing them before they were fully closed.  Like `Position::pnl`, it doesn't include
+    /// fees.
+    pub fn pnl_by_symbol(&self, account_uuid: Uuid) -> Result<HashMap<String, i64>, BrokerError> {
+        let ledger = match self.accounts.data.get(&account_uuid) {
+            Some(acct) => &acct.ledger,
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+
+        let mut pnls = HashMap::new();
+        for pos in ledger.closed_positions.values() {
+            *pnls.entry(self.symbols[pos.symbol_id].name.clone()).or_insert(0) += pos.pnl().unwrap_or(0);
+        }
+
+        Ok(pnls)
+    }
+
+    /// Returns the `n` most recently closed positions of an account, most recent first, without cloning the rest.
+    /// Positions closed at the same time are ordered by UUID.
+    pub fn recent_trades(&self, account_uuid: Uuid, n: usize) -> Result<Vec<Position>, BrokerError> {
+        let ledger = match self.accounts.data.get(&account_uuid) {
+            Some(acct) => &acct.ledger,
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+
+        let mut closed: Vec<(&Uuid, &Position)> = ledger.closed_positions.iter().collect();
+        closed.sort_by(|&(uuid1, pos1), &(uuid2, pos2)| (pos2.exit_time, uuid1).cmp(&(pos1.exit_time, uuid2)));
+        Ok(closed.into_iter().take(n).map(|(_, pos)| pos.clone()).collect())
+    }
+
+    /// Groups the closed positions of an account by the hour of the day (0-23) that they were entered at, returning
+    /// the number of positions and the sum of their realized PnL for each hour that had any.
+    pub fn trades_by_hour(&self, account_uuid: Uuid) -> Result<HashMap<u8, (usize, i64)>, BrokerError> {
+        let ledger = match self.accounts.data.get(&account_uuid) {
+            Some(acct) => &acct.ledger,
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+
+        let mut buckets = HashMap::new();
+        for pos in ledger.closed_positions.values() {
+            let entry_time = pos.execution_time.unwrap_or(pos.creation_time);
+            let hour = (entry_time / NS_PER_HOUR % 24) as u8;
+            let bucket = buckets.entry(hour).or_insert((0, 0));
+            bucket.0 += 1;
+            bucket.1 += pos.pnl().unwrap_or(0);
+        }
+
+        Ok(buckets)
+    }
+
+    /// Returns the uuid, buying power, number of open positions, and liveness of every account on the broker without
+    /// cloning their ledgers.
+    pub fn accounts_summary(&self) -> Vec<(Uuid, usize, usize, bool)> {
+        self.accounts.iter()
+            .map(|(&uuid, acct)| (uuid, acct.ledger.buying_power, acct.ledger.open_positions.len(), acct.live))
+            .collect()
+    }
+
+    /// Sets the contract multiplier of a non-FX symbol, the amount that its price is multiplied by to get the
+    /// notional value of one unit.
+    pub fn set_contract_multiplier(&mut self, name: &String, multiplier: usize) -> BrokerResult {
+        if !self.symbols.contains(name) {
+            return Err(BrokerError::NoSuchSymbol);
+        }
+        self.symbols[name].metadata.contract_multiplier = multiplier;
+        Ok(BrokerMessage::Success)
+    }
+
+    /// Declares a trading halt on a symbol lasting from `start` (inclusive) to `end` (exclusive).  While the halt is
+    /// in effect, market opens and closes on the symbol are rejected and no orders, stops, or take profits on it
+    /// are filled.
+    pub fn add_trading_halt(&mut self, name: &String, start: u64, end: u64) -> BrokerResult {
+        if !self.symbols.contains(name) {
+            return Err(BrokerError::NoSuchSymbol);
+        }
+        if end <= start {
+            return Err(BrokerError::Message{
+                message: String::from("A trading halt must end after it starts."),
+            });
+        }
+        self.symbols[name].metadata.halts.push((start, end));
+        Ok(BrokerMessage::Success)
+    }
+
+    /// Returns an error if trading in the symbol with the supplied index is currently halted.
+    fn check_halt(&self, symbol_ix: usize) -> Result<(), BrokerError> {
+        let sym = &self.symbols[symbol_ix];
+        if sym.is_halted(self.timestamp) {
+            return Err(BrokerError::SymbolHalted{symbol: sym.name.clone()});
+        }
+        Ok(())
     }
 
-    /// Cancels the pending position.
-    pub fn cancel_order(&mut self, account_uuid: Uuid, order_uuid: Uuid) -> BrokerResult {
-        let new_buying_power;
-        let res = {
-            let account = match self.accounts.entry(account_uuid) {
-                Entry::Occupied(o) => o.into_mut(),
-                Entry::Vacant(_) => {
-                    return Err(BrokerError::NoSuchAccount);
-                },
+    /// Realizes the profit or loss of all open positions at current market prices and resets their entry prices to
+    /// the settlement price.  The funds reserved for the positions are left untouched.
+    fn settle_positions(&mut self) {
+        let mut settlements = Vec::new();
+        for (&acct_uuid, account) in self.accounts.data.iter() {
+            for (&pos_uuid, pos) in account.ledger.open_positions.iter() {
+                let (bid, ask) = match self.get_price(pos.symbol_id) {
+                    Ok(price) => price,
+                    Err(_) => continue,
+                };
+                let settlement_price = if pos.long { bid } else { ask };
+                match self.get_position_pnl(pos, settlement_price) {
+                    Ok(pnl) => settlements.push((acct_uuid, pos_uuid, settlement_price, pnl)),
+                    Err(err) => self.logger.error_log(&format!("Unable to settle position {}: {:?}", pos_uuid, err)),
+                }
+            }
+        }
+        // settle in a fixed order so that the resulting messages and statistics are reproducible
+        settlements.sort_by_key(|&(acct_uuid, pos_uuid, _, _)| (acct_uuid, pos_uuid));
+
+        for (acct_uuid, pos_uuid, settlement_price, pnl) in settlements {
+            self.stats.entry(acct_uuid).or_insert_with(AccountStats::default)
+                .record_pnl(pnl, self.settings.pnl_minor_unit, false);
+            let (pos, new_buying_power) = {
+                let ledger = &mut self.accounts.data.get_mut(&acct_uuid).unwrap().ledger;
+                ledger.realize_pnl(pnl);
+                let pos = ledger.open_positions.get_mut(&pos_uuid).unwrap();
+                pos.execution_price = Some(settlement_price);
+                (pos.clone(), ledger.buying_power)
             };
-            // attempt to cancel the order and remove it from the hashmaps
-            let res = account.ledger.cancel_order(order_uuid, self.timestamp);
-            new_buying_power = account.ledger.buying_power;
-            res
-        };
+            self.accounts.position_modified(&pos, pos_uuid);
+            self.buying_power_changed(acct_uuid, new_buying_power);
+        }
+    }
 
-        // if it was successful, remove the position from the `pending` cache
-        // also send notification of ledger buying power change
-        match res {
-            Ok(ref msg) => {
-                match msg {
-                    &BrokerMessage::OrderCancelled{ ref order, order_id: _, timestamp: _ } => {
-                        self.accounts.order_cancelled(order_uuid, order.symbol_id);
-                        self.buying_power_changed(account_uuid, new_buying_power);
+    /// Charges all open positions financing on their borrowed portions for the supplied number of days, adding it to
+    /// the fees recorded for them.  Only the margin required at `leverage` is considered to be funded by the account.
+    fn charge_financing(&mut self, days: u64) {
+        let leverage = ::std::cmp::max(self.settings.leverage, 1);
+        let mut charges = Vec::new();
+        for (&acct_uuid, account) in self.accounts.data.iter() {
+            for (&pos_uuid, pos) in account.ledger.open_positions.iter() {
+                match self.get_position_value(pos) {
+                    Ok(value) => {
+                        let borrowed = value - value / leverage;
+                        let charge = (borrowed as f64 * self.settings.daily_financing_rate * days as f64) as usize;
+                        charges.push((acct_uuid, pos_uuid, charge));
                     },
-                    _ => unreachable!(),
+                    Err(err) => self.logger.error_log(&format!("Unable to finance position {}: {:?}", pos_uuid, err)),
                 }
-            },
-            Err(_) => (),
+            }
+        }
+        // charge in a fixed order so that the resulting messages are reproducible
+        charges.sort_by_key(|&(acct_uuid, pos_uuid, _)| (acct_uuid, pos_uuid));
+
+        for (acct_uuid, pos_uuid, charge) in charges {
+            let (pos, new_buying_power) = {
+                let ledger = &mut self.accounts.data.get_mut(&acct_uuid).unwrap().ledger;
+                let charged = ledger.charge_fee(charge);
+                let pos = ledger.open_positions.get_mut(&pos_uuid).unwrap();
+                pos.fees += charged;
+                (pos.clone(), ledger.buying_power)
+            };
+            self.accounts.position_modified(&pos, pos_uuid);
+            self.buying_power_changed(acct_uuid, new_buying_power);
         }
+    }
 
-        res
+    /// Returns an error if the symbol with the supplied index is a streamed symbol that hasn't received enough data
+    /// yet to satisfy the `warmup_ticks` and `warmup_ns` settings.
+    fn check_warmup(&self, symbol_ix: usize) -> Result<(), BrokerError> {
+        let sym = &self.symbols[symbol_ix];
+        // symbols with static prices have no data to warm up on
+        if sym.input_iter.is_none() {
+            return Ok(());
+        }
+
+        let warmup_ns = self.settings.warmup_ns;
+        let warming_up = sym.ticks_processed < self.settings.warmup_ticks || (warmup_ns != 0 && match sym.first_tick_time {
+            Some(first_tick_time) => self.timestamp < first_tick_time + warmup_ns,
+            None => true,
+        });
+        if warming_up {
+            return Err(BrokerError::WarmingUp{symbol: sym.name.clone()});
+        }
+        Ok(())
     }
 
-    /// Modifies the stop loss or take profit of a position.  SL and TP are double option-wrapped; the outer
-    /// option indicates if they should be changed and the inner option indicates if the value should be set
-    /// or not (`Some(None)` indicates that the current SL should be removed, for example).
-    fn modify_position(
-        &mut self, account_id: Uuid, position_uuid: Uuid, sl: Option<Option<usize>>, tp: Option<Option<usize>>
-    ) -> BrokerResult {
-        let res = {
-            let account = match self.accounts.entry(account_id) {
-                Entry::Occupied(o) => o.into_mut(),
-                Entry::Vacant(_) => {
-                    return Err(BrokerError::NoSuchAccount);
-                },
-            };
-            account.ledger.modify_position(position_uuid, sl, tp, self.timestamp)
-        };
+    /// Sets the widest spread at which market orders on a symbol are executed, overriding the `max_spread` setting.
+    /// 0 disables the check for the symbol.
+    pub fn set_max_spread(&mut self, name: &String, max_spread: usize) -> BrokerResult {
+        if !self.symbols.contains(name) {
+            return Err(BrokerError::NoSuchSymbol);
+        }
+        self.symbols[name].metadata.max_spread = Some(max_spread);
+        Ok(BrokerMessage::Success)
+    }
 
-        // TODO: Check if the new SL/TP make the position meet closure conditions and if they do, close it
+    /// Sets the timestamp after which new positions can't be opened in a symbol, overriding the
+    /// `open_cutoff_timestamp` setting.  0 disables the cutoff for the symbol.
+    pub fn set_open_cutoff(&mut self, name: &String, cutoff: u64) -> BrokerResult {
+        if !self.symbols.contains(name) {
+            return Err(BrokerError::NoSuchSymbol);
+        }
+        self.symbols[name].metadata.open_cutoff = Some(cutoff);
+        Ok(BrokerMessage::Success)
+    }
 
-        // if the position was actually modified, remove it from the cache
-        match res {
-            Ok(ref message) => match message {
-                &BrokerMessage::PositionModified{position: ref pos, position_id: pos_uuid, timestamp: _} => {
-                    self.accounts.position_modified(pos, pos_uuid);
-                },
-                _ => (),
-            },
-            Err(_) => (),
+    /// Sets whether or not short positions can be opened in a symbol.
+    pub fn set_allow_short(&mut self, name: &String, allow_short: bool) -> BrokerResult {
+        if !self.symbols.contains(name) {
+            return Err(BrokerError::NoSuchSymbol);
         }
-        res
+        self.symbols[name].metadata.allow_short = allow_short;
+        Ok(BrokerMessage::Success)
     }
 
-    /// Dumps the SimBroker state to a file that can be resumed later.
-    fn dump_to_file(&mut self, filename: &str) {
-        unimplemented!(); // TODO
+    /// Sets the largest number of units that a single position in a symbol can hold.  0 disables the limit.
+    pub fn set_max_position_size(&mut self, name: &String, max_position_size: usize) -> BrokerResult {
+        if !self.symbols.contains(name) {
+            return Err(BrokerError::NoSuchSymbol);
+        }
+        self.symbols[name].metadata.max_position_size = max_position_size;
+        Ok(BrokerMessage::Success)
     }
 
-    /// Used for Forex exchange rate conversions.  The cost to open a position is determined
-    /// by the exchange rate between the base currency and the primary currency of the pair.
-    /// A decimal precision of 10 is used for all returned results.
-    ///
-    /// Gets the conversion rate (in pips) between the base currency of the simbroker and
-    /// the supplied currency.  If the base currency is USD and AUD is provided, the exchange
-    /// rate for AUD/USD will be returned.  Returns Err if we lack the data to do that.  Results
-    /// are returned with the specified decimal precision.
-    fn get_base_rate(&self, currency: &str, desired_decimals: usize) -> Result<usize, BrokerError> {
-        if !self.settings.fx {
-            return Err(BrokerError::Message{
-                message: String::from("Can only convert to base rate when in FX mode.")
-            });
+    /// Sets the model used to assign synthetic volumes to the ticks of a symbol, replacing any existing one.  Only
+    /// ticks processed after it's set are assigned volumes.
+    pub fn set_volume_model(&mut self, name: &String, model: VolumeModel) -> BrokerResult {
+        if !self.symbols.contains(name) {
+            return Err(BrokerError::NoSuchSymbol);
         }
+        self.symbols[name].volume_gen = Some(VolumeGenerator::new(model));
+        Ok(BrokerMessage::Success)
+    }
 
-        let base_currency = &self.settings.fx_base_currency;
-        let base_pair = format!("{}{}", currency, base_currency);
+    /// Returns the volume-weighted average mid price of all ticks of a symbol that have been assigned volumes.
+    pub fn vwap(&self, name: &String) -> Result<usize, BrokerError> {
+        if !self.symbols.contains(name) {
+            return Err(BrokerError::NoSuchSymbol);
+        }
+        self.symbols[name].vwap().ok_or(BrokerError::NoDataAvailable)
+    }
 
-        let (_, ask, decimals) = if !self.symbols.contains(&base_pair) {
-            // try reversing the order or the pairs
-            let base_pair_reverse = format!("{}{}", base_currency, currency);
-            if !self.symbols.contains(&base_pair_reverse) {
-                return Err(BrokerError::NoDataAvailable);
-            } else {
-                self.symbols[&base_pair_reverse].get_price()
-            }
-        } else {
-            self.symbols[&base_pair].get_price()
+    /// Returns an error if a new stop or take profit for a position is already past the price the position would
+    /// be closed at.  If the position's symbol has no price yet, the modification is either allowed without being
+    /// validated or rejected with `NoDataAvailable` depending on the `unpriced_modify_handling` setting.
+    fn check_modify_prices(
+        &mut self, account_uuid: Uuid, pos_uuid: Uuid, stop: Option<usize>, take_profit: Option<usize>
+    ) -> Result<(), BrokerError> {
+        let (symbol_ix, long) = match self.accounts.get(&account_uuid) {
+            Some(acct) => match acct.ledger.open_positions.get(&pos_uuid) {
+                Some(pos) => (pos.symbol_id, pos.long),
+                None => return Err(acct.ledger.missing_position_error(pos_uuid)),
+            },
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+        let (bid, ask) = match self.get_price(symbol_ix) {
+            Ok(price) => price,
+            Err(err) => {
+                if self.settings.unpriced_modify_handling == UnpricedModifyHandling::Reject {
+                    return Err(err);
+                }
+                let ts_string = self.timestamp.to_string();
+                let msg = format!(
+                    "Modifying position {} without validating it because {} has no price", pos_uuid, self.symbols[symbol_ix].name
+                );
+                self.cs.warning(Some(&ts_string), &msg);
+                return Ok(());
+            },
         };
 
-        Ok(convert_decimals(ask, decimals, desired_decimals))
+        let close_price = if long { bid } else { ask };
+        if let Some(stop) = stop {
+            if (long && stop >= close_price) || (!long && stop <= close_price) {
+                return Err(BrokerError::InvalidStopValue);
+            }
+        }
+        if let Some(take_profit) = take_profit {
+            if (long && take_profit <= close_price) || (!long && take_profit >= close_price) {
+                return Err(BrokerError::InvalidTakeProfitValue);
+            }
+        }
+
+        Ok(())
     }
 
-    /// Returns the value of a position in units of base currency, not taking into account leverage.
-    fn get_position_value(&self, pos: &Position) -> Result<usize, BrokerError> {
-        let ix = pos.symbol_id;
+    /// Returns an error if the position being opened is short and shorting is disabled for the symbol with the
+    /// supplied index.
+    fn check_short(&self, symbol_ix: usize, long: bool) -> Result<(), BrokerError> {
+        let sym = &self.symbols[symbol_ix];
+        if !long && !sym.metadata.allow_short {
+            return Err(BrokerError::ShortingDisabled{symbol: sym.name.clone()});
+        }
+        Ok(())
+    }
 
-        let sym = &self.symbols[ix];
-        if sym.is_fx() {
-            let base_rate: usize = self.get_base_rate(&sym.name[0..3], sym.metadata.decimal_precision)?;
-            Ok(pos.size * base_rate * self.settings.fx_lot_size)
+    /// Verifies that the values of a position make sense, only checking the ordering of its stop and take profit
+    /// if the `strict_brackets` setting is enabled.
+    fn check_position(&self, pos: &Position) -> Result<(), BrokerError> {
+        if self.settings.strict_brackets {
+            pos.check_sanity()
         } else {
-            Ok(pos.size)
+            pos.check_execution_data()
+        }
+    }
+
+    /// Returns an error if a position of `size` units is larger than the symbol's `max_position_size` allows.
+    fn check_position_size(&self, symbol_ix: usize, size: usize) -> Result<(), BrokerError> {
+        let sym = &self.symbols[symbol_ix];
+        let max_position_size = sym.metadata.max_position_size;
+        if max_position_size != 0 && size > max_position_size {
+            return Err(BrokerError::PositionTooLarge{symbol: sym.name.clone(), max_position_size: max_position_size});
+        }
+        Ok(())
+    }
+
+    /// Returns an error if a market order of `size` units is larger than `max_liquidity_fraction` of the volume of
+    /// the last tick of the symbol with the supplied index.  Symbols without a volume model aren't checked.
+    fn check_liquidity(&self, symbol_ix: usize, size: usize) -> Result<(), BrokerError> {
+        let sym = &self.symbols[symbol_ix];
+        let fraction = self.settings.max_liquidity_fraction;
+        if fraction == 0.0 || sym.volume_gen.is_none() {
+            return Ok(());
+        }
+
+        let max_size = (sym.last_volume as f64 * fraction) as usize;
+        if size > max_size {
+            return Err(BrokerError::InsufficientLiquidity{symbol: sym.name.clone(), max_size: max_size});
+        }
+        Ok(())
+    }
+
+    /// Returns an error if the current time is past the open cutoff of the symbol with the supplied index.
+    fn check_open_cutoff(&self, symbol_ix: usize) -> Result<(), BrokerError> {
+        let sym = &self.symbols[symbol_ix];
+        let cutoff = sym.metadata.open_cutoff.unwrap_or(self.settings.open_cutoff_timestamp);
+        if cutoff != 0 && self.timestamp > cutoff {
+            return Err(BrokerError::PastOpenCutoff{symbol: sym.name.clone(), cutoff: cutoff});
+        }
+        Ok(())
+    }
+
+    /// Returns an error if the spread of the symbol with the supplied index is wider than its maximum spread.
+    fn check_spread(&self, symbol_ix: usize, bid: usize, ask: usize) -> Result<(), BrokerError> {
+        let sym = &self.symbols[symbol_ix];
+        let max_spread = sym.metadata.max_spread.unwrap_or(self.settings.max_spread);
+        let spread = ask.saturating_sub(bid);
+        if max_spread != 0 && spread > max_spread {
+            return Err(BrokerError::SpreadTooWide{
+                symbol: sym.name.clone(),
+                spread: spread,
+                max_spread: max_spread,
+            });
         }
+        Ok(())
     }
 
     /// Called every price update the broker receives.  It simulates some kind of market activity on the simulated exchange
@@ -745,26 +2907,57 @@ impl SimBroker {
     /// All actions that take place here are guarenteed to succeed since they are simulated as taking place within the
     /// brokerage itself.  All `BrokerMessage`s generated by any actions that take place are sent through the supplied
     /// push stream handle to the client.  The returned value is how many push messages were sent to the client
-    /// during this tick.
+    /// during this tick.  Positions closed by the same tick are closed, and have their messages sent, in order of
+    /// creation time and then UUID.
     pub fn tick_positions(
         &mut self, symbol_id: usize, price: (usize, usize), cur_index: usize, buffer: &mut Vec<TickOutput>
     ) -> usize {
         let (bid, ask) = price;
         let mut push_msg_count = 0;
+        // nothing is filled while trading is halted; it'll be checked again on the first tick after the halt lifts
+        if self.symbols[symbol_id].is_halted(self.timestamp) {
+            return push_msg_count;
+        }
         // check if any pending orders should be closed, modified, or opened
         // manually keep track of the index because we remove things from the vector dynamically
         let mut i = 0;
         while i < self.accounts.positions[symbol_id].pending.len() {
+            // cancel the order without checking if it can be filled if its time in force has elapsed
+            let (expired, pos_uuid, acct_uuid) = {
+                let cached_pos = &self.accounts.positions[symbol_id].pending[i];
+                (cached_pos.pos.is_expired(self.timestamp), cached_pos.pos_uuid, cached_pos.acct_uuid)
+            };
+            if expired {
+                let push_msg = self.accounts.data.get_mut(&acct_uuid).unwrap().ledger.cancel_order(pos_uuid, self.timestamp);
+                // this should always succeed
+                assert!(push_msg.is_ok());
+                // remove it from the pending cache and release the funds reserved for it
+                self.pegs.remove(&pos_uuid);
+                self.accounts.order_cancelled(pos_uuid, symbol_id);
+                let new_buying_power = self.accounts.data[&acct_uuid].ledger.buying_power;
+                self.buying_power_changed(acct_uuid, new_buying_power);
+                // send the push message to the client
+                self.push_msg(push_msg.clone());
+                buffer[cur_index + push_msg_count] = TickOutput::Pushstream(self.timestamp, push_msg);
+                push_msg_count += 1;
+                // don't increment `i` since the cache shrunk
+                continue;
+            }
+
             let push_msg_opt = {
                 let &CachedPosition { pos_uuid, acct_uuid, ref pos } = &self.accounts.positions[symbol_id].pending[i];
-                match pos.is_open_satisfied(bid, ask) {
+                match self.pending_fill_price(pos_uuid, pos, bid, ask) {
                     Some(open_price) => {
+                        let open_fee = self.fee_model.open_fee(pos);
                         // if the position should be opened, remove it from the pending `HashMap` and the cache and open it.
                         let mut ledger = &mut self.accounts.data.get_mut(&acct_uuid).unwrap().ledger;
                         // remove from the hashmap
                         let mut hm_pos = ledger.pending_positions.remove(&pos_uuid).unwrap();
                         hm_pos.execution_price = Some(open_price);
                         hm_pos.execution_time = Some(self.timestamp);
+                        // the funds for the position itself were reserved when the order was placed
+                        hm_pos.fees += open_fee;
+                        ledger.charge_fee(open_fee);
 
                         Some(ledger.open_position(pos_uuid, hm_pos))
                     },
@@ -775,7 +2968,8 @@ impl SimBroker {
             i += 1;
 
             match push_msg_opt {
-                Some(Ok(BrokerMessage::PositionOpened{position_id: _, position: ref hm_pos, timestamp: _})) => {
+                Some(Ok(BrokerMessage::PositionOpened{position_id, position: ref hm_pos, timestamp: _})) => {
+                    self.pegs.remove(&position_id);
                     // remove from the pending cache
                     let mut cached_pos = self.accounts.positions[symbol_id].pending.remove(i-1);
                     // update the cached position with the one with execution data
@@ -789,12 +2983,19 @@ impl SimBroker {
                     assert!(push_msg.is_ok());
                     // add it to the open cache
                     self.accounts.positions[symbol_id].open.push(cached_pos);
-                    // send the push message to the client
-                    self.push_msg(Ok(push_msg.as_ref().unwrap().clone()));
-                    // put the new tick into the buffer to be returned to the client
-                    let output = TickOutput::Pushstream(self.timestamp, Ok(push_msg.as_ref().unwrap().clone()));
-                    buffer[cur_index + push_msg_count] = output;
-                    push_msg_count += 1;
+                    if self.settings.confirmation_latency_ns == 0 {
+                        // send the push message to the client
+                        self.push_msg(Ok(push_msg.as_ref().unwrap().clone()));
+                        // put the new tick into the buffer to be returned to the client
+                        let output = TickOutput::Pushstream(self.timestamp, Ok(push_msg.as_ref().unwrap().clone()));
+                        buffer[cur_index + push_msg_count] = output;
+                        push_msg_count += 1;
+                    } else {
+                        // the fill has happened, but the client doesn't find out about it until later
+                        let notification = push_msg.as_ref().unwrap().clone();
+                        let notify_time = self.timestamp + self.settings.confirmation_latency_ns;
+                        self.pq.push(QueueItem::new(notify_time, WorkUnit::Notification(Ok(notification))));
+                    }
                     // decrement i since we modified the cache
                     i -= 1;
                 },
@@ -803,56 +3004,207 @@ impl SimBroker {
                 None => (),
             }
         }
-
-        // check if any open positions should be closed or modified
-        let mut i = 0;
-        while i < self.accounts.positions[symbol_id].open.len() {
-            let mut new_buying_power = 0;
-            let push_msg_opt: Option<(usize, BrokerResult)> = {
-                let &CachedPosition { pos_uuid, acct_uuid, ref pos } = &self.accounts.positions[symbol_id].open[i];
-                match pos.is_close_satisfied(bid, ask) {
-                    Some((closure_price, closure_reason)) => {
-                        let pos_value = self.get_position_value(&pos).expect("Unable to get position value for pending position!");
-                        // if the position should be closed, remove it from the cache.
-                        let mut ledger = &mut self.accounts.data.get_mut(&acct_uuid).unwrap().ledger;
-
-                        let res = ledger.close_position(pos_uuid, pos_value, self.timestamp, closure_reason);
-                        new_buying_power = ledger.buying_power;
-                        Some((closure_price, res))
+        // pegged orders that weren't filled follow the market for the next tick
+        self.update_pegs(symbol_id, bid, ask);
+        // move the stops of positions that have made enough profit to break-even
+        push_msg_count += self.update_breakevens(symbol_id, bid, ask, cur_index + push_msg_count, buffer);
+        // fill the resting exit orders of positions that the market has reached
+        push_msg_count += self.fill_limit_closes(symbol_id, bid, ask, cur_index + push_msg_count, buffer);
+        // trailing stops follow the market if it's moved in the positions' favor
+        push_msg_count += self.update_trailing_stops(symbol_id, bid, ask, cur_index + push_msg_count, buffer);
+
+        // find all open positions that should be closed at this price, including ones whose stops were triggered
+        // by earlier ticks but haven't been completely filled yet.  They're closed in order of creation time and
+        // then UUID so that the resulting push messages are always delivered in the same order.
+        let touch_triggers = self.settings.touch_triggers;
+        let fill_size = self.settings.fill_size_per_tick;
+        let mut closures: Vec<(u64, Uuid, Uuid, bool, usize, PositionClosureReason)> = self.accounts.positions[symbol_id].open
+            .iter()
+            .filter_map(|&CachedPosition { pos_uuid, acct_uuid, ref pos }| {
+                let closure = if self.stop_fills.contains(&pos_uuid) {
+                    Some((if pos.long { bid } else { ask }, PositionClosureReason::StopLoss))
+                } else {
+                    pos.is_close_satisfied(bid, ask, touch_triggers)
+                };
+                closure.map(|(closure_price, closure_reason)| {
+                    let stopped = match pos.stop {
+                        Some(stop) => if pos.long { closure_price <= stop } else { closure_price >= stop },
+                        None => false,
+                    };
+                    // stops of positions too large to be filled by one tick are filled in chunks over the
+                    // following ticks
+                    let partial_fill = stopped && fill_size != 0 && pos.size > fill_size;
+                    (pos.creation_time, pos_uuid, acct_uuid, partial_fill, closure_price, closure_reason)
+                })
+            }).collect();
+        closures.sort_by_key(|&(creation_time, pos_uuid, _, _, _, _)| (creation_time, pos_uuid));
+
+        for (_, pos_uuid, acct_uuid, partial_fill, closure_price, closure_reason) in closures {
+            let push_msg = if partial_fill {
+                self.stop_fills.insert(pos_uuid);
+                let push_msg = self.reduce_position_at(acct_uuid, pos_uuid, fill_size, closure_price);
+                match push_msg {
+                    Ok(BrokerMessage::PositionModified{ref position, ..}) |
+                    Ok(BrokerMessage::PositionPartiallyClosed{ref position, ..}) => {
+                        self.accounts.position_modified(position, pos_uuid);
                     },
-                    None => None,
+                    _ => (),
+                }
+                push_msg
+            } else {
+                let push_msg = self.close_position_at(acct_uuid, pos_uuid, closure_price, closure_reason);
+                // remove from the open cache
+                if let Ok(BrokerMessage::PositionClosed{ref position, ..}) = push_msg {
+                    self.position_closed(position, pos_uuid);
                 }
+                push_msg
             };
+            let new_buying_power = self.accounts.data[&acct_uuid].ledger.buying_power;
+            // this should always succeed
+            assert!(push_msg.is_ok());
+            // send notification of ledger buying power change to client
+            let buying_power_notification = BrokerMessage::LedgerBalanceChange{
+                account_uuid: acct_uuid,
+                new_buying_power: new_buying_power,
+            };
+            let output = TickOutput::Pushstream(self.timestamp, Ok(buying_power_notification));
+            // add the message to the buffer and increment the length
+            buffer[cur_index + push_msg_count] = output;
+            push_msg_count += 1;
+            // send the push message to the client
+            self.push_msg(push_msg.clone());
+            // put the new tick into the buffer to be returned to the client
+            let output = TickOutput::Pushstream(self.timestamp, push_msg);
+            // add the message to the buffer and increment the length
+            buffer[cur_index + push_msg_count] = output;
+            push_msg_count += 1;
+        }
 
-            i += 1;
+        push_msg_count += self.check_margin(cur_index + push_msg_count, buffer);
 
-            if push_msg_opt.is_some() {
-                let (closure_price, push_msg) = push_msg_opt.unwrap();
-                // remove from the open cache
-                let mut cached_pos = self.accounts.positions[symbol_id].open.remove(i-1);
-                cached_pos.pos.exit_price = Some(closure_price);
-                cached_pos.pos.exit_time = Some(self.timestamp);
-                // this should always succeed
-                assert!(push_msg.is_ok());
-                // send notification of ledger buying power change to client
-                let buying_power_notification = BrokerMessage::LedgerBalanceChange{
-                    account_uuid: cached_pos.acct_uuid,
-                    new_buying_power: new_buying_power,
+        push_msg_count
+    }
+
+    /// Fully closes an open position in the ledger at the supplied price, crediting the account with its value, PnL,
+    /// and fees.  PnL is converted to the base currency at the exchange rate at the time of the close.  Doesn't touch
+    /// the position cache.
+    fn close_position_at(
+        &mut self, account_uuid: Uuid, pos_uuid: Uuid, closure_price: usize, reason: PositionClosureReason
+    ) -> BrokerResult {
+        let pos = match self.accounts.data.get(&account_uuid) {
+            Some(acct) => match acct.ledger.open_positions.get(&pos_uuid) {
+                Some(pos) => pos.clone(),
+                None => return Err(acct.ledger.missing_position_error(pos_uuid)),
+            },
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+        let pos_value = self.released_value(account_uuid, pos_uuid, &pos, 0)?;
+        let pnl = self.get_position_pnl(&pos, closure_price)?;
+        let close_fees = self.get_close_fees(&pos);
+        let minor_unit = self.settings.pnl_minor_unit;
+        let reported_pnl = match self.stats.get(&account_uuid) {
+            Some(stats) => stats.reported_pnl(pnl, minor_unit),
+            None => AccountStats::default().reported_pnl(pnl, minor_unit),
+        };
+        let timestamp = self.timestamp;
+
+        let res = {
+            let ledger = &mut self.accounts.data.get_mut(&account_uuid).unwrap().ledger;
+            let mut res = ledger.close_position(
+                pos_uuid, apply_pnl(pos_value, pnl - close_fees as i64), reported_pnl, timestamp, reason
+            );
+            // only charge the closing fees and mark the exit once the close has gone through
+            let record_close = |pos: &mut Position| {
+                pos.fees += close_fees;
+                pos.exit_price = Some(closure_price);
+                pos.exit_time = Some(timestamp);
+            };
+            if let Ok(BrokerMessage::PositionClosed{ref mut position, ..}) = res {
+                record_close(position);
+                record_close(ledger.closed_positions.get_mut(&pos_uuid).unwrap());
+            }
+            res
+        };
+        if res.is_ok() {
+            self.stats.entry(account_uuid).or_insert_with(AccountStats::default).record_pnl(pnl, minor_unit, true);
+        }
+        res
+    }
+
+    /// Returns `true` if the margin level of an account is below `margin_call_level`.
+    fn below_margin_call_level(&self, account_uuid: Uuid) -> bool {
+        match self.margin_summary(account_uuid) {
+            Ok(MarginSummary{margin_level: Some(level), ..}) => level < self.settings.margin_call_level as f64,
+            _ => false,
+        }
+    }
+
+    /// Checks the margin level of every account.  If an account's margin level has been below `margin_call_level`
+    /// for longer than `margin_call_grace_ns`, its positions are closed at market price, largest loss first, until
+    /// its margin level is back above it.  Accounts that recover within the grace period have their timers reset.
+    /// Returns the number of push messages generated.
+    fn check_margin(&mut self, cur_index: usize, buffer: &mut Vec<TickOutput>) -> usize {
+        let mut push_msg_count = 0;
+        if self.settings.margin_call_level == 0 {
+            return push_msg_count;
+        }
+
+        // accounts and positions are processed in a fixed order so that runs are reproducible
+        let mut acct_uuids: Vec<Uuid> = self.accounts.data.keys().cloned().collect();
+        acct_uuids.sort();
+        for acct_uuid in acct_uuids {
+            if !self.below_margin_call_level(acct_uuid) {
+                self.margin_breaches.remove(&acct_uuid);
+                continue;
+            }
+
+            // start the grace timer if this is a new breach
+            if self.settings.notices.margin_warning && !self.margin_breaches.contains_key(&acct_uuid) {
+                let msg = format!("Account {} has fallen below the margin call level", acct_uuid);
+                push_msg_count += self.send_notice(msg, cur_index + push_msg_count, buffer);
+            }
+            let breach_start = *self.margin_breaches.entry(acct_uuid).or_insert(self.timestamp);
+            if self.timestamp - breach_start < self.settings.margin_call_grace_ns {
+                continue;
+            }
+
+            self.margin_breaches.remove(&acct_uuid);
+            let mut pos_uuids: Vec<(i64, u64, Uuid)> = self.accounts.data[&acct_uuid].ledger.open_positions.iter()
+                .map(|(&pos_uuid, pos)| (self.get_unrealized_pnl(pos).unwrap_or(0), pos.creation_time, pos_uuid))
+                .collect();
+            pos_uuids.sort();
+            for (_, _, pos_uuid) in pos_uuids {
+                if !self.below_margin_call_level(acct_uuid) {
+                    break;
+                }
+                let pos = self.accounts.data[&acct_uuid].ledger.open_positions[&pos_uuid].clone();
+                let closure_price = match self.get_price(pos.symbol_id) {
+                    Ok((bid, ask)) => if pos.long { bid } else { ask },
+                    Err(err) => {
+                        self.logger.error_log(&format!("Unable to liquidate position {}: {:?}", pos_uuid, err));
+                        continue;
+                    },
                 };
-                let output = TickOutput::Pushstream(self.timestamp, Ok(buying_power_notification));
-                // add the message to the buffer and increment the length
-                buffer[cur_index + push_msg_count] = output;
-                push_msg_count += 1;
+                let push_msg = self.close_position_at(acct_uuid, pos_uuid, closure_price, PositionClosureReason::MarginCall);
+                match push_msg {
+                    Ok(BrokerMessage::PositionClosed{ref position, position_id, ..}) => {
+                        self.position_closed(position, position_id);
+                    },
+                    _ => self.logger.error_log(&format!("Unable to liquidate position {}: {:?}", pos_uuid, push_msg)),
+                }
                 // send the push message to the client
                 self.push_msg(push_msg.clone());
-                // put the new tick into the buffer to be returned to the client
-                let output = TickOutput::Pushstream(self.timestamp, push_msg);
-                // add the message to the buffer and increment the length
-                buffer[cur_index + push_msg_count] = output;
+                buffer[cur_index + push_msg_count] = TickOutput::Pushstream(self.timestamp, push_msg);
                 push_msg_count += 1;
-                // decrement i since we modified the cache
-                i -= 1;
             }
+
+            // send notification of ledger buying power change to client
+            let buying_power_notification = BrokerMessage::LedgerBalanceChange{
+                account_uuid: acct_uuid,
+                new_buying_power: self.accounts.data[&acct_uuid].ledger.buying_power,
+            };
+            buffer[cur_index + push_msg_count] = TickOutput::Pushstream(self.timestamp, Ok(buying_power_notification));
+            push_msg_count += 1;
         }
 
         push_msg_count
@@ -871,6 +3223,8 @@ impl SimBroker {
         if self.symbols.contains(&name) {
             self.symbols[&name].price = price;
         } else {
+            // allocate space for open positions of the new symbol in `Accounts`
+            self.accounts.add_symbol();
             let symbol = Symbol::new_oneshot(price, is_fx, decimal_precision, name.clone());
             self.symbols.add(name, symbol).expect("Unable to set oneshot price for new symbol");
         }
@@ -880,34 +3234,234 @@ impl SimBroker {
     pub fn get_ledger_clone(&mut self, account_uuid: Uuid) -> Result<Ledger, BrokerError> {
         match self.accounts.get(&account_uuid) {
             Some(acct) => Ok(acct.ledger.clone()),
-            None => Err(BrokerError::Message{
-                message: "No account exists with that UUID.".to_string()
-            }),
+            None => Err(BrokerError::NoSuchAccount),
+        }
+    }
+
+    /// Returns the ledger of an account, including its balance and all of its positions, serialized as pretty
+    /// JSON or an error if it doesn't exist.
+    pub fn account_json(&self, account_uuid: Uuid) -> Result<String, BrokerError> {
+        let acct = match self.accounts.data.get(&account_uuid) {
+            Some(acct) => acct,
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+
+        // going through a `Value` sorts the keys of the position maps so the output is the same for identical ledgers
+        serde_json::to_value(&acct.ledger)
+            .and_then(|val| serde_json::to_string_pretty(&val))
+            .map_err(|err| BrokerError::Message{message: format!("Unable to serialize ledger: {:?}", err)})
+    }
+
+    /// Records the current equity of every account, either in memory or in the equity curve file, and updates the
+    /// drawdown statistics of the account with it.  Accounts whose equity can't be determined, for example because a
+    /// symbol they hold has no price, are skipped.
+    fn sample_equity(&mut self) {
+        let mut account_uuids: Vec<Uuid> = self.accounts.data.keys().cloned().collect();
+        account_uuids.sort();
+        for account_uuid in account_uuids {
+            let equity = match self.margin_summary(account_uuid) {
+                Ok(summary) => summary.equity,
+                Err(_) => continue,
+            };
+            let timestamp = self.timestamp;
+            self.stats.entry(account_uuid).or_insert_with(AccountStats::default).record_equity(timestamp, equity);
+
+            let res = match self.equity_writer {
+                Some(ref mut writer) => writeln!(writer, "{},{},{}", self.timestamp, account_uuid, equity),
+                None => {
+                    self.equity_curves.entry(account_uuid).or_insert_with(Vec::new).push((self.timestamp, equity));
+                    continue;
+                },
+            };
+            if let Err(err) = res {
+                self.cs.error(None, &format!("Unable to write equity sample: {}", err));
+            }
+            self.unflushed_samples += 1;
+        }
+
+        if self.unflushed_samples >= EQUITY_FLUSH_SAMPLES {
+            self.unflushed_samples = 0;
+            if let Some(Err(err)) = self.equity_writer.as_mut().map(|writer| writer.flush()) {
+                self.cs.error(None, &format!("Unable to flush equity curve file: {}", err));
+            }
         }
     }
 
+    /// Returns the `(timestamp, equity)` samples taken of an account so far.  Empty if equity samples are written to
+    /// a file instead; they're flushed to it periodically and when the broker is dropped.
+    pub fn equity_curve(&self, account_uuid: Uuid) -> Result<&[(u64, i64)], BrokerError> {
+        if !self.accounts.data.contains_key(&account_uuid) {
+            return Err(BrokerError::NoSuchAccount);
+        }
+
+        Ok(self.equity_curves.get(&account_uuid).map(|curve| &curve[..]).unwrap_or(&[]))
+    }
+
     /// Registers a data source into the SimBroker.  Ticks from the supplied generator will be
     /// used to upate the SimBroker's internal prices and transmitted to connected clients.
+    ///
+    /// If a canonical precision is configured for the symbol, ticks are converted to it as they're read so that
+    /// all prices for the symbol are comparable regardless of the precision of the source.  If a clock skew is
+    /// configured for the symbol, it's added to the timestamps of its ticks.
     pub fn register_tickstream(
         &mut self, name: String, raw_tickstream: BoxStream<Tick, ()>, is_fx: bool, decimal_precision: usize
     ) -> BrokerResult {
+        let (raw_tickstream, decimal_precision) = if self.settings.precision_detection_ticks != 0 {
+            self.infer_precision(&name, raw_tickstream, decimal_precision)
+        } else {
+            (raw_tickstream, decimal_precision)
+        };
+        let (raw_tickstream, decimal_precision) = match self.precisions.get(&name) {
+            Some(&canonical) if canonical != decimal_precision => {
+                let normalized = raw_tickstream.map(move |t| Tick {
+                    bid: convert_decimals(t.bid, decimal_precision, canonical),
+                    ask: convert_decimals(t.ask, decimal_precision, canonical),
+                    timestamp: t.timestamp,
+                }).boxed();
+                (normalized, canonical)
+            },
+            _ => (raw_tickstream, decimal_precision),
+        };
+        let raw_tickstream = match self.clock_skews.get(&name) {
+            Some(&skew) if skew != 0 => raw_tickstream.map(move |t| Tick {
+                timestamp: skew_timestamp(t.timestamp, skew),
+                ..t
+            }).boxed(),
+            _ => raw_tickstream,
+        };
         // allocate space for open positions of the new symbol in `Accounts`
         self.accounts.add_symbol();
-        let mut sym = Symbol::new_from_stream(raw_tickstream, is_fx, decimal_precision, name.clone());
-        // get the first element out of the tickstream and set the next tick equal to it
-        let first_tick = sym.next().unwrap().unwrap();
-        self.cs.debug(None, &format!("Set first tick for tickstream {}: {:?}", name, &first_tick));
-        sym.next_tick = Some(first_tick);
+        // the first element of the tickstream is pulled out and set as the next tick during construction
+        let sym = Symbol::new_from_stream(raw_tickstream, is_fx, decimal_precision, name.clone());
+        self.debug_log(&format!("Set first tick for tickstream {}: {:?}", name, sym.next_tick.as_ref().unwrap()));
         self.symbols.add(name, sym)
     }
 
-    /// Returns the current price for a given symbol or None if the SimBroker
-    /// doensn't have a price.
-    pub fn get_price(&self, ix: usize) -> Option<(usize, usize)> {
-        if !self.symbols.len() > ix {
-            return Some(self.symbols[ix].price)
+    /// Reads the first `precision_detection_ticks` ticks of a tickstream to infer its decimal precision, falling
+    /// back to `default_precision` if they're ambiguous.  Returns the inferred precision along with a tickstream
+    /// that yields all of the ticks of the original, including the ones that were inspected.
+    fn infer_precision(
+        &mut self, name: &str, raw_tickstream: BoxStream<Tick, ()>, default_precision: usize
+    ) -> (BoxStream<Tick, ()>, usize) {
+        let mut iter = raw_tickstream.wait();
+        let mut inspected = Vec::with_capacity(self.settings.precision_detection_ticks);
+        while inspected.len() < self.settings.precision_detection_ticks {
+            match iter.next() {
+                Some(Ok(tick)) => inspected.push(tick),
+                _ => break,
+            }
+        }
+
+        let precision = match detect_precision(&inspected) {
+            Some(precision) => precision,
+            None => {
+                self.cs.warning(None, &format!(
+                    "Unable to infer the decimal precision of {}; falling back to {}.", name, default_precision
+                ));
+                default_precision
+            },
+        };
+        let ticks = inspected.into_iter().map(|t| Ok(t)).chain(iter);
+        (::futures::stream::iter(ticks).boxed(), precision)
+    }
+
+    /// Registers a symbol whose ticks are received from `tick_rx` in real time, as for paper trading.  Ticks are
+    /// processed as soon as they arrive without any pacing.  Rather than ending once it runs out of events, the
+    /// simulation loop blocks until the next live tick arrives and only ends once all live tickstreams are closed.
+    pub fn register_live_tickstream(
+        &mut self, name: String, tick_rx: UnboundedReceiver<Tick>, is_fx: bool, decimal_precision: usize
+    ) -> BrokerResult {
+        let canonical = self.precisions.get(&name).cloned().unwrap_or(decimal_precision);
+        // allocate space for open positions of the new symbol in `Accounts`
+        self.accounts.add_symbol();
+        let sym = Symbol::new_live(is_fx, canonical, name.clone());
+        self.symbols.add(name, sym)?;
+
+        let ix = self.symbols.len() - 1;
+        let ticks = tick_rx.map(move |t| (ix, Tick {
+            bid: convert_decimals(t.bid, decimal_precision, canonical),
+            ask: convert_decimals(t.ask, decimal_precision, canonical),
+            timestamp: t.timestamp,
+        })).boxed();
+        self.live_ticks = Some(match self.live_ticks.take() {
+            Some(live_ticks) => live_ticks.select(ticks).boxed(),
+            None => ticks,
+        });
+
+        Ok(BrokerMessage::Success)
+    }
+
+    /// Returns the beta of the returns of an account's open positions, weighted by their current values, against
+    /// the returns of a benchmark symbol.  Returns are measured between consecutive ticks of the benchmark in its
+    /// history, so the `tick_history_ns` or `retain_ticks` setting determines the period covered.  Intervals that
+    /// the histories of the positions' symbols don't reach back to are skipped.
+    pub fn portfolio_beta(&self, account_uuid: Uuid, benchmark_symbol: &String) -> Result<f64, BrokerError> {
+        let benchmark_ix = match self.symbols.get_index(benchmark_symbol) {
+            Some(ix) => ix,
+            None => return Err(BrokerError::NoSuchSymbol),
+        };
+        let positions: Vec<Position> = match self.accounts.get(&account_uuid) {
+            Some(acct) => acct.ledger.open_positions.values().cloned().collect(),
+            None => return Err(BrokerError::NoSuchAccount),
+        };
+
+        // short positions gain from falling prices, so their weights are negative
+        let mut weights = Vec::with_capacity(positions.len());
+        let mut total_value = 0.;
+        for pos in positions {
+            let value = self.get_position_value(&pos)? as f64;
+            total_value += value;
+            weights.push((pos.symbol_id, if pos.long { value } else { -value }));
+        }
+        if total_value == 0. {
+            return Err(BrokerError::NoDataAvailable);
+        }
+
+        let mid = |(bid, ask): (usize, usize)| (bid + ask) as f64 / 2.;
+        let mut returns = Vec::new();
+        let mut benchmark_returns = Vec::new();
+        for pair in self.symbols[benchmark_ix].history.windows(2) {
+            let (start, end) = (pair[0].timestamp, pair[1].timestamp);
+            let weighted_returns: Option<Vec<f64>> = weights.iter().map(|&(symbol_ix, weight)| {
+                let symbol = &self.symbols[symbol_ix];
+                match (symbol.price_at(start), symbol.price_at(end)) {
+                    (Some(start_price), Some(end_price)) => {
+                        Some(weight / total_value * (mid(end_price) / mid(start_price) - 1.))
+                    },
+                    _ => None,
+                }
+            }).collect();
+
+            if let Some(weighted_returns) = weighted_returns {
+                returns.push(weighted_returns.iter().sum::<f64>());
+                benchmark_returns.push(mid((pair[1].bid, pair[1].ask)) / mid((pair[0].bid, pair[0].ask)) - 1.);
+            }
+        }
+
+        beta(&returns, &benchmark_returns).ok_or(BrokerError::NoDataAvailable)
+    }
+
+    /// Returns the ticks of a symbol that have been processed by the broker and are still in its history.  All of
+    /// them are available if the `retain_ticks` setting is enabled.
+    pub fn ticks(&self, symbol: &String) -> Result<&[Tick], BrokerError> {
+        match self.symbols.get_index(symbol) {
+            Some(ix) => Ok(&self.symbols[ix].history),
+            None => Err(BrokerError::NoSuchSymbol),
+        }
+    }
+
+    /// Returns the current price for a given symbol.  Returns `NoSuchSymbol` if the SimBroker doesn't know
+    /// of the symbol and `NoDataAvailable` if no tick has arrived for it yet.
+    pub fn get_price(&self, ix: usize) -> Result<(usize, usize), BrokerError> {
+        if ix >= self.symbols.len() {
+            return Err(BrokerError::NoSuchSymbol);
+        }
+
+        let sym = &self.symbols[ix];
+        if !sym.has_price() {
+            return Err(BrokerError::NoDataAvailable);
         }
 
-        None
+        Ok(sym.price)
     }
 }
@@ -0,0 +1,106 @@
+//! Facilities for running many independent simulations at once, for example when sweeping across parameters.
+
+use std::cmp;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+use futures::{Stream, stream};
+use uuid::Uuid;
+
+use tickgrinder_util::trading::tick::Tick;
+use tickgrinder_util::transport::command_server::CommandServer;
+
+use super::*;
+
+/// The maximum number of simulations that `run_parallel` runs at once
+const PARALLEL_WORKERS: usize = 4;
+
+/// The ticks that a simulation started by `run_parallel` is run over as a list of
+/// `(symbol name, ticks, is_fx, decimal precision)`, one for each symbol.
+pub type TickSource = Vec<(String, Vec<Tick>, bool, usize)>;
+
+/// A summary of the state of a simulation after it has run out of events.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunReport {
+    /// The timestamp of the last event processed by the simulation
+    pub end_timestamp: u64,
+    /// The number of ticks processed for each symbol in the same order as the `TickSource`
+    pub ticks_processed: Vec<usize>,
+    /// The buying power of the default account at the end of the simulation
+    pub buying_power: usize,
+    /// The number of positions that were still open in the default account at the end of the simulation
+    pub open_positions: usize,
+    /// Trading statistics of the default account
+    pub stats: AccountStats,
+    /// The seed of the simulation's PRNG; running again with `rng_seed` set to it reproduces the run
+    pub seed: u32,
+}
+
+/// Runs a separate simulation for each of the supplied configurations on a pool of worker threads and returns the
+/// results in the same order as the configurations.  Each `SimBroker` is created on the thread that runs it and
+/// shares no state with the others.
+pub fn run_parallel(configs: Vec<(SimBrokerSettings, TickSource)>) -> Vec<Result<RunReport, BrokerError>> {
+    let job_count = configs.len();
+    let jobs = Arc::new(Mutex::new(configs.into_iter().enumerate().collect::<Vec<_>>()));
+    let (tx, rx) = mpsc::channel();
+
+    for _ in 0..cmp::min(PARALLEL_WORKERS, job_count) {
+        let jobs = jobs.clone();
+        let tx = tx.clone();
+        thread::spawn(move || loop {
+            let job = jobs.lock().unwrap().pop();
+            match job {
+                Some((i, (settings, ticks))) => tx.send((i, run_simulation(settings, ticks))).unwrap(),
+                None => return,
+            }
+        });
+    }
+    // the channel closes once all of the workers have finished
+    drop(tx);
+
+    let mut results: Vec<Option<Result<RunReport, BrokerError>>> = (0..job_count).map(|_| None).collect();
+    for (i, res) in rx.iter() {
+        results[i] = Some(res);
+    }
+    results.into_iter().map(|res| res.expect("A simulation thread panicked before finishing!")).collect()
+}
+
+/// Runs a single simulation over the supplied ticks until it runs out of events and reports on its final state.
+fn run_simulation(mut settings: SimBrokerSettings, ticks: TickSource) -> Result<RunReport, BrokerError> {
+    // all symbols come from the tick source
+    settings.tickstreams = String::from("[]");
+    let (_client_tx, client_rx) = mpsc::channel();
+    let cs = CommandServer::new(Uuid::new_v4(), "SimBroker Parallel Run");
+    let mut sim = SimBroker::new(settings, cs, client_rx)?;
+    let account_uuid = *sim.accounts.data.keys().next().unwrap();
+
+    let mut symbol_indexes = Vec::with_capacity(ticks.len());
+    for (name, symbol_ticks, is_fx, decimals) in ticks {
+        let strm = stream::iter(symbol_ticks.into_iter().map(|t| Ok::<Tick, ()>(t))).boxed();
+        sim.register_tickstream(name.clone(), strm, is_fx, decimals)?;
+        let ix = sim.symbols.get_index(&name).unwrap();
+        // nothing consumes the ticks sent to the client, so drain them to keep the simulation from blocking
+        let client_rx = sim.symbols[ix].client_receiver.take().unwrap();
+        thread::spawn(move || {
+            for _ in client_rx.wait() {}
+        });
+        symbol_indexes.push(ix);
+    }
+
+    sim.init_sim_loop();
+    let mut buffer = Vec::with_capacity(STEP_BUFFER_SIZE);
+    buffer.resize(STEP_BUFFER_SIZE, TickOutput::Tick(0, Tick::null()));
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+
+    let ledger = sim.get_ledger_clone(account_uuid)?;
+    Ok(RunReport {
+        end_timestamp: sim.timestamp,
+        ticks_processed: symbol_indexes.iter().map(|&ix| sim.symbols[ix].ticks_processed).collect(),
+        buying_power: ledger.buying_power,
+        open_positions: ledger.open_positions.len(),
+        stats: sim.stats.get(&account_uuid).cloned().unwrap_or_default(),
+        seed: sim.seed(),
+    })
+}
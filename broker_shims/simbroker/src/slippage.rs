@@ -0,0 +1,48 @@
+//! Defines the interface through which the SimBroker determines how much worse than the quoted price market orders
+//! are filled as well as the built-in slippage models.
+
+/// Determines the slippage of market orders: the number of pips by which they're filled worse than the price that
+/// was quoted when they were executed.
+pub trait SlippageModel {
+    /// Returns the slippage in pips of a market order for `size` units with the quoted price `price`.
+    fn slippage(&mut self, long: bool, size: usize, price: usize) -> usize;
+}
+
+/// A slippage model that always fills at the quoted price.  This is the default.
+pub struct NullSlippageModel;
+
+impl SlippageModel for NullSlippageModel {
+    #[allow(unused_variables)]
+    fn slippage(&mut self, long: bool, size: usize, price: usize) -> usize {
+        0
+    }
+}
+
+/// A slippage model that fills every market order the same number of pips worse than the quoted price.
+pub struct FixedSlippageModel {
+    pub pips: usize,
+}
+
+impl SlippageModel for FixedSlippageModel {
+    #[allow(unused_variables)]
+    fn slippage(&mut self, long: bool, size: usize, price: usize) -> usize {
+        self.pips
+    }
+}
+
+/// A slippage model that fills market orders `pips` pips worse than the quoted price for every `units` units in
+/// them, rounded down, so that larger orders get worse fills.
+pub struct ProportionalSlippageModel {
+    pub pips: usize,
+    pub units: usize,
+}
+
+impl SlippageModel for ProportionalSlippageModel {
+    #[allow(unused_variables)]
+    fn slippage(&mut self, long: bool, size: usize, price: usize) -> usize {
+        if self.units == 0 {
+            return 0;
+        }
+        self.pips * size / self.units
+    }
+}
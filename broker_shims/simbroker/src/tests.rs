@@ -2,11 +2,83 @@
 
 #![allow(unused_imports)]
 use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
 
-use futures::{Future, Sink};
+use futures::{Future, Sink, stream};
+use futures::sync::mpsc::unbounded;
+use rand::{SeedableRng, XorShiftRng};
+use std::sync::Mutex;
 
 use super::*;
 
+/// Creates a `SimBroker` without any tickstreams.  Returns the broker, the sending end of the channel over which
+/// client actions are submitted to it, and the Uuid of the broker's default account.
+fn init_empty_sim(
+    mut settings: SimBrokerSettings
+) -> (SimBroker, mpsc::Sender<(BrokerAction, Complete<BrokerResult>)>, Uuid) {
+    settings.tickstreams = String::from("[]");
+    let (client_tx, client_rx) = mpsc::channel();
+    let sim = SimBroker::new(settings, CommandServer::new(Uuid::new_v4(), "SimBroker Test"), client_rx).unwrap();
+    let account_uuid = *sim.accounts.data.keys().next().unwrap();
+
+    (sim, client_tx, account_uuid)
+}
+
+/// Creates a `SimBroker` without any tickstreams that contains a single non-FX symbol called "TEST" with a static
+/// price.  Returns the broker, the index of the symbol, and the Uuid of the broker's default account.
+fn init_oneshot_sim(settings: SimBrokerSettings, price: (usize, usize)) -> (SimBroker, usize, Uuid) {
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    let name = String::from("TEST");
+    sim.oneshot_price_set(name.clone(), price, false, 4);
+    let ix = sim.symbols.get_index(&name).unwrap();
+
+    (sim, ix, account_uuid)
+}
+
+/// Opens a position at market without a take profit, panicking if it can't be opened.  Returns the UUID of
+/// the new position.
+fn open_position(
+    sim: &mut SimBroker, account_uuid: Uuid, ix: usize, long: bool, size: usize, stop: Option<usize>
+) -> Uuid {
+    match sim.market_open(account_uuid, ix, long, size, stop, None, None) {
+        Ok(BrokerMessage::PositionOpened{position_id, ..}) => position_id,
+        res => panic!("Unexpected result while opening position: {:?}", res),
+    }
+}
+
+/// Registers a tickstream that yields the supplied ticks and spawns a thread to consume the copies of them that are
+/// sent to the client.  Returns the index of the new symbol.
+fn register_ticks(sim: &mut SimBroker, name: &str, ticks: Vec<Tick>, is_fx: bool, decimals: usize) -> usize {
+    let strm = stream::iter(ticks.into_iter().map(|t| Ok::<Tick, ()>(t))).boxed();
+    sim.register_tickstream(String::from(name), strm, is_fx, decimals).unwrap();
+    let ix = sim.symbols.get_index(&String::from(name)).unwrap();
+    let client_rx = sim.symbols[ix].client_receiver.take().unwrap();
+    thread::spawn(move || {
+        for _ in client_rx.wait() {}
+    });
+
+    ix
+}
+
+/// Returns an empty buffer large enough to be passed to `tick_sim_loop`.
+fn get_buffer() -> Vec<TickOutput> {
+    let mut buffer = Vec::new();
+    buffer.resize(64, TickOutput::Tick(99, Tick::null()));
+    buffer
+}
+
+/// Sets a new price for a symbol and checks all positions against it as if a tick had arrived at the broker.
+/// Returns all of the push messages that were generated as a result.
+fn tick_price(sim: &mut SimBroker, ix: usize, price: (usize, usize)) -> Vec<BrokerResult> {
+    sim.symbols[ix].price = price;
+    let mut buffer = get_buffer();
+    let count = sim.tick_positions(ix, price, 0, &mut buffer);
+    buffer.into_iter().take(count).map(|output| match output {
+        TickOutput::Pushstream(_, res) => res,
+        TickOutput::Tick(_, _) => panic!("`tick_positions` produced a tick!"),
+    }).collect()
+}
+
 /// It should be an error to try to subscribe to a symbol that the SimBroker doesn't keep track of.
 #[test]
 fn sub_ticks_err() {
@@ -119,14 +191,8 @@ fn decimal_conversion() {
 /// Make sure that the ordering of `QueueItem`s is reversed as it should be.
 #[test]
 fn reverse_event_ordering() {
-    let item1 = QueueItem {
-        timestamp: 5,
-        unit: WorkUnit::NewTick(0, Tick::null()),
-    };
-    let item2 = QueueItem {
-        timestamp: 6,
-        unit: WorkUnit::NewTick(0, Tick::null()),
-    };
+    let item1 = QueueItem::new(5, WorkUnit::NewTick(0, Tick::null()));
+    let item2 = QueueItem::new(6, WorkUnit::NewTick(0, Tick::null()));
 
     assert!(item2 < item1);
 }
@@ -140,3 +206,3046 @@ fn symbols_contains(b: &mut test::Bencher) {
     symbols.add(name, symbol).unwrap();
     b.iter(|| symbols.contains(&name_clone))
 }
+
+/// Thousands of round trips at a flat price, closed in uneven chunks, should leave the account with exactly the
+/// balance that it started with.
+#[test]
+fn flat_round_trips_conserve_balance() {
+    let settings = SimBrokerSettings::default();
+    let starting_balance = settings.starting_balance;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+
+    for i in 0..5000 {
+        let size = 1 + (i % 7);
+        let pos_uuid = open_position(&mut sim, account_uuid, ix, i % 2 == 0, size, None);
+
+        let mut remaining = size;
+        while remaining > 0 {
+            let chunk = if remaining > 3 { 3 } else { remaining };
+            sim.market_close(account_uuid, pos_uuid, chunk, false).unwrap();
+            remaining -= chunk;
+        }
+    }
+
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.buying_power, starting_balance);
+    assert_eq!(ledger.open_positions.len(), 0);
+    assert_eq!(ledger.closed_positions.len(), 5000);
+}
+
+/// Immediate-or-cancel orders are all-or-nothing since marketable orders are always filled in full: they should
+/// fill completely right away if they can and have their whole size cancelled without resting otherwise.
+#[test]
+fn time_in_force_ioc() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (9999, 10001));
+    let starting_balance = sim.get_ledger_clone(account_uuid).unwrap().buying_power;
+
+    let res = sim.place_order(account_uuid, ix, 9990, true, 5, None, None, TimeInForce::ImmediateOrCancel);
+    match res {
+        Ok(BrokerMessage::OrderCancelled{ref order, ..}) => assert_eq!(order.size, 5),
+        _ => panic!("Unmarketable IOC order wasn't cancelled: {:?}", res),
+    }
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.pending_positions.len(), 0);
+    assert_eq!(ledger.open_positions.len(), 0);
+    assert_eq!(ledger.buying_power, starting_balance);
+
+    let res = sim.place_order(account_uuid, ix, 10005, true, 5, None, None, TimeInForce::ImmediateOrCancel);
+    match res {
+        Ok(BrokerMessage::PositionOpened{ref position, ..}) => assert_eq!(position.size, 5),
+        _ => panic!("Marketable IOC order wasn't filled: {:?}", res),
+    }
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.open_positions.len(), 1);
+    assert_eq!(ledger.pending_positions.len(), 0);
+}
+
+/// Good-till-cancelled orders should rest until the price reaches them.
+#[test]
+fn time_in_force_gtc() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (9999, 10001));
+
+    let order_uuid = match sim.place_order(account_uuid, ix, 9990, true, 5, None, None, TimeInForce::GoodTillCancelled) {
+        Ok(BrokerMessage::OrderPlaced{order_id, ..}) => order_id,
+        res => panic!("Unexpected result while placing order: {:?}", res),
+    };
+
+    sim.timestamp = 1000000;
+    let msgs = tick_price(&mut sim, ix, (9995, 9997));
+    assert_eq!(msgs.len(), 0);
+    assert!(sim.get_ledger_clone(account_uuid).unwrap().pending_positions.contains_key(&order_uuid));
+
+    let msgs = tick_price(&mut sim, ix, (9987, 9989));
+    match msgs[0] {
+        Ok(BrokerMessage::PositionOpened{position_id, ..}) => assert_eq!(position_id, order_uuid),
+        ref res => panic!("Expected the order to be filled but got {:?}", res),
+    }
+    assert!(sim.get_ledger_clone(account_uuid).unwrap().open_positions.contains_key(&order_uuid));
+}
+
+/// Good-till-date orders should rest until their expiry and then be cancelled.
+#[test]
+fn time_in_force_gtd() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (9999, 10001));
+
+    let order_uuid = match sim.place_order(account_uuid, ix, 9990, true, 5, None, None, TimeInForce::GoodTillDate(100)) {
+        Ok(BrokerMessage::OrderPlaced{order_id, ..}) => order_id,
+        res => panic!("Unexpected result while placing order: {:?}", res),
+    };
+
+    sim.timestamp = 50;
+    let msgs = tick_price(&mut sim, ix, (9998, 10000));
+    assert_eq!(msgs.len(), 0);
+    assert!(sim.get_ledger_clone(account_uuid).unwrap().pending_positions.contains_key(&order_uuid));
+
+    sim.timestamp = 100;
+    let msgs = tick_price(&mut sim, ix, (9998, 10000));
+    assert_eq!(msgs.len(), 1);
+    match msgs[0] {
+        Ok(BrokerMessage::OrderCancelled{order_id, ..}) => assert_eq!(order_id, order_uuid),
+        ref res => panic!("Expected the order to expire but got {:?}", res),
+    }
+    assert_eq!(sim.get_ledger_clone(account_uuid).unwrap().pending_positions.len(), 0);
+    // the client is notified of the funds released by the expired order
+    let starting_balance = SimBrokerSettings::default().starting_balance;
+    assert!(sim.pq.q.iter().any(|item| match item.unit {
+        WorkUnit::Notification(Ok(BrokerMessage::LedgerBalanceChange{new_buying_power, ..})) => {
+            new_buying_power == starting_balance
+        },
+        _ => false,
+    }));
+}
+
+/// Records the kind of every `WorkUnit` it observes.
+struct RecordingObserver {
+    kinds: Arc<Mutex<Vec<&'static str>>>,
+}
+
+impl SimObserver for RecordingObserver {
+    fn observe(&mut self, _: u64, unit: &WorkUnit) {
+        let kind = match unit {
+            &WorkUnit::NewTick(_, _) => "NewTick",
+            &WorkUnit::ClientTick(_, _) => "ClientTick",
+            &WorkUnit::ActionComplete(_, _, _) => "ActionComplete",
+            &WorkUnit::Response(_, _) => "Response",
+            &WorkUnit::Notification(_) => "Notification",
+        };
+        self.kinds.lock().unwrap().push(kind);
+    }
+}
+
+/// The observer should see every `WorkUnit` in the order that the simulation loop processes them.
+#[test]
+fn observer_sees_work_units_in_order() {
+    let mut settings = SimBrokerSettings::default();
+    settings.ping_ns = 5;
+    settings.execution_delay_ns = 3;
+    let (mut sim, client_tx, _) = init_empty_sim(settings);
+    let ticks = vec![Tick {timestamp: 10, bid: 9999, ask: 10001}, Tick {timestamp: 20, bid: 9999, ask: 10001}];
+    register_ticks(&mut sim, "TEST", ticks, false, 4);
+    let kinds = Arc::new(Mutex::new(Vec::new()));
+    sim.set_observer(Box::new(RecordingObserver { kinds: kinds.clone() }));
+    sim.init_sim_loop();
+
+    let mut buffer = get_buffer();
+    // NewTick @ 10
+    sim.tick_sim_loop(0, &mut buffer);
+    // ClientTick @ 15
+    sim.tick_sim_loop(0, &mut buffer);
+    // the client responds to the tick with a ping which completes @ 18
+    let (c, _o) = oneshot::<BrokerResult>();
+    client_tx.send((BrokerAction::Ping, c)).unwrap();
+    sim.tick_sim_loop(1, &mut buffer);
+    // NewTick @ 20, Response @ 23, ClientTick @ 25
+    for _ in 0..3 {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+
+    let expected = vec!["NewTick", "ClientTick", "ActionComplete", "NewTick", "Response", "ClientTick"];
+    assert_eq!(*kinds.lock().unwrap(), expected);
+}
+
+/// Trying to trade a symbol that the broker knows about but hasn't seen a tick for yet should be distinguished
+/// from trying to trade a symbol that doesn't exist.
+#[test]
+fn open_before_first_tick() {
+    let (mut sim, _, account_uuid) = init_empty_sim(SimBrokerSettings::default());
+    let ticks = vec![Tick {timestamp: 10, bid: 9999, ask: 10001}];
+    let ix = register_ticks(&mut sim, "TEST", ticks, false, 4);
+
+    assert_eq!(sim.get_price(ix), Err(BrokerError::NoDataAvailable));
+    let res = sim.market_open(account_uuid, ix, true, 5, None, None, None);
+    assert_eq!(res, Err(BrokerError::NoDataAvailable));
+    let res = sim.market_open(account_uuid, ix + 1, true, 5, None, None, None);
+    assert_eq!(res, Err(BrokerError::NoSuchSymbol));
+}
+
+/// The contract multiplier of a futures-like symbol should scale both the notional value and the PnL of positions.
+#[test]
+fn contract_multiplier_scaling() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (2000, 2000));
+    let starting_balance = sim.get_ledger_clone(account_uuid).unwrap().buying_power;
+    sim.set_contract_multiplier(&String::from("TEST"), 50).unwrap();
+
+    let (pos_uuid, pos) = match sim.market_open(account_uuid, ix, true, 2, None, None, None) {
+        Ok(BrokerMessage::PositionOpened{position_id, position, ..}) => (position_id, position),
+        res => panic!("Unexpected result while opening position: {:?}", res),
+    };
+    assert_eq!(sim.get_position_value(&pos), Ok(2 * 2000 * 50));
+    assert_eq!(sim.get_position_pnl(&pos, 2010), Ok(10 * 2 * 50));
+    assert_eq!(sim.get_ledger_clone(account_uuid).unwrap().buying_power, starting_balance - (2 * 2000 * 50));
+
+    tick_price(&mut sim, ix, (2010, 2010));
+    sim.market_close(account_uuid, pos_uuid, 2, false).unwrap();
+    assert_eq!(sim.get_ledger_clone(account_uuid).unwrap().buying_power, starting_balance + (10 * 2 * 50));
+}
+
+/// The break-even price of a position should be offset from its entry by exactly its fees in price terms.
+#[test]
+fn break_even_price_covers_fees() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10000));
+
+    let long_uuid = open_position(&mut sim, account_uuid, ix, true, 2, None);
+    let short_uuid = open_position(&mut sim, account_uuid, ix, false, 2, None);
+    for pos in sim.accounts.get_mut(&account_uuid).unwrap().ledger.open_positions.values_mut() {
+        pos.fees = 40;
+    }
+
+    // 40 units of fees at 2 units of currency per pip is 20 pips
+    assert_eq!(sim.break_even_price(account_uuid, long_uuid), Ok(10020));
+    assert_eq!(sim.break_even_price(account_uuid, short_uuid), Ok(9980));
+    assert_eq!(sim.break_even_price(account_uuid, Uuid::new_v4()), Err(BrokerError::NoSuchPosition));
+}
+
+/// Submits a ping to the broker after the final tick has been processed, drives the simulation to completion, and
+/// returns the result that the ping's future resolved to.
+fn ping_after_data_ends(process_actions_after_end: bool) -> BrokerResult {
+    let mut settings = SimBrokerSettings::default();
+    settings.process_actions_after_end = process_actions_after_end;
+    let (mut sim, client_tx, _) = init_empty_sim(settings);
+    register_ticks(&mut sim, "TEST", vec![Tick {timestamp: 10, bid: 9999, ask: 10001}], false, 4);
+    sim.init_sim_loop();
+
+    let mut buffer = get_buffer();
+    // NewTick, ClientTick
+    sim.tick_sim_loop(0, &mut buffer);
+    sim.tick_sim_loop(0, &mut buffer);
+    let (c, o) = oneshot::<BrokerResult>();
+    client_tx.send((BrokerAction::Ping, c)).unwrap();
+    // make sure this terminates even if the action is never accounted for
+    for _ in 0..10 {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+    assert!(sim.is_finished());
+
+    o.wait().expect("The action's future was dropped without being completed!")
+}
+
+/// Actions that are outstanding when the data ends should be executed or rejected instead of left hanging.
+#[test]
+fn outstanding_actions_complete_after_data_ends() {
+    match ping_after_data_ends(true) {
+        Ok(BrokerMessage::Pong{..}) => (),
+        res => panic!("Expected the ping to be executed but got {:?}", res),
+    }
+
+    match ping_after_data_ends(false) {
+        Err(BrokerError::SimulationEnded) => (),
+        res => panic!("Expected the ping to be rejected but got {:?}", res),
+    }
+}
+
+/// The margin summary should reflect the margin used by and the unrealized PnL of all open positions.
+#[test]
+fn margin_summary_calculation() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10000));
+    let starting_balance = sim.get_ledger_clone(account_uuid).unwrap().buying_power;
+    sim.market_open(account_uuid, ix, true, 2, None, None, None).unwrap();
+    sim.market_open(account_uuid, ix, false, 3, None, None, None).unwrap();
+    tick_price(&mut sim, ix, (10010, 10012));
+
+    let summary = sim.margin_summary(account_uuid).unwrap();
+    // long: 2 * (10010 - 10000) = 20, short: 3 * (10000 - 10012) = -36
+    let equity = starting_balance as i64 - 16;
+    assert_eq!(summary.used_margin, 50000);
+    assert_eq!(summary.free_margin, starting_balance - 50000);
+    assert_eq!(summary.equity, equity);
+    assert_eq!(summary.margin_level, Some((equity as f64 / 50000.) * 100.));
+}
+
+/// Submits a ping with a large execution delay and returns the result that its future resolved to.
+fn delayed_ping(max_action_staleness_ns: u64) -> BrokerResult {
+    let mut settings = SimBrokerSettings::default();
+    settings.execution_delay_ns = 1000;
+    settings.max_action_staleness_ns = max_action_staleness_ns;
+    let (mut sim, client_tx, _) = init_empty_sim(settings);
+    let ticks = vec![Tick {timestamp: 10, bid: 9999, ask: 10001}, Tick {timestamp: 5000, bid: 9999, ask: 10001}];
+    register_ticks(&mut sim, "TEST", ticks, false, 4);
+    sim.init_sim_loop();
+
+    let mut buffer = get_buffer();
+    // NewTick, ClientTick
+    sim.tick_sim_loop(0, &mut buffer);
+    sim.tick_sim_loop(0, &mut buffer);
+    let (c, o) = oneshot::<BrokerResult>();
+    client_tx.send((BrokerAction::Ping, c)).unwrap();
+    // either the ActionComplete or the rejection response, then the response to the ActionComplete
+    sim.tick_sim_loop(1, &mut buffer);
+    sim.tick_sim_loop(0, &mut buffer);
+
+    o.wait().unwrap()
+}
+
+/// Actions that would execute too long after they're submitted should be rejected.
+#[test]
+fn stale_actions_rejected() {
+    match delayed_ping(500) {
+        Err(BrokerError::StaleAction{delay_ns}) => assert_eq!(delay_ns, 1000),
+        res => panic!("Expected the stale ping to be rejected but got {:?}", res),
+    }
+
+    match delayed_ping(2000) {
+        Ok(BrokerMessage::Pong{time_received}) => assert_eq!(time_received, 1010),
+        res => panic!("Expected the ping to be executed but got {:?}", res),
+    }
+}
+
+/// Reduce-only closes should fully close positions smaller than the requested size and never act without a position.
+#[test]
+fn reduce_only_closes() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10000));
+    let starting_balance = sim.get_ledger_clone(account_uuid).unwrap().buying_power;
+
+    // equal size
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 3, None);
+    match sim.market_close(account_uuid, pos_uuid, 3, true) {
+        Ok(BrokerMessage::PositionClosed{position_id, ..}) => assert_eq!(position_id, pos_uuid),
+        res => panic!("Expected the position to be closed but got {:?}", res),
+    }
+
+    // larger size gets clamped
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, false, 3, None);
+    assert_eq!(sim.market_close(account_uuid, pos_uuid, 5, false), Err(BrokerError::InvalidModificationAmount));
+    match sim.market_close(account_uuid, pos_uuid, 5, true) {
+        Ok(BrokerMessage::PositionClosed{position_id, ..}) => assert_eq!(position_id, pos_uuid),
+        res => panic!("Expected the position to be closed but got {:?}", res),
+    }
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.open_positions.len(), 0);
+    assert_eq!(ledger.buying_power, starting_balance);
+
+    // no position
+    assert_eq!(sim.market_close(account_uuid, Uuid::new_v4(), 1, true), Err(BrokerError::NoSuchPosition));
+    assert_eq!(sim.market_close(account_uuid, pos_uuid, 1, true), Err(BrokerError::PositionAlreadyClosed));
+}
+
+/// Charges a fixed fee whenever a position is opened or closed.
+struct FixedFeeModel {
+    fee: usize,
+}
+
+impl FeeModel for FixedFeeModel {
+    fn open_fee(&self, _: &Position) -> usize {
+        self.fee
+    }
+
+    fn close_fee(&self, _: &Position) -> usize {
+        self.fee
+    }
+
+    fn carry_fee(&self, _: &Position, _: u64) -> usize {
+        0
+    }
+}
+
+/// A custom fee model should be charged on both sides of a trade and recorded on the position.
+#[test]
+fn custom_fee_model() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10000));
+    let starting_balance = sim.get_ledger_clone(account_uuid).unwrap().buying_power;
+    sim.set_fee_model(Box::new(FixedFeeModel { fee: 7 }));
+
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 2, None);
+    assert_eq!(sim.get_ledger_clone(account_uuid).unwrap().buying_power, starting_balance - 20000 - 7);
+    sim.market_close(account_uuid, pos_uuid, 2, false).unwrap();
+
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.buying_power, starting_balance - 14);
+    assert_eq!(ledger.closed_positions.get(&pos_uuid).unwrap().fees, 14);
+}
+
+/// Market orders should be rejected during a trading halt and resting stops shouldn't fire until it lifts.
+#[test]
+fn trading_halts() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10000));
+    let name = String::from("TEST");
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, Some(9000));
+    sim.add_trading_halt(&name, 100, 200).unwrap();
+
+    sim.timestamp = 150;
+    assert!(sim.market_open(account_uuid, ix, true, 1, None, None, None).is_err());
+    assert!(sim.market_close(account_uuid, pos_uuid, 1, false).is_err());
+    // the stop is crossed but trading is halted
+    assert_eq!(tick_price(&mut sim, ix, (8900, 8900)).len(), 0);
+    assert!(sim.get_ledger_clone(account_uuid).unwrap().open_positions.contains_key(&pos_uuid));
+
+    sim.timestamp = 200;
+    let msgs = tick_price(&mut sim, ix, (8900, 8900));
+    assert!(msgs.iter().any(|msg| match msg {
+        &Ok(BrokerMessage::PositionClosed{position_id, ..}) => position_id == pos_uuid,
+        _ => false,
+    }));
+}
+
+/// Ticks from sources with different decimal precisions should be normalized to the symbol's canonical precision.
+#[test]
+fn tick_precision_normalization() {
+    let mut settings = SimBrokerSettings::default();
+    settings.symbol_precisions = String::from("{\"EURUSD\": 5}");
+    let mut prices = Vec::new();
+    for &(bid, ask, decimals) in [(106140, 106150, 5), (10614, 10615, 4)].iter() {
+        let (mut sim, _, _) = init_empty_sim(settings.clone());
+        let ticks = vec![Tick {timestamp: 1, bid: bid, ask: ask}];
+        let ix = register_ticks(&mut sim, "EURUSD", ticks, true, decimals);
+        assert_eq!(sim.symbols[ix].metadata.decimal_precision, 5);
+        sim.init_sim_loop();
+        sim.tick_sim_loop(0, &mut get_buffer());
+        prices.push(sim.get_price(ix).unwrap());
+    }
+
+    assert_eq!(prices[0], (106140, 106150));
+    assert_eq!(prices[0], prices[1]);
+}
+
+/// With daily settlement enabled, the variation in the value of open positions should be realized at each day
+/// boundary and PnL should accrue from the settlement price afterwards.
+#[test]
+fn daily_settlement() {
+    let mut settings = SimBrokerSettings::default();
+    settings.daily_settlement = true;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    let ticks = vec![
+        Tick {timestamp: 1, bid: 10000, ask: 10000},
+        Tick {timestamp: NS_PER_DAY - 1, bid: 10100, ask: 10100},
+        Tick {timestamp: NS_PER_DAY + 1, bid: 10050, ask: 10050},
+    ];
+    let ix = register_ticks(&mut sim, "TEST", ticks, false, 4);
+    let starting_balance = sim.get_ledger_clone(account_uuid).unwrap().buying_power;
+    let mut buffer = get_buffer();
+    sim.init_sim_loop();
+
+    // process the first tick and open a position at its price
+    sim.tick_sim_loop(0, &mut buffer);
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 2, None);
+
+    // run through the day boundary
+    while sim.timestamp <= NS_PER_DAY {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.buying_power, starting_balance - 20000 + 200);
+    assert_eq!(ledger.open_positions.get(&pos_uuid).unwrap().execution_price, Some(10100));
+
+    // closing realizes only the movement since the settlement
+    sim.market_close(account_uuid, pos_uuid, 2, false).unwrap();
+    assert_eq!(sim.get_ledger_clone(account_uuid).unwrap().buying_power, starting_balance + 200 - 100);
+}
+
+/// A `SimBroker` constructed with pre-built symbols should be able to use them right away.
+#[test]
+fn construct_with_symbols() {
+    let cs = CommandServer::new(Uuid::new_v4(), "SimBroker Test");
+    let mut symbols = Symbols::new(cs.clone());
+    symbols.add(String::from("TEST"), Symbol::new_oneshot((9998, 10002), false, 4, String::from("TEST"))).unwrap();
+    symbols.add(String::from("TEST2"), Symbol::new_oneshot((500, 501), false, 2, String::from("TEST2"))).unwrap();
+    let (_, client_rx) = mpsc::channel();
+    let mut sim = SimBroker::with_symbols(SimBrokerSettings::default(), cs, client_rx, symbols).unwrap();
+
+    assert_eq!(sim.get_price(0), Ok((9998, 10002)));
+    assert_eq!(sim.get_price(1), Ok((500, 501)));
+    // space for positions should have been allocated for the symbols
+    let account_uuid = *sim.accounts.data.keys().next().unwrap();
+    assert!(sim.market_open(account_uuid, 1, true, 1, None, None, None).is_ok());
+}
+
+/// Processes all of the supplied ticks for a symbol called "TEST" and returns the broker's price after each of them.
+fn prices_after_ticks(settings: SimBrokerSettings, ticks: Vec<Tick>) -> Vec<(usize, usize)> {
+    let (mut sim, _, _) = init_empty_sim(settings);
+    let ix = register_ticks(&mut sim, "TEST", ticks, false, 4);
+    let mut buffer = get_buffer();
+    sim.init_sim_loop();
+    let mut prices = Vec::new();
+    while !sim.is_finished() {
+        let is_tick = match sim.pq.q.peek() {
+            Some(&QueueItem{unit: WorkUnit::NewTick(_, _), ..}) => true,
+            _ => false,
+        };
+        sim.tick_sim_loop(0, &mut buffer);
+        if is_tick {
+            prices.push(sim.symbols[ix].price);
+        }
+    }
+
+    prices
+}
+
+/// Ticks with zero prices should either be dropped or have their prices replaced with the last valid ones.
+#[test]
+fn zero_price_ticks() {
+    let ticks = vec![
+        Tick {timestamp: 1, bid: 10000, ask: 10002},
+        Tick {timestamp: 2, bid: 0, ask: 10004},
+        Tick {timestamp: 3, bid: 0, ask: 0},
+    ];
+
+    let prices = prices_after_ticks(SimBrokerSettings::default(), ticks.clone());
+    assert_eq!(prices, vec![(10000, 10002), (10000, 10002), (10000, 10002)]);
+
+    let mut settings = SimBrokerSettings::default();
+    settings.clamp_invalid_prices = true;
+    let prices = prices_after_ticks(settings, ticks);
+    assert_eq!(prices, vec![(10000, 10002), (10000, 10004), (10000, 10004)]);
+}
+
+/// The accounts summary should reflect the state of every account on the broker.
+#[test]
+fn accounts_summary() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10000));
+    let starting_balance = sim.settings.starting_balance;
+    let other_uuid = Uuid::new_v4();
+    sim.accounts.insert(other_uuid, Account {
+        uuid: other_uuid,
+        ledger: Ledger::new(12345),
+        live: true,
+    });
+    sim.market_open(account_uuid, ix, true, 1, None, None, None).unwrap();
+    sim.market_open(account_uuid, ix, false, 1, None, None, None).unwrap();
+
+    let mut summary = sim.accounts_summary();
+    summary.sort_by_key(|&(_, balance, _, _)| balance);
+    let mut expected = vec![(account_uuid, starting_balance - 20000, 2, false), (other_uuid, 12345, 0, true)];
+    expected.sort_by_key(|&(_, balance, _, _)| balance);
+    assert_eq!(summary, expected);
+}
+
+/// Confirmations of fills should reach the client `confirmation_latency_ns` after the fill takes place.
+#[test]
+fn confirmation_latency() {
+    let mut settings = SimBrokerSettings::default();
+    settings.confirmation_latency_ns = 500;
+    let (mut sim, _, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+    sim.timestamp = 1000;
+    let (c, o) = oneshot::<BrokerResult>();
+    sim.queue_action(BrokerAction::TradingAction {
+        account_uuid: account_uuid,
+        action: TradingAction::MarketOrder {
+            symbol: String::from("TEST"), long: true, size: 1, stop: None, take_profit: None, max_range: None,
+        },
+    }, c);
+
+    let mut buffer = get_buffer();
+    // ActionComplete; the position is filled here
+    assert_eq!(sim.tick_sim_loop(0, &mut buffer), 0);
+    // Response
+    assert_eq!(sim.tick_sim_loop(0, &mut buffer), 1);
+    match buffer[0] {
+        TickOutput::Pushstream(timestamp, Ok(BrokerMessage::PositionOpened{ref position, ..})) => {
+            assert_eq!(position.execution_time, Some(1000));
+            assert_eq!(timestamp, 1000 + 500);
+        },
+        _ => panic!("Expected a `PositionOpened` message in the buffer!"),
+    }
+    assert!(o.wait().unwrap().is_ok());
+}
+
+/// Make sure that amounts are rounded to the nearest multiple with ties going to the even multiple.
+#[test]
+fn unit_rounding() {
+    assert_eq!(round_to_unit(149, 100), 100);
+    assert_eq!(round_to_unit(150, 100), 200);
+    assert_eq!(round_to_unit(250, 100), 200);
+    assert_eq!(round_to_unit(-149, 100), -100);
+    assert_eq!(round_to_unit(-150, 100), -200);
+    assert_eq!(round_to_unit(-250, 100), -200);
+    assert_eq!(round_to_unit(1234, 1), 1234);
+}
+
+/// Realized PnL reported over many trades that are each smaller than a minor unit should add up to the true total.
+#[test]
+fn rounded_pnl_doesnt_drift() {
+    let mut settings = SimBrokerSettings::default();
+    settings.pnl_minor_unit = 100;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+
+    let mut reported_total = 0;
+    for _ in 0..1000 {
+        sim.symbols[ix].price = (10000, 10000);
+        let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, None);
+        sim.symbols[ix].price = (10033, 10033);
+        match sim.market_close(account_uuid, pos_uuid, 1, false) {
+            Ok(BrokerMessage::PositionClosed{realized_pnl, ..}) => {
+                assert_eq!(realized_pnl % 100, 0);
+                reported_total += realized_pnl;
+            },
+            res => panic!("Expected the position to be closed but got {:?}", res),
+        }
+    }
+
+    let stats = sim.get_stats(account_uuid).unwrap();
+    assert_eq!(reported_total, 33 * 1000);
+    assert_eq!(stats.realized_pnl, reported_total);
+    assert_eq!(stats.closed_trades, 1000);
+    assert_eq!(stats.pnl_remainder, 0);
+}
+
+/// Backfilled positions should be opened at the price the symbol had at the requested time.
+#[test]
+fn backfill_open() {
+    let mut settings = SimBrokerSettings::default();
+    settings.tick_history_ns = 1000;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    let ticks = vec![
+        Tick {timestamp: 1, bid: 9998, ask: 10002},
+        Tick {timestamp: 5, bid: 10098, ask: 10102},
+        Tick {timestamp: 10, bid: 10198, ask: 10202},
+    ];
+    let ix = register_ticks(&mut sim, "TEST", ticks, false, 4);
+    let mut buffer = get_buffer();
+    sim.init_sim_loop();
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+
+    let backfill = BrokerAction::TradingAction {
+        account_uuid: account_uuid,
+        action: TradingAction::BackfillOrder {
+            symbol: String::from("TEST"), long: true, size: 1, stop: None, take_profit: None, timestamp: 7,
+        },
+    };
+    match sim.exec_action(&backfill) {
+        Ok(BrokerMessage::PositionOpened{position, ..}) => {
+            assert_eq!(position.execution_price, Some(10102));
+            assert_eq!(position.execution_time, Some(7));
+        },
+        res => panic!("Unexpected result while backfilling position: {:?}", res),
+    }
+
+    // no data before the first tick
+    assert_eq!(sim.backfill_open(account_uuid, ix, true, 1, None, None, 0), Err(BrokerError::NoDataAvailable));
+}
+
+/// Market orders should be rejected while the spread is wider than the maximum and filled otherwise.
+#[test]
+fn max_spread() {
+    let mut settings = SimBrokerSettings::default();
+    settings.max_spread = 10;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10020));
+    let name = String::from("TEST");
+
+    assert!(sim.market_open(account_uuid, ix, true, 1, None, None, None).is_err());
+    sim.symbols[ix].price = (10000, 10005);
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, None);
+
+    // the per-symbol setting takes precedence
+    sim.set_max_spread(&name, 50).unwrap();
+    sim.symbols[ix].price = (10000, 10020);
+    assert!(sim.market_open(account_uuid, ix, true, 1, None, None, None).is_ok());
+    sim.set_max_spread(&name, 2).unwrap();
+    assert!(sim.market_close(account_uuid, pos_uuid, 1, false).is_err());
+}
+
+/// Realized PnL should be broken down by the symbol of the closed positions.
+#[test]
+fn pnl_by_symbol() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10000));
+    sim.oneshot_price_set(String::from("TEST2"), (5000, 5000), false, 4);
+    let ix2 = sim.symbols.get_index(&String::from("TEST2")).unwrap();
+
+    let round_trip = |sim: &mut SimBroker, ix: usize, long: bool, size: usize, exit_price: (usize, usize)| {
+        let entry_price = sim.symbols[ix].price;
+        let pos_uuid = open_position(sim, account_uuid, ix, long, size, None);
+        sim.symbols[ix].price = exit_price;
+        sim.market_close(account_uuid, pos_uuid, size, false).unwrap();
+        sim.symbols[ix].price = entry_price;
+    };
+    // TEST: +2 * 50 - 1 * 20
+    round_trip(&mut sim, ix, true, 2, (10050, 10050));
+    round_trip(&mut sim, ix, false, 1, (10020, 10020));
+    // TEST2: -3 * 10
+    round_trip(&mut sim, ix2, true, 3, (4990, 4990));
+
+    let pnls = sim.pnl_by_symbol(account_uuid).unwrap();
+    assert_eq!(pnls.len(), 2);
+    assert_eq!(pnls.get("TEST"), Some(&80));
+    assert_eq!(pnls.get("TEST2"), Some(&-30));
+}
+
+/// Accounts should only be liquidated if they stay below the margin call level for longer than the grace period.
+#[test]
+fn margin_call_grace_period() {
+    let mut settings = SimBrokerSettings::default();
+    settings.margin_call_level = 100;
+    settings.margin_call_grace_ns = 1000;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+    // 4,000,000 of the 5,000,000 balance is used as margin, so equity drops below it once the loss exceeds 1,000,000
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 400, None);
+
+    // a brief dip that recovers within the grace period
+    sim.timestamp = 100;
+    tick_price(&mut sim, ix, (7000, 7000));
+    sim.timestamp = 500;
+    tick_price(&mut sim, ix, (10000, 10000));
+    // the grace period restarts with the next breach
+    sim.timestamp = 1200;
+    assert_eq!(tick_price(&mut sim, ix, (7000, 7000)).len(), 0);
+    assert!(sim.get_ledger_clone(account_uuid).unwrap().open_positions.contains_key(&pos_uuid));
+
+    sim.timestamp = 2200;
+    let msgs = tick_price(&mut sim, ix, (7000, 7000));
+    assert!(msgs.iter().any(|msg| match msg {
+        &Ok(BrokerMessage::PositionClosed{position_id, reason: PositionClosureReason::MarginCall, ..}) => {
+            position_id == pos_uuid
+        },
+        _ => false,
+    }));
+    assert!(sim.get_ledger_clone(account_uuid).unwrap().open_positions.is_empty());
+}
+
+/// Offsetting positions in the same symbol should only count towards used margin at the hedged rate.
+#[test]
+fn hedged_margin() {
+    let mut settings = SimBrokerSettings::default();
+    settings.hedged_margin_factor = 0.25;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+    let starting_balance = sim.get_ledger_clone(account_uuid).unwrap().buying_power;
+    sim.market_open(account_uuid, ix, true, 3, None, None, None).unwrap();
+    sim.market_open(account_uuid, ix, false, 2, None, None, None).unwrap();
+
+    // 2 units are hedged on each side and 1 long unit is net
+    let summary = sim.margin_summary(account_uuid).unwrap();
+    let used_margin = 10000 + (40000 as f64 * 0.25) as usize;
+    assert_eq!(summary.used_margin, used_margin);
+    assert_eq!(summary.free_margin, starting_balance - used_margin);
+    assert_eq!(summary.equity, starting_balance as i64);
+}
+
+/// Opening positions should be rejected until the symbol has received `warmup_ticks` ticks.
+#[test]
+fn warmup_period() {
+    let mut settings = SimBrokerSettings::default();
+    settings.warmup_ticks = 3;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    let ticks = (1..6).map(|i| Tick {timestamp: i, bid: 10000, ask: 10000}).collect();
+    let ix = register_ticks(&mut sim, "TEST", ticks, false, 4);
+    let mut buffer = get_buffer();
+    sim.init_sim_loop();
+
+    while sim.symbols[ix].ticks_processed < 2 {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+    assert!(sim.market_open(account_uuid, ix, true, 1, None, None, None).is_err());
+    assert!(sim.place_order(account_uuid, ix, 9000, true, 1, None, None, TimeInForce::GoodTillCancelled).is_err());
+
+    while sim.symbols[ix].ticks_processed < 3 {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+    assert!(sim.market_open(account_uuid, ix, true, 1, None, None, None).is_ok());
+}
+
+/// With tick retention enabled, all ticks that drove the simulation should be available afterwards.
+#[test]
+fn retained_ticks() {
+    let mut settings = SimBrokerSettings::default();
+    settings.retain_ticks = true;
+    let (mut sim, _, _) = init_empty_sim(settings);
+    let ticks: Vec<Tick> = (0..20).map(|i| Tick {timestamp: i * 1000000000, bid: 10000 + i as usize, ask: 10002 + i as usize}).collect();
+    register_ticks(&mut sim, "TEST", ticks.clone(), false, 4);
+    let mut buffer = get_buffer();
+    sim.init_sim_loop();
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+
+    assert_eq!(sim.ticks(&String::from("TEST")).unwrap(), &ticks[..]);
+    assert!(sim.ticks(&String::from("TEST2")).is_err());
+}
+
+/// Opens a long position with a stop at 9900 and sets the price exactly to the stop.  Returns true if it closed.
+fn stop_touched(touch_triggers: bool) -> bool {
+    let mut settings = SimBrokerSettings::default();
+    settings.touch_triggers = touch_triggers;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+    sim.market_open(account_uuid, ix, true, 1, Some(9900), None, None).unwrap();
+    tick_price(&mut sim, ix, (9900, 9902));
+
+    sim.get_ledger_clone(account_uuid).unwrap().open_positions.is_empty()
+}
+
+/// Prices exactly at the stop should only trigger it if `touch_triggers` is set.
+#[test]
+fn touch_triggers() {
+    assert!(stop_touched(true));
+    assert!(!stop_touched(false));
+}
+
+/// The worst-case loss should be the sum of the losses at each position's stop plus the assumed risk of stopless ones.
+#[test]
+fn max_open_risk() {
+    let mut settings = SimBrokerSettings::default();
+    settings.stopless_position_risk = 1000;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10002));
+    assert_eq!(sim.max_open_risk(account_uuid), Ok(0));
+
+    // long entered at 10002: 2 * (10002 - 9950) = 104
+    sim.market_open(account_uuid, ix, true, 2, Some(9950), None, None).unwrap();
+    // short entered at 10000: 3 * (10030 - 10000) = 90
+    sim.market_open(account_uuid, ix, false, 3, Some(10030), None, None).unwrap();
+    assert_eq!(sim.max_open_risk(account_uuid), Ok(104 + 90));
+
+    sim.market_open(account_uuid, ix, true, 1, None, None, None).unwrap();
+    assert_eq!(sim.max_open_risk(account_uuid), Ok(104 + 90 + 1000));
+}
+
+/// Places a buy limit order at 10000 and gaps the price through it.  Returns the price that the order was filled at.
+fn limit_fill_after_gap(allow_price_improvement: bool) -> usize {
+    let mut settings = SimBrokerSettings::default();
+    settings.allow_price_improvement = allow_price_improvement;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10008, 10010));
+    sim.place_order(account_uuid, ix, 10000, true, 1, None, None, TimeInForce::GoodTillCancelled).unwrap();
+    let msgs = tick_price(&mut sim, ix, (9978, 9980));
+
+    msgs.iter().filter_map(|msg| match msg {
+        &Ok(BrokerMessage::PositionOpened{ref position, ..}) => position.execution_price,
+        _ => None,
+    }).next().expect("The order wasn't filled!")
+}
+
+/// Limit orders should only be filled at better prices than their limit prices if price improvement is enabled.
+#[test]
+fn limit_price_improvement() {
+    assert_eq!(limit_fill_after_gap(true), 9980);
+    assert_eq!(limit_fill_after_gap(false), 10000);
+}
+
+/// Rejections of orders should use the specific error variant for their reason.
+#[test]
+fn structured_errors() {
+    let mut settings = SimBrokerSettings::default();
+    settings.max_spread = 10;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings.clone(), (10000, 10020));
+    let name = String::from("TEST");
+    assert_eq!(
+        sim.market_open(account_uuid, ix, true, 1, None, None, None),
+        Err(BrokerError::SpreadTooWide{symbol: name.clone(), spread: 20, max_spread: 10})
+    );
+
+    sim.add_trading_halt(&name, 0, 100).unwrap();
+    assert_eq!(
+        sim.market_open(account_uuid, ix, true, 1, None, None, None),
+        Err(BrokerError::SymbolHalted{symbol: name.clone()})
+    );
+
+    settings.warmup_ticks = 5;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    let ix = register_ticks(&mut sim, "TEST", vec![Tick {timestamp: 1, bid: 10000, ask: 10000}], false, 4);
+    sim.init_sim_loop();
+    sim.tick_sim_loop(0, &mut get_buffer());
+    assert_eq!(
+        sim.market_open(account_uuid, ix, true, 1, None, None, None),
+        Err(BrokerError::WarmingUp{symbol: name})
+    );
+
+    assert_eq!(sim.get_ledger_clone(Uuid::new_v4()), Err(BrokerError::NoSuchAccount));
+}
+
+/// Submits a long market order at timestamp 1 into a market whose price rises by 100 pips every 2ns and returns
+/// the result of the order after an execution delay of 5ns.
+fn order_in_fast_market(requote_threshold: usize) -> BrokerResult {
+    let mut settings = SimBrokerSettings::default();
+    settings.execution_delay_ns = 5;
+    settings.requote_threshold = requote_threshold;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    let ticks = (0..10).map(|i| {
+        let timestamp = i * 2 + 1;
+        Tick {timestamp: timestamp, bid: 10000 + (timestamp as usize * 50), ask: 10000 + (timestamp as usize * 50)}
+    }).collect();
+    let ix = register_ticks(&mut sim, "TEST", ticks, false, 4);
+    let mut buffer = get_buffer();
+    sim.init_sim_loop();
+    while sim.symbols[ix].ticks_processed < 1 {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+
+    let (c, o) = oneshot::<BrokerResult>();
+    sim.queue_action(BrokerAction::TradingAction {
+        account_uuid: account_uuid,
+        action: TradingAction::MarketOrder {
+            symbol: String::from("TEST"), long: true, size: 1, stop: None, take_profit: None, max_range: None,
+        },
+    }, c);
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+
+    o.wait().unwrap()
+}
+
+/// Market orders whose price moves further than `requote_threshold` during their execution delay should be
+/// requoted at the new price rather than filled.
+#[test]
+fn requotes() {
+    // quoted at 10050, executed after the tick at timestamp 5
+    assert_eq!(order_in_fast_market(100), Err(BrokerError::Requote{price: 10250}));
+    match order_in_fast_market(500) {
+        Ok(BrokerMessage::PositionOpened{ref position, ..}) => assert_eq!(position.execution_price, Some(10250)),
+        res => panic!("Expected a `PositionOpened` message but got {:?}", res),
+    }
+    assert!(order_in_fast_market(0).is_ok());
+}
+
+/// Stepping the simulation should process exactly the requested number of events and keep state between calls.
+#[test]
+fn step_events() {
+    let (mut sim, _, _) = init_empty_sim(SimBrokerSettings::default());
+    let ticks = vec![Tick {timestamp: 1, bid: 10000, ask: 10001}, Tick {timestamp: 2, bid: 10002, ask: 10003}];
+    let ix = register_ticks(&mut sim, "TEST", ticks.clone(), false, 4);
+    sim.init_sim_loop();
+
+    // NewTick
+    assert!(sim.step(1).is_empty());
+    assert_eq!(sim.timestamp, 1);
+    assert_eq!(sim.get_price(ix), Ok((10000, 10001)));
+    // ClientTick
+    let outputs = sim.step(1);
+    assert_eq!(outputs.len(), 1);
+    match outputs[0] {
+        TickOutput::Tick(tick_ix, tick) => assert_eq!((tick_ix, tick), (ix, ticks[0])),
+        _ => panic!("Expected a tick to be sent to the client!"),
+    }
+    // NewTick + ClientTick
+    assert_eq!(sim.step(2).len(), 1);
+    assert_eq!(sim.timestamp, 2);
+    assert_eq!(sim.get_price(ix), Ok((10002, 10003)));
+    // nothing is left to process
+    assert!(sim.step(5).is_empty());
+    assert!(sim.is_finished());
+}
+
+/// Tries to open a second position that can only be afforded using the unrealized profit of the first.
+fn open_with_unrealized_profit(use_unrealized_for_margin: bool) -> BrokerResult {
+    let mut settings = SimBrokerSettings::default();
+    settings.use_unrealized_for_margin = use_unrealized_for_margin;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+    // 4,000,000 of the 5,000,000 balance is used by the first position
+    sim.market_open(account_uuid, ix, true, 400, None, None, None).unwrap();
+    // the first position is now 400,000 in profit
+    tick_price(&mut sim, ix, (11000, 11000));
+
+    // costs 1,320,000
+    sim.market_open(account_uuid, ix, true, 120, None, None, None)
+}
+
+/// Unrealized profit should only count towards the funds available for opening positions if enabled.
+#[test]
+fn unrealized_pnl_for_margin() {
+    assert_eq!(open_with_unrealized_profit(false), Err(BrokerError::InsufficientBuyingPower));
+    assert!(open_with_unrealized_profit(true).is_ok());
+}
+
+/// Positions closed by the same tick should have their messages sent in order of creation time and then UUID
+/// regardless of the order in which they were opened.
+#[test]
+fn closure_message_ordering() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10000));
+    let open = |sim: &mut SimBroker, timestamp: u64| {
+        sim.timestamp = timestamp;
+        match sim.market_open(account_uuid, ix, true, 1, None, Some(10100), None) {
+            Ok(BrokerMessage::PositionOpened{position_id, ..}) => position_id,
+            res => panic!("Unexpected result while opening position: {:?}", res),
+        }
+    };
+    let later_uuid = open(&mut sim, 10);
+    let earlier_uuid = open(&mut sim, 5);
+
+    sim.timestamp = 20;
+    let closed: Vec<Uuid> = tick_price(&mut sim, ix, (10200, 10200)).into_iter().filter_map(|msg| match msg {
+        Ok(BrokerMessage::PositionClosed{position_id, ..}) => Some(position_id),
+        _ => None,
+    }).collect();
+    assert_eq!(closed, vec![earlier_uuid, later_uuid]);
+}
+
+/// Short positions shouldn't be opened in symbols for which shorting is disabled, but long ones should.
+#[test]
+fn short_disabled() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10000));
+    let name = String::from("TEST");
+    sim.set_allow_short(&name, false).unwrap();
+
+    assert_eq!(
+        sim.market_open(account_uuid, ix, false, 1, None, None, None),
+        Err(BrokerError::ShortingDisabled{symbol: name})
+    );
+    assert!(sim.market_open(account_uuid, ix, true, 1, None, None, None).is_ok());
+}
+
+/// Currency conversions should use the last price of the conversion pair until it's older than `max_rate_age_ns`.
+#[test]
+fn stale_conversion_rates() {
+    let mut settings = SimBrokerSettings::default();
+    settings.max_rate_age_ns = 100;
+    let (mut sim, _, _) = init_empty_sim(settings);
+    register_ticks(&mut sim, "EURUSD", vec![Tick {timestamp: 1, bid: 106143, ask: 106147}], true, 5);
+    sim.init_sim_loop();
+    // NewTick
+    sim.step(1);
+
+    sim.timestamp = 101;
+    assert_eq!(sim.get_base_rate("EUR", 5), Ok(106147));
+    sim.timestamp = 102;
+    assert_eq!(sim.get_base_rate("EUR", 5), Err(BrokerError::StaleRate{symbol: String::from("EURUSD"), age_ns: 101}));
+}
+
+/// Simulations run in parallel should each report on their own configuration and ticks.
+#[test]
+fn parallel_runs() {
+    let configs: Vec<(SimBrokerSettings, TickSource)> = (1..6).map(|i| {
+        let mut settings = SimBrokerSettings::default();
+        settings.starting_balance = i * 1000;
+        settings.rng_seed = i as u32;
+        let ticks = (0..i * 10).map(|t| Tick {timestamp: t as u64 + 1, bid: 10000, ask: 10000}).collect();
+        (settings, vec![(String::from("TEST"), ticks, false, 4)])
+    }).collect();
+
+    let reports = run_parallel(configs);
+    assert_eq!(reports.len(), 5);
+    for (i, report) in (1..6).zip(reports.into_iter()) {
+        assert_eq!(report, Ok(RunReport {
+            end_timestamp: i as u64 * 10,
+            ticks_processed: vec![i * 10],
+            buying_power: i * 1000,
+            open_positions: 0,
+            stats: AccountStats::default(),
+            seed: i as u32,
+        }));
+    }
+}
+
+/// Records every debug message logged by the broker.
+struct LogObserver {
+    messages: Arc<Mutex<Vec<String>>>,
+}
+
+impl SimObserver for LogObserver {
+    fn observe(&mut self, _: u64, _: &WorkUnit) {}
+
+    fn debug(&mut self, message: &str) {
+        self.messages.lock().unwrap().push(String::from(message));
+    }
+}
+
+/// Runs a simulation over 10 ticks (20 events) and returns the progress messages that were logged.
+fn progress_messages(progress_interval: u64) -> Vec<String> {
+    let mut settings = SimBrokerSettings::default();
+    settings.progress_interval = progress_interval;
+    let (mut sim, _, _) = init_empty_sim(settings);
+    let ticks = (1..11).map(|i| Tick {timestamp: i, bid: 10000, ask: 10000}).collect();
+    register_ticks(&mut sim, "TEST", ticks, false, 4);
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    sim.set_observer(Box::new(LogObserver { messages: messages.clone() }));
+    sim.init_sim_loop();
+    while !sim.is_finished() {
+        sim.step(1);
+    }
+
+    assert_eq!(sim.events_processed, 20);
+    let messages = messages.lock().unwrap();
+    messages.iter().filter(|msg| msg.contains("events processed")).cloned().collect()
+}
+
+/// Progress messages should be logged once every `progress_interval` events unless disabled.
+#[test]
+fn progress_interval() {
+    assert_eq!(progress_messages(0), Vec::<String>::new());
+    assert_eq!(progress_messages(3).len(), 6);
+    assert_eq!(progress_messages(20), vec![String::from("20 events processed; simulation time is 10")]);
+}
+
+/// Opens a position with 37.5% of the buying power of an account with 5,000,000, which comes out to 187.5 units
+/// or 1.875 lots of 100 units, and returns the size it was opened with.
+fn pct_order_size(lot_rounding: LotRounding) -> Result<usize, BrokerError> {
+    let mut settings = SimBrokerSettings::default();
+    settings.lot_rounding = lot_rounding;
+    let (mut sim, _, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+    sim.set_lot_step(&String::from("TEST"), 100).unwrap();
+
+    let action = BrokerAction::TradingAction {
+        account_uuid: account_uuid,
+        action: TradingAction::MarketOrderPct {
+            symbol: String::from("TEST"), long: true, pct: 37.5, stop: None, take_profit: None, max_range: None,
+        },
+    };
+    match sim.exec_action(&action) {
+        Ok(BrokerMessage::PositionOpened{position, ..}) => Ok(position.size),
+        Ok(msg) => panic!("Unexpected message while opening position: {:?}", msg),
+        Err(err) => Err(err),
+    }
+}
+
+/// Sizes computed from a percentage of buying power should be snapped to whole lots according to `lot_rounding`.
+#[test]
+fn pct_size_lot_rounding() {
+    assert_eq!(pct_order_size(LotRounding::Floor), Ok(100));
+    assert_eq!(pct_order_size(LotRounding::Nearest), Ok(200));
+    assert_eq!(pct_order_size(LotRounding::Reject), Err(BrokerError::FractionalLot{symbol: String::from("TEST")}));
+}
+
+/// The number of positions an account can still open should go down as positions and orders are opened.
+#[test]
+fn positions_remaining() {
+    let mut settings = SimBrokerSettings::default();
+    settings.max_positions = 3;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+    assert_eq!(sim.positions_remaining(account_uuid), Ok(3));
+
+    sim.market_open(account_uuid, ix, true, 1, None, None, None).unwrap();
+    assert_eq!(sim.positions_remaining(account_uuid), Ok(2));
+    sim.place_order(account_uuid, ix, 9000, true, 1, None, None, TimeInForce::GoodTillCancelled).unwrap();
+    assert_eq!(sim.positions_remaining(account_uuid), Ok(1));
+    sim.market_open(account_uuid, ix, false, 1, None, None, None).unwrap();
+    assert_eq!(sim.positions_remaining(account_uuid), Ok(0));
+
+    assert_eq!(
+        sim.market_open(account_uuid, ix, true, 1, None, None, None),
+        Err(BrokerError::MaxPositionsReached{max_positions: 3})
+    );
+}
+
+/// The completion callback should be called exactly once with the final state of the broker.
+#[test]
+fn on_complete_callback() {
+    let mut settings = SimBrokerSettings::default();
+    settings.starting_balance = 12345;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    let ticks = (1..6).map(|i| Tick {timestamp: i, bid: 10000, ask: 10000}).collect();
+    register_ticks(&mut sim, "TEST", ticks, false, 4);
+    let balances = Arc::new(Mutex::new(Vec::new()));
+    let balances_clone = balances.clone();
+    sim.set_on_complete(move |sim: &SimBroker| {
+        balances_clone.lock().unwrap().push(sim.accounts.data[&account_uuid].ledger.buying_power);
+    });
+
+    let mut buffer = get_buffer();
+    sim.init_sim_loop();
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+        assert!(balances.lock().unwrap().is_empty());
+    }
+    for _ in 0..3 {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+    sim.step(3);
+
+    assert_eq!(*balances.lock().unwrap(), vec![12345]);
+}
+
+/// Stopped out positions larger than `fill_size_per_tick` should be filled in chunks at the prices of the
+/// following ticks.
+#[test]
+fn partial_stop_fills() {
+    let mut settings = SimBrokerSettings::default();
+    settings.fill_size_per_tick = 100;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 300, Some(9900));
+
+    let closed = |msgs: &Vec<BrokerResult>| msgs.iter().any(|msg| match msg {
+        &Ok(BrokerMessage::PositionClosed{position_id, reason: PositionClosureReason::StopLoss, ..}) => {
+            position_id == pos_uuid
+        },
+        _ => false,
+    });
+    let open_size = |sim: &mut SimBroker| sim.get_ledger_clone(account_uuid).unwrap().open_positions[&pos_uuid].size;
+
+    assert!(!closed(&tick_price(&mut sim, ix, (9890, 9890))));
+    assert_eq!(open_size(&mut sim), 200);
+    // the rest of the stop is filled even if the price recovers past it
+    assert!(!closed(&tick_price(&mut sim, ix, (9950, 9950))));
+    assert_eq!(open_size(&mut sim), 100);
+    assert!(closed(&tick_price(&mut sim, ix, (9800, 9800))));
+
+    // blended exit price of (9890 + 9950 + 9800) / 3 = 9880
+    let stats = sim.get_stats(account_uuid).unwrap();
+    assert_eq!(stats.realized_pnl, (9880 - 10000) * 300);
+    assert_eq!(stats.closed_trades, 1);
+    assert_eq!(sim.get_ledger_clone(account_uuid).unwrap().buying_power, 5000000 - 120 * 300);
+}
+
+/// Trading actions submitted sooner than `min_order_interval_ns` after the last one should be rejected.
+#[test]
+fn order_rate_limit() {
+    let mut settings = SimBrokerSettings::default();
+    settings.min_order_interval_ns = 1000;
+    let (mut sim, _, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+    let order = BrokerAction::TradingAction {
+        account_uuid: account_uuid,
+        action: TradingAction::MarketOrder {
+            symbol: String::from("TEST"), long: true, size: 1, stop: None, take_profit: None, max_range: None,
+        },
+    };
+
+    sim.timestamp = 5000;
+    assert!(sim.exec_action(&order).is_ok());
+    sim.timestamp = 5400;
+    assert_eq!(sim.exec_action(&order), Err(BrokerError::RateLimited{wait_ns: 600}));
+    // other actions aren't limited
+    assert!(sim.exec_action(&BrokerAction::Ping).is_ok());
+    sim.timestamp = 6000;
+    assert!(sim.exec_action(&order).is_ok());
+}
+
+/// Trading actions that are rejected shouldn't count as the account's last action for rate limiting.
+#[test]
+fn rejected_order_not_rate_limited() {
+    let mut settings = SimBrokerSettings::default();
+    settings.min_order_interval_ns = 1000;
+    let (mut sim, _, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+    let order = |size: usize| BrokerAction::TradingAction {
+        account_uuid: account_uuid,
+        action: TradingAction::MarketOrder {
+            symbol: String::from("TEST"), long: true, size: size, stop: None, take_profit: None, max_range: None,
+        },
+    };
+
+    sim.timestamp = 5000;
+    assert_eq!(sim.exec_action(&order(1000000)), Err(BrokerError::InsufficientBuyingPower));
+    sim.timestamp = 5400;
+    assert!(sim.exec_action(&order(1)).is_ok());
+}
+
+/// The buying power recomputed from realized PnL and reserved margin should match the stored buying power through
+/// opens, partial closes, fills, and cancellations, and corruptions of it should be caught.
+#[test]
+fn balance_verification() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    sim.set_fee_model(Box::new(FixedFeeModel {fee: 25}));
+    let verify = |sim: &mut SimBroker| sim.get_ledger_clone(account_uuid).unwrap().verify_balance();
+
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 10, None);
+    sim.place_order(account_uuid, ix, 9000, true, 3, None, None, TimeInForce::GoodTillCancelled).unwrap();
+    let order_uuid = match sim.place_order(account_uuid, ix, 8000, true, 3, None, None, TimeInForce::GoodTillCancelled) {
+        Ok(BrokerMessage::OrderPlaced{order_id, ..}) => order_id,
+        res => panic!("Unexpected result while placing order: {:?}", res),
+    };
+    assert_eq!(verify(&mut sim), Ok(()));
+
+    tick_price(&mut sim, ix, (10300, 10302));
+    sim.market_close(account_uuid, pos_uuid, 4, false).unwrap();
+    assert_eq!(verify(&mut sim), Ok(()));
+    tick_price(&mut sim, ix, (8998, 9000));
+    sim.cancel_order(account_uuid, order_uuid).unwrap();
+    sim.market_close(account_uuid, pos_uuid, 6, false).unwrap();
+    assert_eq!(verify(&mut sim), Ok(()));
+
+    sim.accounts.data.get_mut(&account_uuid).unwrap().ledger.buying_power += 1;
+    assert!(verify(&mut sim).is_err());
+}
+
+/// Processes ticks of a symbol with a seeded random volume model and returns the volumes assigned to them along
+/// with the symbol's VWAP.
+fn seeded_volumes(seed: u32) -> (Vec<usize>, Result<usize, BrokerError>) {
+    let (mut sim, _, _) = init_empty_sim(SimBrokerSettings::default());
+    let ticks = (1..11).map(|i| Tick {timestamp: i, bid: 10000 + i as usize * 10, ask: 10002 + i as usize * 10}).collect();
+    let ix = register_ticks(&mut sim, "TEST", ticks, false, 4);
+    sim.set_volume_model(&String::from("TEST"), VolumeModel::Random{seed: seed, min: 1, max: 100}).unwrap();
+    sim.init_sim_loop();
+
+    let mut volumes = Vec::new();
+    while sim.symbols[ix].ticks_processed < 10 {
+        let processed = sim.symbols[ix].ticks_processed;
+        sim.step(1);
+        if sim.symbols[ix].ticks_processed > processed {
+            volumes.push(sim.symbols[ix].last_volume);
+        }
+    }
+
+    (volumes, sim.vwap(&String::from("TEST")))
+}
+
+/// Synthetic volumes should be the same for the same seed and be used to weight the VWAP.
+#[test]
+fn synthetic_volume() {
+    let (volumes, vwap) = seeded_volumes(42);
+    assert_eq!(volumes.len(), 10);
+    assert!(volumes.iter().all(|&v| v >= 1 && v < 100));
+    assert_eq!(seeded_volumes(42).0, volumes);
+
+    let price_volume: usize = volumes.iter().enumerate().map(|(i, &v)| (10001 + (i + 1) * 10) * v).sum();
+    let total_volume: usize = volumes.iter().sum();
+    assert_eq!(vwap, Ok(price_volume / total_volume));
+
+    let tick = Tick {timestamp: 1, bid: 10000, ask: 10004};
+    assert_eq!(VolumeGenerator::new(VolumeModel::Constant(7)).volume(&tick), 7);
+    assert_eq!(VolumeGenerator::new(VolumeModel::SpreadInverse{scale: 1000}).volume(&tick), 250);
+}
+
+/// Opens a position of `size` units, closes `pct` percent of it, and returns the size of what's left.
+fn remaining_after_pct_close(size: usize, pct: f64, lot_step: usize) -> usize {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10000));
+    sim.set_lot_step(&String::from("TEST"), lot_step).unwrap();
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, size, None);
+
+    let res = sim.exec_action(&BrokerAction::TradingAction {
+        account_uuid: account_uuid,
+        action: TradingAction::MarketClosePct{uuid: pos_uuid, pct: pct},
+    });
+    assert!(res.is_ok(), "Unexpected result while closing position: {:?}", res);
+
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    ledger.open_positions.get(&pos_uuid).unwrap().size
+}
+
+/// Closing a percentage of a position should close that fraction of its units, snapped to whole lots.
+#[test]
+fn market_close_pct() {
+    assert_eq!(remaining_after_pct_close(10, 50.0, 1), 5);
+    // 6 units is 1.5 lots of 4, which floors to a single lot
+    assert_eq!(remaining_after_pct_close(12, 50.0, 4), 8);
+}
+
+/// Places a limit order and returns a copy of the account's ledger along with the UUID of the order.
+fn ledger_with_lingering_order() -> (Ledger, Uuid) {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let order_uuid = match sim.place_order(account_uuid, ix, 9000, true, 3, None, None, TimeInForce::GoodTillCancelled) {
+        Ok(BrokerMessage::OrderPlaced{order_id, ..}) => order_id,
+        res => panic!("Unexpected result while placing order: {:?}", res),
+    };
+
+    (sim.get_ledger_clone(account_uuid).unwrap(), order_uuid)
+}
+
+/// A position UUID showing up in more than one of the ledger's position maps should be detected.
+#[test]
+fn duplicate_position_uuids() {
+    let (mut ledger, order_uuid) = ledger_with_lingering_order();
+    assert_eq!(ledger.verify_positions(), Ok(()));
+    assert!(ledger.find_position(order_uuid).is_ok());
+    assert_eq!(ledger.find_position(Uuid::new_v4()), Err(BrokerError::NoSuchPosition));
+
+    let pos = ledger.pending_positions.get(&order_uuid).unwrap().clone();
+    ledger.open_positions.insert(order_uuid, pos);
+    assert_eq!(ledger.verify_positions(), Err(BrokerError::AmbiguousPosition{uuid: order_uuid}));
+    assert_eq!(ledger.find_position(order_uuid), Err(BrokerError::AmbiguousPosition{uuid: order_uuid}));
+}
+
+/// Opening a position that's still pending should trip the ledger's invariant check in debug builds.
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic]
+fn lingering_pending_position() {
+    let (mut ledger, order_uuid) = ledger_with_lingering_order();
+    let mut pos = ledger.pending_positions.get(&order_uuid).unwrap().clone();
+    pos.execution_time = Some(1);
+    pos.execution_price = Some(9000);
+    let _ = ledger.open_position(order_uuid, pos);
+}
+
+/// Queues a burst of market orders with congestion delays enabled and returns the delays each of them incurred.
+fn burst_delays(scaling: CongestionScaling) -> Vec<u64> {
+    let mut settings = SimBrokerSettings::default();
+    settings.execution_delay_ns = 1000;
+    settings.congestion_delay_ns = 10;
+    settings.congestion_scaling = scaling;
+    let (mut sim, _, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+
+    let mut delays = Vec::new();
+    for _ in 0..5 {
+        let (c, _) = oneshot::<BrokerResult>();
+        sim.queue_action(BrokerAction::TradingAction {
+            account_uuid: account_uuid,
+            action: TradingAction::MarketOrder {
+                symbol: String::from("TEST"), long: true, size: 1, stop: None, take_profit: None, max_range: None,
+            },
+        }, c);
+        let latest = sim.pq.q.iter().map(|qi| qi.timestamp).max().unwrap();
+        delays.push(latest - sim.timestamp);
+    }
+
+    delays
+}
+
+/// Actions received while the simulation queue is congested should be delayed more the deeper the queue is.
+#[test]
+fn congestion_delays() {
+    assert_eq!(burst_delays(CongestionScaling::Linear), vec![1000, 1010, 1020, 1030, 1040]);
+    assert_eq!(burst_delays(CongestionScaling::Quadratic), vec![1000, 1010, 1040, 1090, 1160]);
+    assert_eq!(burst_delays(CongestionScaling::Logarithmic), vec![1000, 1010, 1020, 1020, 1030]);
+}
+
+/// The JSON export of an account should contain its balance and the details of its positions.
+#[test]
+fn account_json_export() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 3, None);
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+
+    let json = sim.account_json(account_uuid).unwrap();
+    assert!(json.contains(&format!("\"buying_power\": {}", ledger.buying_power)));
+    assert!(json.contains(&format!("\"{}\"", pos_uuid.hyphenated())));
+    assert!(json.contains("\"size\": 3"));
+    assert!(json.contains("\"execution_price\": 10002"));
+    assert!(json.contains("\"pending_positions\": {}"));
+    assert_eq!(serde_json::from_str::<Ledger>(&json).unwrap(), ledger);
+
+    assert_eq!(sim.account_json(Uuid::new_v4()), Err(BrokerError::NoSuchAccount));
+}
+
+/// Ticks sent over a live tickstream should be processed as they arrive, with the simulation continuing while it
+/// waits for more of them until the tickstream is closed.
+#[test]
+fn live_tickstream() {
+    let (mut sim, _, _) = init_empty_sim(SimBrokerSettings::default());
+    let (tx, rx) = unbounded::<Tick>();
+    sim.register_live_tickstream(String::from("LIVE"), rx, false, 4).unwrap();
+    let ix = sim.symbols.get_index(&String::from("LIVE")).unwrap();
+    let client_rx = sim.symbols[ix].client_receiver.take().unwrap();
+    thread::spawn(move || {
+        for _ in client_rx.wait() {}
+    });
+
+    // send ticks with gaps between them so that the simulation runs out of events while waiting
+    let (sent_tx, sent_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut tx = tx;
+        for i in 1..4 {
+            thread::sleep(Duration::from_millis(20));
+            tx = tx.send(Tick {timestamp: i, bid: 10000 + i as usize, ask: 10002 + i as usize}).wait().unwrap();
+            sent_tx.send(i).unwrap();
+        }
+    });
+
+    sim.init_sim_loop();
+    assert!(!sim.is_finished());
+    while !sim.is_finished() {
+        sim.step(1);
+    }
+
+    assert_eq!(sent_rx.iter().count(), 3);
+    assert_eq!(sim.symbols[ix].ticks_processed, 3);
+    assert_eq!(sim.get_price(ix), Ok((10003, 10005)));
+}
+
+/// Market orders larger than `max_liquidity_fraction` of the last tick's volume should be rejected.
+#[test]
+fn liquidity_cap() {
+    let mut settings = SimBrokerSettings::default();
+    settings.max_liquidity_fraction = 0.5;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    let ix = register_ticks(&mut sim, "TEST", vec![Tick {timestamp: 1, bid: 10000, ask: 10002}], false, 4);
+    sim.set_volume_model(&String::from("TEST"), VolumeModel::Constant(100)).unwrap();
+    sim.init_sim_loop();
+    while sim.symbols[ix].ticks_processed < 1 {
+        sim.step(1);
+    }
+
+    assert_eq!(
+        sim.market_open(account_uuid, ix, true, 51, None, None, None),
+        Err(BrokerError::InsufficientLiquidity{symbol: String::from("TEST"), max_size: 50})
+    );
+    match sim.market_open(account_uuid, ix, true, 50, None, None, None) {
+        Ok(BrokerMessage::PositionOpened{position, ..}) => assert_eq!(position.size, 50),
+        res => panic!("Unexpected result while opening position: {:?}", res),
+    }
+}
+
+/// Runs a scenario with two symbols whose ticks are generated from a fixed seed, periodically opening positions
+/// with stops and take profits in them.  Returns the final ledger of the account as JSON and every output that
+/// was sent to the client, formatted as strings.
+fn run_replay_scenario() -> (String, Vec<String>) {
+    let mut settings = SimBrokerSettings::default();
+    settings.rng_seed = 1234;
+    settings.ping_ns = 1;
+    settings.execution_delay_ns = 3;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let mut ixs = Vec::new();
+    for (i, name) in ["AAA", "BBB"].iter().enumerate() {
+        let mut price = 10000;
+        let ticks = (0..200).map(|t| {
+            price = price + rng.gen_range(0, 21) - 10;
+            Tick {timestamp: t * 10 + i as u64 * 5, bid: price, ask: price + 2}
+        }).collect();
+        let ix = register_ticks(&mut sim, name, ticks, false, 4);
+        sim.set_volume_model(&String::from(*name), VolumeModel::Random{seed: 42, min: 1, max: 100}).unwrap();
+        ixs.push((ix, String::from(*name)));
+    }
+
+    sim.init_sim_loop();
+    let mut outputs = Vec::new();
+    let mut events = 0;
+    while !sim.is_finished() {
+        if events % 50 == 25 {
+            let (ix, ref name) = ixs[(events / 50) % 2];
+            let (bid, ask) = sim.get_price(ix).unwrap();
+            let long = events % 100 < 50;
+            let (stop, take_profit) = if long { (bid - 30, ask + 30) } else { (ask + 30, bid - 30) };
+            let (c, _) = oneshot::<BrokerResult>();
+            sim.queue_action(BrokerAction::TradingAction {
+                account_uuid: account_uuid,
+                action: TradingAction::MarketOrder {
+                    symbol: name.clone(), long: long, size: 10, stop: Some(stop), take_profit: Some(take_profit),
+                    max_range: None,
+                },
+            }, c);
+        }
+
+        for output in sim.step(1) {
+            outputs.push(match output {
+                TickOutput::Tick(ix, tick) => format!("tick {} {:?}", ix, tick),
+                TickOutput::Pushstream(timestamp, res) => format!("push {} {:?}", timestamp, res),
+            });
+        }
+        events += 1;
+    }
+
+    (sim.account_json(account_uuid).unwrap(), outputs)
+}
+
+/// Running the same scenario twice should produce identical ledgers and identical sequences of client outputs.
+#[test]
+fn replay_determinism() {
+    let (ledger, outputs) = run_replay_scenario();
+    assert!(outputs.iter().any(|output| output.starts_with("push")));
+
+    let (replayed_ledger, replayed_outputs) = run_replay_scenario();
+    assert_eq!(ledger, replayed_ledger);
+    assert_eq!(outputs, replayed_outputs);
+}
+
+/// Places a limit order at 10000 while it isn't marketable, moves the price to `price`, and returns the price the
+/// order was filled at, if it was.
+fn limit_fill_at(limit_fill_side: LimitFillSide, long: bool, price: (usize, usize)) -> Option<usize> {
+    let mut settings = SimBrokerSettings::default();
+    settings.limit_fill_side = limit_fill_side;
+    let start_price = if long { (10010, 10012) } else { (9988, 9990) };
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, start_price);
+    sim.place_order(account_uuid, ix, 10000, long, 1, None, None, TimeInForce::GoodTillCancelled).unwrap();
+
+    tick_price(&mut sim, ix, price).into_iter().filter_map(|msg| match msg {
+        Ok(BrokerMessage::PositionOpened{position, ..}) => position.execution_price,
+        _ => None,
+    }).next()
+}
+
+/// Limit orders should be filled once the side of the market selected by `limit_fill_side` reaches their price.
+#[test]
+fn limit_fill_sides() {
+    // only the bid has reached the price of the buy
+    assert_eq!(limit_fill_at(LimitFillSide::Cross, true, (10000, 10002)), None);
+    assert_eq!(limit_fill_at(LimitFillSide::Trade, true, (10000, 10002)), Some(10000));
+    // only the ask has reached the price of the sell
+    assert_eq!(limit_fill_at(LimitFillSide::Cross, false, (9998, 10000)), None);
+    assert_eq!(limit_fill_at(LimitFillSide::Trade, false, (9998, 10000)), Some(10000));
+    // since the bid is never above the ask, whatever fills under `Cross` also fills under `Trade`
+    assert_eq!(limit_fill_at(LimitFillSide::Cross, true, (9996, 9998)), Some(9998));
+    assert_eq!(limit_fill_at(LimitFillSide::Trade, true, (9996, 9998)), Some(9998));
+}
+
+/// Accounts with trading disabled shouldn't be able to open or modify positions, but their stops should still fire.
+#[test]
+fn trading_disabled() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, Some(9900));
+
+    sim.set_trading_enabled(account_uuid, false).unwrap();
+    let disabled = Err(BrokerError::TradingDisabled{account_uuid: account_uuid});
+    assert_eq!(sim.market_open(account_uuid, ix, true, 1, None, None, None), disabled);
+    assert_eq!(
+        sim.place_order(account_uuid, ix, 9000, true, 1, None, None, TimeInForce::GoodTillCancelled),
+        disabled
+    );
+    let modify = sim.exec_action(&BrokerAction::TradingAction {
+        account_uuid: account_uuid,
+        action: TradingAction::ModifyPosition{uuid: pos_uuid, stop: Some(9950), take_profit: None},
+    });
+    assert_eq!(modify, disabled);
+
+    // the resting stop still closes the position
+    let msgs = tick_price(&mut sim, ix, (9890, 9892));
+    assert!(msgs.iter().any(|msg| match msg {
+        &Ok(BrokerMessage::PositionClosed{position_id, ..}) => position_id == pos_uuid,
+        _ => false,
+    }));
+
+    sim.set_trading_enabled(account_uuid, true).unwrap();
+    assert!(sim.market_open(account_uuid, ix, true, 1, None, None, None).is_ok());
+    assert_eq!(sim.set_trading_enabled(Uuid::new_v4(), false), Err(BrokerError::NoSuchAccount));
+}
+
+/// The average slippage reported in an account's stats should match what the slippage model applied.
+#[test]
+fn average_slippage() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    assert_eq!(sim.get_stats(account_uuid).unwrap().average_slippage(), None);
+
+    sim.set_slippage_model(Box::new(FixedSlippageModel {pips: 3}));
+    match sim.market_open(account_uuid, ix, true, 1, None, None, None) {
+        Ok(BrokerMessage::PositionOpened{position, ..}) => {
+            assert_eq!(position.price, Some(10002));
+            assert_eq!(position.execution_price, Some(10005));
+        },
+        res => panic!("Unexpected result while opening position: {:?}", res),
+    }
+    match sim.market_open(account_uuid, ix, false, 1, None, None, None) {
+        Ok(BrokerMessage::PositionOpened{position, ..}) => assert_eq!(position.execution_price, Some(9997)),
+        res => panic!("Unexpected result while opening position: {:?}", res),
+    }
+    sim.set_slippage_model(Box::new(NullSlippageModel));
+    sim.market_open(account_uuid, ix, true, 1, None, None, None).unwrap();
+
+    assert_eq!(sim.get_stats(account_uuid).unwrap().average_slippage(), Some(2.0));
+}
+
+/// A pegged buy order's trigger should follow the market down and be filled once the market rebounds through it.
+#[test]
+fn pegged_order() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (9998, 10000));
+    let order_uuid = match sim.exec_action(&BrokerAction::TradingAction {
+        account_uuid: account_uuid,
+        action: TradingAction::PeggedOrder{symbol: String::from("TEST"), long: true, size: 1, offset: 5},
+    }) {
+        Ok(BrokerMessage::OrderPlaced{order_id, order, ..}) => {
+            assert_eq!(order.price, Some(10005));
+            order_id
+        },
+        res => panic!("Unexpected result while placing pegged order: {:?}", res),
+    };
+
+    // the market falls and the trigger follows it, staying put when it starts to recover
+    assert!(tick_price(&mut sim, ix, (9988, 9990)).is_empty());
+    assert!(tick_price(&mut sim, ix, (9978, 9980)).is_empty());
+    assert!(tick_price(&mut sim, ix, (9982, 9984)).is_empty());
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.pending_positions.get(&order_uuid).unwrap().price, Some(9985));
+
+    // the rebound goes through the trigger
+    let msgs = tick_price(&mut sim, ix, (9984, 9986));
+    match msgs.as_slice().first() {
+        Some(&Ok(BrokerMessage::PositionOpened{position_id, ref position, ..})) => {
+            assert_eq!(position_id, order_uuid);
+            assert_eq!(position.execution_price, Some(9986));
+        },
+        res => panic!("Unexpected result while ticking pegged order: {:?}", res),
+    }
+}
+
+/// Ticks of symbols with skewed clocks should be processed in order of their skewed timestamps.
+#[test]
+fn clock_skews() {
+    let mut settings = SimBrokerSettings::default();
+    settings.clock_skews = String::from("{\"AAA\": -5, \"BBB\": 12}");
+    let (mut sim, _, _) = init_empty_sim(settings);
+    let ticks: Vec<Tick> = (1..4).map(|i| Tick {timestamp: i * 10, bid: 10000, ask: 10002}).collect();
+    let aaa = register_ticks(&mut sim, "AAA", ticks.clone(), false, 4);
+    let bbb = register_ticks(&mut sim, "BBB", ticks, false, 4);
+
+    sim.init_sim_loop();
+    let mut processed = Vec::new();
+    while !sim.is_finished() {
+        for output in sim.step(1) {
+            if let TickOutput::Tick(ix, tick) = output {
+                processed.push((ix, tick.timestamp));
+            }
+        }
+    }
+
+    assert_eq!(processed, vec![(aaa, 5), (aaa, 15), (bbb, 22), (aaa, 25), (bbb, 32), (bbb, 42)]);
+    assert_eq!(skew_timestamp(3, -5), 0);
+}
+
+/// The PnL of FX positions should be converted to the base currency at the exchange rate at the time of their close
+/// without the funds reserved for them being revalued.
+#[test]
+fn close_time_pnl_conversion() {
+    let mut settings = SimBrokerSettings::default();
+    settings.starting_balance = 1000 * 1000 * 1000;
+    let starting_balance = settings.starting_balance;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    sim.oneshot_price_set(String::from("EURUSD"), (110000, 110000), true, 5);
+    sim.oneshot_price_set(String::from("GBPUSD"), (130000, 130000), true, 5);
+    sim.oneshot_price_set(String::from("EURGBP"), (84000, 84002), true, 5);
+    let ix = sim.symbols.get_index(&String::from("EURGBP")).unwrap();
+
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, None);
+
+    sim.oneshot_price_set(String::from("EURUSD"), (120000, 120000), true, 5);
+    sim.oneshot_price_set(String::from("GBPUSD"), (125000, 125000), true, 5);
+    sim.oneshot_price_set(String::from("EURGBP"), (85000, 85002), true, 5);
+
+    // 998 pips * 1000 units in GBP converted at the close-time rate of 1.25
+    let expected_pnl = 998 * 1000 * 125000 / 100000;
+    match sim.market_close(account_uuid, pos_uuid, 1, false) {
+        Ok(BrokerMessage::PositionClosed{realized_pnl, ..}) => assert_eq!(realized_pnl, expected_pnl),
+        res => panic!("Unexpected result while closing position: {:?}", res),
+    }
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.buying_power as i64, starting_balance as i64 + expected_pnl);
+    assert_eq!(ledger.verify_balance(), Ok(()));
+}
+
+/// A position partly funded by unrealized PnL should only release the funds that were actually reserved for it, so
+/// closing it flat returns the buying power to exactly where it was before it was opened.
+#[test]
+fn unrealized_funded_close_releases_reserved() {
+    let mut settings = SimBrokerSettings::default();
+    settings.use_unrealized_for_margin = true;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+    sim.market_open(account_uuid, ix, true, 400, None, None, None).unwrap();
+    tick_price(&mut sim, ix, (11000, 11000));
+    let buying_power = sim.get_ledger_clone(account_uuid).unwrap().buying_power;
+
+    // costs 1,320,000 but only the 1,000,000 of buying power left can be reserved
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 120, None);
+    assert_eq!(sim.get_ledger_clone(account_uuid).unwrap().reserved_margin[&pos_uuid], buying_power);
+
+    sim.market_close(account_uuid, pos_uuid, 120, false).unwrap();
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.buying_power, buying_power);
+    assert_eq!(ledger.verify_balance(), Ok(()));
+}
+
+/// Once a position with a break-even automation has made enough profit, its stop should be moved to its entry price.
+#[test]
+fn breakeven_stop() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, Some(9900));
+    assert_eq!(sim.set_breakeven_stop(account_uuid, Uuid::new_v4(), 20), Err(BrokerError::NoSuchPosition));
+    sim.set_breakeven_stop(account_uuid, pos_uuid, 20).unwrap();
+
+    // not far enough in profit yet
+    assert_eq!(tick_price(&mut sim, ix, (10010, 10012)), Vec::new());
+
+    let msgs = tick_price(&mut sim, ix, (10022, 10024));
+    assert_eq!(msgs.len(), 1);
+    match msgs[0] {
+        Ok(BrokerMessage::PositionModified{ref position, position_id, ..}) => {
+            assert_eq!(position_id, pos_uuid);
+            assert_eq!(position.stop, Some(10002));
+        },
+        ref res => panic!("Unexpected push message after reaching the threshold: {:?}", res),
+    }
+    // the stop is only moved once
+    assert_eq!(tick_price(&mut sim, ix, (10030, 10032)), Vec::new());
+
+    // falling back to the entry price stops the position out
+    let msgs = tick_price(&mut sim, ix, (10001, 10003));
+    assert!(msgs.iter().any(|msg| match msg {
+        &Ok(BrokerMessage::PositionClosed{position_id, ..}) => position_id == pos_uuid,
+        _ => false,
+    }));
+}
+
+/// Closing a position, whether by its stop or manually, should remove the state kept for it by its automations.
+#[test]
+fn close_removes_automations() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let open = |sim: &mut SimBroker| {
+        let pos_uuid = open_position(sim, account_uuid, ix, true, 1, Some(9900));
+        sim.set_breakeven_stop(account_uuid, pos_uuid, 50).unwrap();
+        sim.limit_close(account_uuid, pos_uuid, 1, 10100).unwrap();
+        pos_uuid
+    };
+    let stopped_uuid = open(&mut sim);
+    let closed_uuid = open(&mut sim);
+
+    sim.market_close(account_uuid, closed_uuid, 1, false).unwrap();
+    assert!(!sim.breakevens.contains_key(&closed_uuid));
+    assert!(!sim.limit_closes.contains_key(&closed_uuid));
+
+    tick_price(&mut sim, ix, (9890, 9892));
+    assert!(sim.get_ledger_clone(account_uuid).unwrap().closed_positions.contains_key(&stopped_uuid));
+    assert!(sim.breakevens.is_empty());
+    assert!(sim.limit_closes.is_empty());
+}
+
+/// Manually setting the stop of a position with a break-even automation should disable the automation so that the
+/// manual stop sticks until the automation is set again.
+#[test]
+fn manual_stop_disables_breakeven() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, Some(9900));
+    sim.set_breakeven_stop(account_uuid, pos_uuid, 20).unwrap();
+    sim.modify_position(account_uuid, pos_uuid, Some(Some(9950)), None).unwrap();
+
+    // past the threshold but the manual stop is kept
+    assert_eq!(tick_price(&mut sim, ix, (10022, 10024)), Vec::new());
+    let stop = sim.get_ledger_clone(account_uuid).unwrap().open_positions.get(&pos_uuid).unwrap().stop;
+    assert_eq!(stop, Some(9950));
+
+    // setting the automation again moves the manual stop
+    sim.set_breakeven_stop(account_uuid, pos_uuid, 20).unwrap();
+    let msgs = tick_price(&mut sim, ix, (10030, 10032));
+    assert_eq!(msgs.len(), 1);
+    match msgs[0] {
+        Ok(BrokerMessage::PositionModified{ref position, ..}) => assert_eq!(position.stop, Some(10002)),
+        ref res => panic!("Unexpected push message after re-enabling the automation: {:?}", res),
+    }
+}
+
+/// A portfolio of a symbol that moves exactly in proportion to the benchmark should have a beta of 1, and shorting
+/// it should flip the sign.
+#[test]
+fn portfolio_beta() {
+    let mut settings = SimBrokerSettings::default();
+    settings.retain_ticks = true;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    let prices = [10000, 10100, 9900, 10300, 10200, 10250];
+    let bench_ticks: Vec<Tick> = prices.iter().enumerate()
+        .map(|(i, &price)| Tick {timestamp: i as u64 * 1000000000, bid: price, ask: price})
+        .collect();
+    let test_ticks: Vec<Tick> = bench_ticks.iter().map(|t| Tick {timestamp: t.timestamp, bid: t.bid * 2, ask: t.ask * 2}).collect();
+    register_ticks(&mut sim, "BENCH", bench_ticks, false, 4);
+    let ix = register_ticks(&mut sim, "TEST", test_ticks, false, 4);
+    let mut buffer = get_buffer();
+    sim.init_sim_loop();
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+
+    let bench = String::from("BENCH");
+    assert_eq!(sim.portfolio_beta(account_uuid, &bench), Err(BrokerError::NoDataAvailable));
+    assert_eq!(sim.portfolio_beta(account_uuid, &String::from("NONE")), Err(BrokerError::NoSuchSymbol));
+
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, None);
+    let beta = sim.portfolio_beta(account_uuid, &bench).unwrap();
+    assert!((beta - 1.).abs() < 1e-9, "Unexpected beta: {}", beta);
+
+    sim.market_close(account_uuid, pos_uuid, 1, false).unwrap();
+    sim.market_open(account_uuid, ix, false, 1, None, None, None).unwrap();
+    let beta = sim.portfolio_beta(account_uuid, &bench).unwrap();
+    assert!((beta + 1.).abs() < 1e-9, "Unexpected beta: {}", beta);
+}
+
+/// Returns the number of threads in the current process as reported by the kernel.
+#[cfg(target_os = "linux")]
+fn thread_count() -> usize {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut status = String::new();
+    File::open("/proc/self/status").unwrap().read_to_string(&mut status).unwrap();
+    status.lines()
+        .find(|line| line.starts_with("Threads:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+/// Shutting down a client should join the threads that were started with its simulation loop so that they don't
+/// pile up over many runs.
+#[cfg(target_os = "linux")]
+#[test]
+fn shutdown_joins_threads() {
+    let mut settings = HashMap::new();
+    settings.insert(String::from("tickstreams"), String::from("[]"));
+    let starting_threads = thread_count();
+
+    for _ in 0..100 {
+        let mut client = SimBrokerClient::init(settings.clone()).wait().unwrap().unwrap();
+        client.init_sim_loop().unwrap();
+        client.shutdown();
+    }
+
+    // other tests may be running concurrently, so only make sure the count didn't grow with every broker
+    assert!(thread_count() < starting_threads + 50);
+}
+
+/// Once an account has as many resting orders as `max_pending_orders` allows, more orders should be rejected while
+/// market orders still go through.
+#[test]
+fn max_pending_orders() {
+    let mut settings = SimBrokerSettings::default();
+    settings.max_pending_orders = 2;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10002));
+
+    sim.place_order(account_uuid, ix, 9000, true, 1, None, None, TimeInForce::GoodTillCancelled).unwrap();
+    sim.place_order(account_uuid, ix, 9100, true, 1, None, None, TimeInForce::GoodTillCancelled).unwrap();
+    let rejected = Err(BrokerError::MaxPendingOrdersReached{max_pending_orders: 2});
+    assert_eq!(
+        sim.place_order(account_uuid, ix, 9200, true, 1, None, None, TimeInForce::GoodTillCancelled),
+        rejected
+    );
+    assert_eq!(sim.place_pegged_order(account_uuid, ix, true, 1, 10), rejected);
+
+    assert!(sim.market_open(account_uuid, ix, true, 1, None, None, None).is_ok());
+    assert_eq!(sim.get_ledger_clone(account_uuid).unwrap().pending_positions.len(), 2);
+}
+
+/// Orders that never rest on the book shouldn't be held to the pending order limit: a marketable limit order should
+/// still fill and an immediate-or-cancel order that can't fill should be cancelled rather than rejected.
+#[test]
+fn max_pending_orders_marketable() {
+    let mut settings = SimBrokerSettings::default();
+    settings.max_pending_orders = 1;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10002));
+
+    sim.place_order(account_uuid, ix, 9000, true, 1, None, None, TimeInForce::GoodTillCancelled).unwrap();
+    match sim.place_order(account_uuid, ix, 10005, true, 1, None, None, TimeInForce::GoodTillCancelled) {
+        Ok(BrokerMessage::PositionOpened{position, ..}) => assert_eq!(position.execution_price, Some(10002)),
+        res => panic!("Marketable limit order wasn't filled at the pending order limit: {:?}", res),
+    }
+    match sim.place_order(account_uuid, ix, 9100, true, 1, None, None, TimeInForce::ImmediateOrCancel) {
+        Ok(BrokerMessage::OrderCancelled{..}) => (),
+        res => panic!("Unexpected result while placing IOC order: {:?}", res),
+    }
+
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.pending_positions.len(), 1);
+    assert_eq!(ledger.open_positions.len(), 1);
+}
+
+/// With precision detection enabled, the precision of a symbol should be inferred from the magnitude of its first
+/// ticks, falling back to the supplied one if they disagree, without any ticks being lost.
+#[test]
+fn precision_detection() {
+    let mut settings = SimBrokerSettings::default();
+    settings.precision_detection_ticks = 3;
+    let (mut sim, _, _) = init_empty_sim(settings);
+
+    let ticks: Vec<Tick> = (0..5).map(|i| Tick {timestamp: i, bid: 110000 + i as usize, ask: 110002 + i as usize}).collect();
+    let ix = register_ticks(&mut sim, "EURUSD", ticks.clone(), true, 4);
+    assert_eq!(sim.symbols[ix].metadata.decimal_precision, 5);
+    assert_eq!(sim.symbols[ix].next_tick, Some(ticks[0]));
+
+    let ambiguous = vec![
+        Tick {timestamp: 0, bid: 9998, ask: 10000},
+        Tick {timestamp: 1, bid: 10002, ask: 10004},
+    ];
+    let ix = register_ticks(&mut sim, "TEST", ambiguous, false, 4);
+    assert_eq!(sim.symbols[ix].metadata.decimal_precision, 4);
+
+    sim.init_sim_loop();
+    let mut buffer = get_buffer();
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+    assert_eq!(sim.symbols[0].ticks_processed, 5);
+}
+
+/// An action executed at exactly the timestamp of the final tick should be processed after that tick, which was
+/// queued first, rather than being lost or racing it when the data runs out.
+#[test]
+fn action_at_final_tick() {
+    let mut settings = SimBrokerSettings::default();
+    settings.execution_delay_ns = 10;
+    let (mut sim, client_tx, account_uuid) = init_empty_sim(settings);
+    let ticks = vec![Tick {timestamp: 0, bid: 9999, ask: 10001}, Tick {timestamp: 10, bid: 10009, ask: 10011}];
+    register_ticks(&mut sim, "TEST", ticks, false, 4);
+    sim.init_sim_loop();
+
+    let mut buffer = get_buffer();
+    // NewTick, ClientTick
+    sim.tick_sim_loop(0, &mut buffer);
+    sim.tick_sim_loop(0, &mut buffer);
+    let (c, o) = oneshot::<BrokerResult>();
+    let action = BrokerAction::TradingAction {
+        account_uuid: account_uuid,
+        action: TradingAction::MarketOrder {
+            symbol: String::from("TEST"), long: true, size: 1, stop: None, take_profit: None, max_range: None,
+        },
+    };
+    client_tx.send((action, c)).unwrap();
+    sim.tick_sim_loop(1, &mut buffer);
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+
+    match o.wait().expect("The action's future was dropped without being completed!") {
+        Ok(BrokerMessage::PositionOpened{position, ..}) => {
+            assert_eq!(position.execution_time, Some(10));
+            assert_eq!(position.execution_price, Some(10011));
+        },
+        res => panic!("Expected the order to be executed at the final tick but got {:?}", res),
+    }
+}
+
+/// The most recent trades of an account should be returned in order of exit time, most recent first.
+#[test]
+fn recent_trades() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let mut pos_uuids = Vec::new();
+    for _ in 0..4 {
+        match sim.market_open(account_uuid, ix, true, 1, None, None, None) {
+            Ok(BrokerMessage::PositionOpened{position_id, ..}) => pos_uuids.push(position_id),
+            res => panic!("Unexpected result while opening position: {:?}", res),
+        }
+    }
+    assert_eq!(sim.recent_trades(account_uuid, 3), Ok(Vec::new()));
+
+    // close them out of the order they were opened in
+    for (i, &pos_ix) in [1, 3, 0, 2].iter().enumerate() {
+        sim.timestamp = i as u64 + 1;
+        sim.market_close(account_uuid, pos_uuids[pos_ix], 1, false).unwrap();
+    }
+
+    let trades = sim.recent_trades(account_uuid, 2).unwrap();
+    let exit_times: Vec<Option<u64>> = trades.iter().map(|pos| pos.exit_time).collect();
+    assert_eq!(exit_times, vec![Some(4), Some(3)]);
+    assert_eq!(sim.recent_trades(account_uuid, 10).unwrap().len(), 4);
+    assert_eq!(sim.recent_trades(Uuid::new_v4(), 2), Err(BrokerError::NoSuchAccount));
+}
+
+/// Runs a simulation over a single tick with a wide spread and returns the price a market buy is filled at after it.
+fn fill_with_spread_mode(spread_mode: SpreadMode) -> usize {
+    let mut settings = SimBrokerSettings::default();
+    settings.spread_mode = spread_mode;
+    settings.fixed_spread = 2;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    let ix = register_ticks(&mut sim, "TEST", vec![Tick {timestamp: 0, bid: 9990, ask: 10010}], false, 4);
+    sim.init_sim_loop();
+    let mut buffer = get_buffer();
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+
+    match sim.market_open(account_uuid, ix, true, 1, None, None, None) {
+        Ok(BrokerMessage::PositionOpened{position, ..}) => position.execution_price.unwrap(),
+        res => panic!("Unexpected result while opening position: {:?}", res),
+    }
+}
+
+/// Fixed spreads should replace the spreads of the ticks around their mid prices while floating spreads use them as-is.
+#[test]
+fn fixed_and_floating_spreads() {
+    assert_eq!(fill_with_spread_mode(SpreadMode::Floating), 10010);
+    assert_eq!(fill_with_spread_mode(SpreadMode::Fixed), 10001);
+}
+
+/// Positions held over the end of a day should be charged financing on the part of their value that isn't covered by
+/// the margin required for them.
+#[test]
+fn daily_financing() {
+    let mut settings = SimBrokerSettings::default();
+    settings.daily_financing_rate = 0.001;
+    settings.leverage = 50;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    let ticks = vec![
+        Tick {timestamp: 0, bid: 10000, ask: 10002},
+        Tick {timestamp: NS_PER_DAY + 1, bid: 10000, ask: 10002},
+    ];
+    let ix = register_ticks(&mut sim, "TEST", ticks, false, 4);
+    sim.init_sim_loop();
+    let mut buffer = get_buffer();
+    // NewTick, ClientTick
+    sim.tick_sim_loop(0, &mut buffer);
+    sim.tick_sim_loop(0, &mut buffer);
+
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 100, None);
+    let buying_power = sim.get_ledger_clone(account_uuid).unwrap().buying_power;
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+
+    // 1,000,200 is held, 20,004 of which is margin at 50x leverage, so 980,196 is borrowed
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.open_positions[&pos_uuid].fees, 980);
+    assert_eq!(ledger.buying_power, buying_power - 980);
+}
+
+/// Positions shouldn't be able to be opened or grown past the maximum position size of their symbol.
+#[test]
+fn max_position_size() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    sim.set_max_position_size(&String::from("TEST"), 10).unwrap();
+    let too_large = Err(BrokerError::PositionTooLarge{symbol: String::from("TEST"), max_position_size: 10});
+
+    assert!(sim.market_open(account_uuid, ix, true, 10, None, None, None).is_ok());
+    assert_eq!(sim.market_open(account_uuid, ix, true, 11, None, None, None), too_large);
+
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 6, None);
+    assert_eq!(sim.increase_position(account_uuid, pos_uuid, 5), too_large);
+
+    sim.oneshot_price_set(String::from("TEST"), (10010, 10012), false, 4);
+    match sim.increase_position(account_uuid, pos_uuid, 4) {
+        Ok(BrokerMessage::PositionModified{position, ..}) => {
+            assert_eq!(position.size, 10);
+            // (6 * 10002 + 4 * 10012) / 10
+            assert_eq!(position.execution_price, Some(10006));
+        },
+        res => panic!("Unexpected result while increasing position: {:?}", res),
+    }
+    assert_eq!(sim.get_ledger_clone(account_uuid).unwrap().verify_balance(), Ok(()));
+}
+
+/// Moving a symbol to the price computed for a target equity should bring the account's equity to that target.
+#[test]
+fn price_for_equity() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let symbol = String::from("TEST");
+    assert_eq!(sim.price_for_equity(account_uuid, &symbol, 0), None);
+
+    sim.market_open(account_uuid, ix, true, 10, None, None, None).unwrap();
+    let equity = sim.margin_summary(account_uuid).unwrap().equity;
+    let target_equity = equity + 1000;
+    let price = sim.price_for_equity(account_uuid, &symbol, target_equity).unwrap();
+    assert_eq!(price, 10100);
+
+    sim.oneshot_price_set(symbol.clone(), (price, price + 2), false, 4);
+    assert_eq!(sim.margin_summary(account_uuid).unwrap().equity, target_equity);
+    assert_eq!(sim.price_for_equity(Uuid::new_v4(), &symbol, target_equity), None);
+}
+
+/// Opens a position with the supplied stop and take profit at (10000, 10002) and returns the result.
+fn open_bracket(strict_brackets: bool, long: bool, stop: usize, take_profit: usize) -> BrokerResult {
+    let mut settings = SimBrokerSettings::default();
+    settings.strict_brackets = strict_brackets;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10002));
+    sim.market_open(account_uuid, ix, long, 1, Some(stop), Some(take_profit), None)
+}
+
+/// Stops and take profits on the wrong side of the entry price or each other should be rejected unless
+/// `strict_brackets` is disabled.
+#[test]
+fn bracket_ordering() {
+    // entries are at the ask for longs and the bid for shorts
+    assert!(open_bracket(true, true, 9900, 10100).is_ok());
+    assert!(open_bracket(true, false, 10100, 9900).is_ok());
+
+    assert_eq!(open_bracket(true, true, 10050, 10100), Err(BrokerError::InvalidStopValue));
+    assert_eq!(open_bracket(true, true, 9900, 9950), Err(BrokerError::InvalidTakeProfitValue));
+    assert_eq!(open_bracket(true, true, 10100, 9900), Err(BrokerError::InvertedBracket{stop: 10100, take_profit: 9900}));
+    assert_eq!(open_bracket(true, false, 9950, 9900), Err(BrokerError::InvalidStopValue));
+    assert_eq!(open_bracket(true, false, 10100, 10050), Err(BrokerError::InvalidTakeProfitValue));
+    assert_eq!(open_bracket(true, false, 9900, 10100), Err(BrokerError::InvertedBracket{stop: 9900, take_profit: 10100}));
+
+    assert!(open_bracket(false, true, 10100, 9900).is_ok());
+    assert!(open_bracket(false, false, 9900, 10100).is_ok());
+}
+
+/// Runs a simulation over ten seconds of ticks with a position opened after the first one, sampling equity every
+/// second, and returns the broker along with the uuid of its default account.
+fn run_equity_scenario(equity_curve_file: String) -> (SimBroker, Uuid) {
+    let mut settings = SimBrokerSettings::default();
+    settings.rng_seed = 42;
+    settings.equity_sample_interval_ns = 1000000000;
+    settings.equity_curve_file = equity_curve_file;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    let ticks: Vec<Tick> = (0..10)
+        .map(|i| Tick {timestamp: i * 1000000000, bid: 10000 + 7 * i as usize, ask: 10002 + 7 * i as usize})
+        .collect();
+    let ix = register_ticks(&mut sim, "TEST", ticks, false, 4);
+    sim.init_sim_loop();
+    let mut buffer = get_buffer();
+    // NewTick, ClientTick
+    sim.tick_sim_loop(0, &mut buffer);
+    sim.tick_sim_loop(0, &mut buffer);
+    sim.market_open(account_uuid, ix, true, 10, None, None, None).unwrap();
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+
+    (sim, account_uuid)
+}
+
+/// Equity samples written to a file should be the same as the ones that would have been kept in memory.
+#[test]
+fn equity_curve_file() {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    let (sim, account_uuid) = run_equity_scenario(String::new());
+    let curve = sim.equity_curve(account_uuid).unwrap().to_vec();
+    assert_eq!(curve.len(), 10);
+    assert!(curve[0].1 != curve[9].1);
+
+    let path = env::temp_dir().join(format!("simbroker_equity_{}.csv", Uuid::new_v4()));
+    let (sim, file_account_uuid) = run_equity_scenario(path.to_str().unwrap().to_string());
+    assert_eq!(file_account_uuid, account_uuid);
+    assert!(sim.equity_curve(account_uuid).unwrap().is_empty());
+    // flushes the rest of the samples
+    drop(sim);
+
+    let mut contents = String::new();
+    File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+    fs::remove_file(&path).unwrap();
+    let expected: Vec<String> = curve.iter()
+        .map(|&(timestamp, equity)| format!("{},{},{}", timestamp, account_uuid, equity))
+        .collect();
+    assert_eq!(contents.lines().collect::<Vec<_>>(), expected);
+}
+
+/// Actions on a position that was stopped out after the client sent them but before they reached the broker should
+/// fail with `PositionAlreadyClosed` rather than `NoSuchPosition`.
+#[test]
+fn action_on_stopped_position() {
+    let mut settings = SimBrokerSettings::default();
+    settings.execution_delay_ns = 10;
+    let (mut sim, client_tx, account_uuid) = init_empty_sim(settings);
+    let ticks = vec![Tick {timestamp: 0, bid: 10000, ask: 10002}, Tick {timestamp: 5, bid: 9890, ask: 9892}];
+    let ix = register_ticks(&mut sim, "TEST", ticks, false, 4);
+    sim.init_sim_loop();
+    let mut buffer = get_buffer();
+    // NewTick, ClientTick
+    sim.tick_sim_loop(0, &mut buffer);
+    sim.tick_sim_loop(0, &mut buffer);
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, Some(9900));
+
+    // both actions are sent before the tick that stops the position out but executed after it
+    let (modify_c, modify_o) = oneshot::<BrokerResult>();
+    let modify = TradingAction::ModifyPosition{uuid: pos_uuid, stop: Some(9950), take_profit: None};
+    client_tx.send((BrokerAction::TradingAction{account_uuid: account_uuid, action: modify}, modify_c)).unwrap();
+    let (close_c, close_o) = oneshot::<BrokerResult>();
+    let close = TradingAction::MarketClose{uuid: pos_uuid, size: 1, reduce_only: false};
+    client_tx.send((BrokerAction::TradingAction{account_uuid: account_uuid, action: close}, close_c)).unwrap();
+    sim.tick_sim_loop(2, &mut buffer);
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+
+    assert!(sim.get_ledger_clone(account_uuid).unwrap().closed_positions.contains_key(&pos_uuid));
+    assert_eq!(modify_o.wait().unwrap(), Err(BrokerError::PositionAlreadyClosed));
+    assert_eq!(close_o.wait().unwrap(), Err(BrokerError::PositionAlreadyClosed));
+}
+
+/// Closing part of, modifying or pricing a position that has already been closed should fail with
+/// `PositionAlreadyClosed`.
+#[test]
+fn reduce_closed_position() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 2, None);
+    sim.market_close(account_uuid, pos_uuid, 2, false).unwrap();
+
+    assert_eq!(sim.reduce_position_at(account_uuid, pos_uuid, 1, 10000), Err(BrokerError::PositionAlreadyClosed));
+    assert_eq!(sim.break_even_price(account_uuid, pos_uuid), Err(BrokerError::PositionAlreadyClosed));
+    let mut ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.modify_position(pos_uuid, Some(None), None, 0), Err(BrokerError::PositionAlreadyClosed));
+    assert_eq!(ledger.modify_position(Uuid::new_v4(), Some(None), None, 0), Err(BrokerError::NoSuchPosition));
+}
+
+/// Opens a position in a broker created with the supplied `rng_seed` and returns the seed the broker reports along
+/// with the Uuids of its default account and the new position.
+fn seeded_run(rng_seed: u32) -> (u32, Uuid, Uuid) {
+    let mut settings = SimBrokerSettings::default();
+    settings.rng_seed = rng_seed;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, None);
+
+    (sim.seed(), account_uuid, pos_uuid)
+}
+
+/// A randomly chosen seed should be reported so that passing it back in reproduces the run exactly.
+#[test]
+fn reported_seed_reproduces_run() {
+    let (seed, account_uuid, pos_uuid) = seeded_run(0);
+    assert!(seed != 0);
+    assert_eq!(seeded_run(seed), (seed, account_uuid, pos_uuid));
+
+    let ticks: Vec<Tick> = (0..10).map(|t| Tick {timestamp: t + 1, bid: 10000, ask: 10002}).collect();
+    let source = vec![(String::from("TEST"), ticks, false, 4)];
+    let first = run_parallel(vec![(SimBrokerSettings::default(), source.clone())]).pop().unwrap().unwrap();
+    let mut settings = SimBrokerSettings::default();
+    settings.rng_seed = first.seed;
+    let second = run_parallel(vec![(settings, source)]).pop().unwrap().unwrap();
+    assert_eq!(first, second);
+}
+
+/// The maximum drawdown duration should be the longest stretch from an equity high to the sample that recovered it,
+/// including a stretch that hasn't recovered yet.
+#[test]
+fn max_drawdown_duration() {
+    let curve = [(0, 1000), (10, 1200), (20, 1100), (30, 900), (40, 1150), (50, 1200), (60, 1300), (70, 1250), (80, 1290)];
+    let mut stats = AccountStats::default();
+    for &(timestamp, equity) in curve.iter() {
+        stats.record_equity(timestamp, equity);
+    }
+    assert_eq!(stats.max_drawdown_duration_ns, 40);
+
+    // still below the high of 1300 reached at 60
+    stats.record_equity(200, 1299);
+    assert_eq!(stats.max_drawdown_duration_ns, 140);
+    stats.record_equity(210, 1400);
+    stats.record_equity(220, 1390);
+    assert_eq!(stats.max_drawdown_duration_ns, 140);
+}
+
+/// Currencies without a pair against the base currency should be converted through the cross currency, with the
+/// rate being the product of the rates of the two legs.
+#[test]
+fn triangulated_conversion_rates() {
+    let mut settings = SimBrokerSettings::default();
+    settings.fx_cross_currency = String::from("EUR");
+    let (mut sim, _, _) = init_empty_sim(settings);
+    register_ticks(&mut sim, "GBPEUR", vec![Tick {timestamp: 1, bid: 116996, ask: 117000}], true, 5);
+    register_ticks(&mut sim, "EURUSD", vec![Tick {timestamp: 1, bid: 107996, ask: 108000}], true, 5);
+    sim.init_sim_loop();
+    sim.step(10);
+
+    assert_eq!(sim.get_base_rate("EUR", 5), Ok(108000));
+    assert_eq!(sim.get_base_rate("GBP", 5), Ok(117000 * 108000 / 100000));
+    assert_eq!(sim.get_base_rate("CHF", 5), Err(BrokerError::NoDataAvailable));
+
+    sim.settings.fx_cross_currency = String::new();
+    assert_eq!(sim.get_base_rate("GBP", 5), Err(BrokerError::NoDataAvailable));
+}
+
+/// Limit closes should rest until the market reaches their exit prices and then close the units they cover, leaving
+/// the rest of the position open.
+#[test]
+fn limit_close() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 10, None);
+    let limit_close = |size: usize, exit_price: usize| BrokerAction::TradingAction {
+        account_uuid: account_uuid,
+        action: TradingAction::LimitClose{uuid: pos_uuid, size: size, exit_price: exit_price},
+    };
+    assert_eq!(sim.exec_action(&limit_close(11, 10100)), Err(BrokerError::InvalidModificationAmount));
+
+    assert_eq!(sim.exec_action(&limit_close(4, 10100)), Ok(BrokerMessage::Success));
+    assert_eq!(tick_price(&mut sim, ix, (10050, 10052)), Vec::new());
+    let msgs = tick_price(&mut sim, ix, (10100, 10102));
+    assert!(msgs.iter().any(|msg| match msg {
+        &Ok(BrokerMessage::PositionModified{ref position, position_id, ..}) => {
+            position_id == pos_uuid && position.size == 6
+        },
+        _ => false,
+    }));
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.open_positions[&pos_uuid].size, 6);
+    // the exit order is used up once it's filled
+    assert_eq!(tick_price(&mut sim, ix, (10110, 10112)), Vec::new());
+
+    assert_eq!(sim.exec_action(&limit_close(6, 10200)), Ok(BrokerMessage::Success));
+    let msgs = tick_price(&mut sim, ix, (10200, 10202));
+    assert!(msgs.iter().any(|msg| match msg {
+        &Ok(BrokerMessage::PositionClosed{position_id, reason: PositionClosureReason::MarketClose, ..}) => {
+            position_id == pos_uuid
+        },
+        _ => false,
+    }));
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert!(!ledger.open_positions.contains_key(&pos_uuid));
+    assert_eq!(ledger.closed_positions[&pos_uuid].exit_price, Some(10200));
+    assert_eq!(ledger.verify_balance(), Ok(()));
+}
+
+/// A tick through the stop of an open position should move it from the ledger's open positions to its closed ones,
+/// marked with the price it was stopped out at.
+#[test]
+fn stopped_position_moves_to_closed() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, Some(9900));
+    assert!(sim.get_ledger_clone(account_uuid).unwrap().open_positions.contains_key(&pos_uuid));
+
+    let msgs = tick_price(&mut sim, ix, (9890, 9892));
+    assert!(msgs.iter().any(|msg| match msg {
+        &Ok(BrokerMessage::PositionClosed{position_id, reason: PositionClosureReason::StopLoss, ..}) => {
+            position_id == pos_uuid
+        },
+        _ => false,
+    }));
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert!(!ledger.open_positions.contains_key(&pos_uuid));
+    assert_eq!(ledger.closed_positions[&pos_uuid].exit_price, Some(9890));
+    assert!(sim.accounts.positions[ix].open.is_empty());
+}
+
+/// With start and end notices enabled, they should be the first and last messages of the push stream.
+#[test]
+fn lifecycle_notices() {
+    let mut settings = SimBrokerSettings::default();
+    settings.notices = "start,end".parse().unwrap();
+    let (mut sim, client_tx, _) = init_empty_sim(settings);
+    let ticks: Vec<Tick> = (0..5).map(|t| Tick {timestamp: t * 10 + 1, bid: 10000, ask: 10002}).collect();
+    register_ticks(&mut sim, "TEST", ticks, false, 4);
+    sim.init_sim_loop();
+
+    let mut buffer = get_buffer();
+    let mut pushes = Vec::new();
+    let mut num_actions = 0;
+    let mut pinged = false;
+    while !sim.is_finished() {
+        let count = sim.tick_sim_loop(num_actions, &mut buffer);
+        num_actions = 0;
+        for output in buffer.iter().take(count) {
+            if let &TickOutput::Pushstream(_, ref res) = output {
+                pushes.push(res.clone());
+            }
+        }
+        if !pinged {
+            // put a response to an action in the middle of the push stream
+            let (c, _o) = oneshot::<BrokerResult>();
+            client_tx.send((BrokerAction::Ping, c)).unwrap();
+            num_actions = 1;
+            pinged = true;
+        }
+    }
+
+    assert_eq!(pushes.len(), 3);
+    assert_eq!(pushes[0], Ok(BrokerMessage::Notice{message: String::from("Simulation started at 1")}));
+    match pushes[1] {
+        Ok(BrokerMessage::Pong{..}) => (),
+        ref res => panic!("Expected the ping's response between the notices but got {:?}", res),
+    }
+    match pushes[2] {
+        Ok(BrokerMessage::Notice{ref message}) => assert!(message.starts_with("Simulation ended")),
+        ref res => panic!("Expected the end notice to be the last push message but got {:?}", res),
+    }
+
+    assert_eq!("".parse::<Notices>(), Ok(Notices::default()));
+    assert_eq!("bogus".parse::<Notices>(), Err(()));
+}
+
+/// Closed positions should store the profit or loss realized by closing them, summed over partial closes.
+#[test]
+fn stored_position_pnl() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 10, None);
+    let open_pos = sim.get_ledger_clone(account_uuid).unwrap().open_positions[&pos_uuid].clone();
+    assert_eq!(open_pos.pnl(), None);
+
+    // 4 units closed at 10100 after being bought at 10002
+    sim.symbols[ix].price = (10100, 10102);
+    sim.market_close(account_uuid, pos_uuid, 4, true).unwrap();
+    assert_eq!(sim.get_ledger_clone(account_uuid).unwrap().open_positions[&pos_uuid].pnl(), Some(392));
+
+    // plus the remaining 6 units closed at 10050
+    sim.symbols[ix].price = (10050, 10052);
+    sim.market_close(account_uuid, pos_uuid, 6, true).unwrap();
+    let closed_pos = sim.get_ledger_clone(account_uuid).unwrap().closed_positions[&pos_uuid].clone();
+    assert_eq!(closed_pos.pnl(), Some(392 + 288));
+}
+
+/// PnL realized by partially closing a position should count towards its symbol's PnL once it's fully closed.
+#[test]
+fn pnl_by_symbol_partial_closes() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10000));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 4, None);
+    sim.symbols[ix].price = (10010, 10010);
+    sim.market_close(account_uuid, pos_uuid, 1, false).unwrap();
+    sim.symbols[ix].price = (10020, 10020);
+    sim.market_close(account_uuid, pos_uuid, 3, false).unwrap();
+
+    // 1 * 10 + 3 * 20
+    let pnls = sim.pnl_by_symbol(account_uuid).unwrap();
+    assert_eq!(pnls.get("TEST"), Some(&70));
+}
+
+/// Opening a position should file it in the ledger under the UUID reported to the client and take exactly its
+/// value out of the account's buying power.
+#[test]
+fn open_reserves_position_value() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let starting_balance = sim.get_ledger_clone(account_uuid).unwrap().buying_power;
+    let (pos_uuid, pos) = match sim.market_open(account_uuid, ix, true, 7, None, None, None) {
+        Ok(BrokerMessage::PositionOpened{position_id, position, ..}) => (position_id, position),
+        res => panic!("Unexpected result while opening position: {:?}", res),
+    };
+
+    let value = sim.get_position_value(&pos).unwrap();
+    assert_eq!(value, 7 * 10002);
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.open_positions[&pos_uuid], pos);
+    assert_eq!(ledger.reserved_margin[&pos_uuid], value);
+    assert_eq!(ledger.buying_power, starting_balance - value);
+}
+
+/// Market orders sized in the quote currency should be converted to the number of units that amount buys at the
+/// price they're filled at, rounded down to whole lots.
+#[test]
+fn quote_sized_orders() {
+    let (mut sim, _, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let mut open_quote_sized = |long: bool, amount: usize| {
+        let action = BrokerAction::TradingAction {
+            account_uuid: account_uuid,
+            action: TradingAction::MarketOrderQuote {
+                symbol: String::from("TEST"), long: long, amount: amount, stop: None, take_profit: None, max_range: None,
+            },
+        };
+        match sim.exec_action(&action) {
+            Ok(BrokerMessage::PositionOpened{position, ..}) => position,
+            res => panic!("Unexpected result while opening position: {:?}", res),
+        }
+    };
+
+    let pos = open_quote_sized(true, 500100);
+    assert_eq!(pos.execution_price, Some(10002));
+    assert_eq!(pos.size * pos.execution_price.unwrap(), 500100);
+
+    // 50.49 units at the bid
+    let pos = open_quote_sized(false, 504900);
+    assert_eq!(pos.execution_price, Some(10000));
+    assert_eq!(pos.size, 50);
+}
+
+/// Placing an order should file it in the ledger's pending positions and reserve its value until it's cancelled.
+#[test]
+fn placed_order_reserves_value() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let starting_balance = sim.get_ledger_clone(account_uuid).unwrap().buying_power;
+    let res = sim.place_order(account_uuid, ix, 9000, true, 3, None, None, TimeInForce::GoodTillCancelled);
+    let (order_uuid, order) = match res {
+        Ok(BrokerMessage::OrderPlaced{order_id, order, ..}) => (order_id, order),
+        res => panic!("Unexpected result while placing order: {:?}", res),
+    };
+
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.pending_positions[&order_uuid], order);
+    assert_eq!(ledger.reserved_margin[&order_uuid], 3 * 9000);
+    assert_eq!(ledger.buying_power, starting_balance - 3 * 9000);
+
+    sim.cancel_order(account_uuid, order_uuid).unwrap();
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert!(ledger.pending_positions.is_empty());
+    assert_eq!(ledger.buying_power, starting_balance);
+}
+
+/// Injects a ping dated before the current time of the simulation and one dated at it and returns the result of
+/// the past-dated one along with the number of lookahead violations the broker detected.
+fn inject_past_ping(lookahead_handling: LookaheadHandling) -> (BrokerResult, usize) {
+    let mut settings = SimBrokerSettings::default();
+    settings.lookahead_handling = lookahead_handling;
+    let (mut sim, _, _) = init_empty_sim(settings);
+    let ticks = vec![Tick {timestamp: 10, bid: 9999, ask: 10001}, Tick {timestamp: 20, bid: 9999, ask: 10001}];
+    register_ticks(&mut sim, "TEST", ticks, false, 4);
+    sim.init_sim_loop();
+    let mut buffer = get_buffer();
+    // NewTick, ClientTick
+    sim.tick_sim_loop(0, &mut buffer);
+    sim.tick_sim_loop(0, &mut buffer);
+
+    let (c, o) = oneshot::<BrokerResult>();
+    sim.queue_action_at(5, BrokerAction::Ping, c);
+    let (current_c, current_o) = oneshot::<BrokerResult>();
+    sim.queue_action_at(10, BrokerAction::Ping, current_c);
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+    assert!(current_o.wait().unwrap().is_ok());
+
+    (o.wait().unwrap(), sim.lookahead_violations())
+}
+
+/// Injected actions dated before the current time should be detected and either executed or rejected depending on
+/// the `lookahead_handling` setting.
+#[test]
+fn lookahead_detection() {
+    match inject_past_ping(LookaheadHandling::Warn) {
+        (Ok(BrokerMessage::Pong{..}), 1) => (),
+        res => panic!("Expected the action to be executed with a warning but got {:?}", res),
+    }
+
+    assert_eq!(
+        inject_past_ping(LookaheadHandling::Reject),
+        (Err(BrokerError::LookaheadBias{action_timestamp: 5, current_timestamp: 10}), 1)
+    );
+}
+
+/// With a commission configured, a round trip at a flat price should lose exactly the commission charged on each
+/// side of the trade, and it should be recorded on the position.
+#[test]
+fn commission_round_trip() {
+    let mut hm = HashMap::new();
+    hm.insert(String::from("commission"), String::from("7"));
+    let settings = SimBrokerSettings::from_hashmap(hm);
+    assert_eq!(settings.commission, 7);
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+    let starting_balance = sim.get_ledger_clone(account_uuid).unwrap().buying_power;
+
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 10, None);
+    sim.market_close(account_uuid, pos_uuid, 10, false).unwrap();
+
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.buying_power, starting_balance - 2 * 7 * 10);
+    assert_eq!(ledger.closed_positions[&pos_uuid].fees, 2 * 7 * 10);
+    assert_eq!(ledger.verify_balance(), Ok(()));
+}
+
+/// With push message batching enabled, all of the messages generated at one timestamp should arrive as a single
+/// batch once the simulation moves past it.
+#[test]
+fn batched_push_messages() {
+    let mut settings = SimBrokerSettings::default();
+    settings.batch_push_messages = true;
+    let (mut sim, _, account_uuid) = init_empty_sim(settings);
+    let ticks = vec![
+        Tick {timestamp: 10, bid: 10000, ask: 10002},
+        Tick {timestamp: 20, bid: 9800, ask: 9802},
+        Tick {timestamp: 30, bid: 9800, ask: 9802},
+    ];
+    let ix = register_ticks(&mut sim, "TEST", ticks, false, 4);
+    sim.init_sim_loop();
+    let mut buffer = get_buffer();
+    // NewTick, ClientTick
+    sim.tick_sim_loop(0, &mut buffer);
+    sim.tick_sim_loop(0, &mut buffer);
+    sim.market_open(account_uuid, ix, true, 1, Some(9900), None, None).unwrap();
+    sim.market_open(account_uuid, ix, true, 2, Some(9900), None, None).unwrap();
+
+    let mut pushes = Vec::new();
+    while !sim.is_finished() {
+        let count = sim.tick_sim_loop(0, &mut buffer);
+        for output in buffer.iter().take(count) {
+            if let &TickOutput::Pushstream(timestamp, ref res) = output {
+                pushes.push((timestamp, res.clone()));
+            }
+        }
+    }
+
+    assert_eq!(pushes.len(), 1);
+    match pushes[0] {
+        (20, Ok(BrokerMessage::Batch(ref msgs))) => {
+            let closed = msgs.iter().filter(|msg| match msg {
+                &&Ok(BrokerMessage::PositionClosed{..}) => true,
+                _ => false,
+            }).count();
+            assert_eq!(closed, 2);
+        },
+        ref push => panic!("Expected a single batch of the stopped out positions but got {:?}", push),
+    }
+}
+
+/// Under the proportional slippage model, large market orders and closes should be filled worse than small ones.
+#[test]
+fn proportional_slippage() {
+    let mut settings = SimBrokerSettings::default();
+    settings.slippage_mode = "proportional".parse().unwrap();
+    settings.slippage_pips = 1;
+    settings.slippage_units = 10;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10002));
+
+    let (small_fill, large_fill, large_uuid) = {
+        let mut open = |size: usize| match sim.market_open(account_uuid, ix, true, size, None, None, None) {
+            Ok(BrokerMessage::PositionOpened{position_id, position, ..}) => {
+                assert_eq!(position.price, Some(10002));
+                (position_id, position.execution_price.unwrap())
+            },
+            res => panic!("Unexpected result while opening position: {:?}", res),
+        };
+        let (_, small_fill) = open(5);
+        let (large_uuid, large_fill) = open(50);
+        (small_fill, large_fill, large_uuid)
+    };
+    assert_eq!(small_fill, 10002);
+    assert_eq!(large_fill, 10007);
+
+    // closing sells below the bid
+    sim.market_close(account_uuid, large_uuid, 50, false).unwrap();
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.closed_positions[&large_uuid].exit_price, Some(9995));
+}
+
+/// The maximum affordable size should be the largest position that can be opened including its opening fee.
+#[test]
+fn max_affordable_size() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    sim.set_fee_model(Box::new(FixedFeeModel {fee: 500}));
+    let name = String::from("TEST");
+    assert_eq!(sim.max_affordable_size(Uuid::new_v4(), &name, true), Err(BrokerError::NoSuchAccount));
+    assert_eq!(sim.max_affordable_size(account_uuid, &String::from("NOPE"), true), Err(BrokerError::NoSuchSymbol));
+
+    // (5,000,000 - 500) / 10,002 = 499.85
+    let size = sim.max_affordable_size(account_uuid, &name, true).unwrap();
+    assert_eq!(size, 499);
+    assert_eq!(
+        sim.market_open(account_uuid, ix, true, size + 1, None, None, None),
+        Err(BrokerError::InsufficientBuyingPower)
+    );
+    assert!(sim.market_open(account_uuid, ix, true, size, None, None, None).is_ok());
+    assert_eq!(sim.max_affordable_size(account_uuid, &name, false), Ok(0));
+}
+
+/// A broker restored from a dump of another should have the same accounts, time, and prices, and should keep
+/// managing the positions it was restored with.
+#[test]
+fn dump_and_restore() {
+    use std::{env, fs};
+
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    sim.timestamp = 1000;
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 3, Some(9900));
+    sim.place_order(account_uuid, ix, 9000, true, 2, None, None, TimeInForce::GoodTillCancelled).unwrap();
+
+    let path = env::temp_dir().join(format!("simbroker_snapshot_{}.json", Uuid::new_v4()));
+    let filename = path.to_str().unwrap();
+    sim.dump_to_file(filename).unwrap();
+    let (_, client_rx) = mpsc::channel();
+    let cs = CommandServer::new(Uuid::new_v4(), "SimBroker Test");
+    let mut restored = SimBroker::restore_from_file(filename, cs, client_rx).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(restored.timestamp, 1000);
+    assert_eq!(restored.accounts.len(), 1);
+    assert_eq!(restored.get_ledger_clone(account_uuid), sim.get_ledger_clone(account_uuid));
+    let restored_ix = restored.symbols.get_index(&String::from("TEST")).unwrap();
+    assert_eq!(restored.symbols[restored_ix].price, (10000, 10002));
+
+    // the restored position is still stopped out
+    tick_price(&mut restored, restored_ix, (9890, 9892));
+    let ledger = restored.get_ledger_clone(account_uuid).unwrap();
+    assert!(ledger.closed_positions.contains_key(&pos_uuid));
+    assert_eq!(ledger.pending_positions.len(), 1);
+}
+
+/// The trading statistics of a dumped broker and the state of its position automations should be restored along
+/// with its accounts.
+#[test]
+fn dump_and_restore_automations() {
+    use std::{env, fs};
+
+    let mut settings = SimBrokerSettings::default();
+    settings.pnl_minor_unit = 100;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 3, Some(9900));
+    // leaves a remainder of realized PnL too small to be reported
+    sim.market_close(account_uuid, pos_uuid, 1, true).unwrap();
+    sim.set_breakeven_stop(account_uuid, pos_uuid, 20).unwrap();
+    sim.limit_close(account_uuid, pos_uuid, 1, 10100).unwrap();
+    sim.place_pegged_order(account_uuid, ix, true, 1, 10).unwrap();
+    sim.stop_fills.insert(pos_uuid);
+    sim.margin_breaches.insert(account_uuid, 500);
+    sim.set_trading_enabled(account_uuid, false).unwrap();
+
+    let path = env::temp_dir().join(format!("simbroker_snapshot_{}.json", Uuid::new_v4()));
+    let filename = path.to_str().unwrap();
+    sim.dump_to_file(filename).unwrap();
+    let (_, client_rx) = mpsc::channel();
+    let cs = CommandServer::new(Uuid::new_v4(), "SimBroker Test");
+    let restored = SimBroker::restore_from_file(filename, cs, client_rx).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(restored.stats[&account_uuid].pnl_remainder != 0);
+    assert_eq!(restored.stats, sim.stats);
+    assert_eq!(restored.margin_breaches[&account_uuid], 500);
+    assert!(restored.stop_fills.contains(&pos_uuid));
+    assert!(restored.disabled_accounts.contains(&account_uuid));
+    assert_eq!(restored.pegs.values().collect::<Vec<_>>(), vec![&10]);
+    assert_eq!(restored.breakevens[&pos_uuid], 20);
+    assert_eq!(restored.limit_closes[&pos_uuid], (1, 10100));
+}
+
+/// Modifies the stop of a position in a symbol that has lost its price and returns the result along with the stop
+/// that the position ended up with.
+fn modify_unpriced(unpriced_modify_handling: UnpricedModifyHandling) -> (BrokerResult, Option<usize>) {
+    let mut settings = SimBrokerSettings::default();
+    settings.unpriced_modify_handling = unpriced_modify_handling;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, Some(9900));
+    let modify = BrokerAction::TradingAction {
+        account_uuid: account_uuid,
+        action: TradingAction::ModifyPosition{uuid: pos_uuid, stop: Some(10001), take_profit: None},
+    };
+    // with a price, the stop is validated against it
+    assert_eq!(sim.exec_action(&modify), Err(BrokerError::InvalidStopValue));
+
+    sim.symbols[ix].price = (0, 0);
+    let res = sim.exec_action(&modify);
+    let stop = sim.get_ledger_clone(account_uuid).unwrap().open_positions[&pos_uuid].stop;
+    (res, stop)
+}
+
+/// Modifications of positions in symbols without a price should be applied unvalidated or rejected depending on the
+/// `unpriced_modify_handling` setting.
+#[test]
+fn unpriced_modify_handling() {
+    match modify_unpriced(UnpricedModifyHandling::Allow) {
+        (Ok(BrokerMessage::PositionModified{..}), Some(10001)) => (),
+        res => panic!("Expected the modification to be applied but got {:?}", res),
+    }
+    assert_eq!(modify_unpriced(UnpricedModifyHandling::Reject), (Err(BrokerError::NoDataAvailable), Some(9900)));
+}
+
+/// Disconnecting in the middle of the data should complete the disconnect, end the simulation without processing
+/// the rest of the data, and reject actions sent afterwards.
+#[test]
+fn disconnect_ends_simulation() {
+    let (mut sim, client_tx, _) = init_empty_sim(SimBrokerSettings::default());
+    let ticks: Vec<Tick> = (0..100).map(|i| Tick {timestamp: i * 1000, bid: 10000, ask: 10002}).collect();
+    let ix = register_ticks(&mut sim, "TEST", ticks, false, 4);
+    sim.init_sim_loop();
+    let mut buffer = get_buffer();
+    for _ in 0..10 {
+        sim.tick_sim_loop(0, &mut buffer);
+    }
+
+    let (disconnect_c, disconnect_o) = oneshot::<BrokerResult>();
+    client_tx.send((BrokerAction::Disconnect, disconnect_c)).unwrap();
+    sim.tick_sim_loop(1, &mut buffer);
+    let mut events = 0;
+    while !sim.is_finished() {
+        sim.tick_sim_loop(0, &mut buffer);
+        events += 1;
+        assert!(events < 100, "The simulation didn't end after the client disconnected");
+    }
+
+    assert_eq!(disconnect_o.wait().unwrap(), Ok(BrokerMessage::Success));
+    assert!(sim.symbols[ix].ticks_processed < 100);
+
+    let (ping_c, ping_o) = oneshot::<BrokerResult>();
+    client_tx.send((BrokerAction::Ping, ping_c)).unwrap();
+    sim.step(1);
+    assert_eq!(ping_o.wait().unwrap(), Err(BrokerError::SimulationEnded));
+}
+
+/// Closed trades should be grouped by the hour of the day they were entered at, regardless of the day.
+#[test]
+fn trades_by_hour() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    {
+        let mut trade = |entry_hour: u64, exit_bid: usize| {
+            sim.timestamp = entry_hour * NS_PER_HOUR + 1000;
+            sim.symbols[ix].price = (10000, 10002);
+            let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, None);
+            // exited in the next hour
+            sim.timestamp += NS_PER_HOUR;
+            sim.symbols[ix].price = (exit_bid, exit_bid + 2);
+            sim.market_close(account_uuid, pos_uuid, 1, true).unwrap();
+        };
+        trade(3, 10100);
+        trade(3, 9900);
+        trade(14, 10200);
+        // hour 23 of the second day
+        trade(47, 10050);
+    }
+
+    let mut expected = HashMap::new();
+    expected.insert(3, (2, 98 - 102));
+    expected.insert(14, (1, 198));
+    expected.insert(23, (1, 48));
+    assert_eq!(sim.trades_by_hour(account_uuid), Ok(expected));
+    assert_eq!(sim.trades_by_hour(Uuid::new_v4()), Err(BrokerError::NoSuchAccount));
+}
+
+/// With 10:1 leverage, a position should only reserve a tenth of the margin it does at 1:1 while its value and PnL
+/// are unaffected.
+#[test]
+fn leveraged_margin() {
+    let open = |leverage: usize| {
+        let mut settings = SimBrokerSettings::default();
+        settings.leverage = leverage;
+        let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10002));
+        let (pos_uuid, pos) = match sim.market_open(account_uuid, ix, true, 100, None, None, None) {
+            Ok(BrokerMessage::PositionOpened{position_id, position, ..}) => (position_id, position),
+            res => panic!("Unexpected result while opening position: {:?}", res),
+        };
+        let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+        assert_eq!(sim.get_position_value(&pos), Ok(100 * 10002));
+        assert_eq!(sim.get_position_pnl(&pos, 10012), Ok(100 * 10));
+        assert_eq!(ledger.used_margin(), ledger.reserved_margin[&pos_uuid]);
+        assert_eq!(ledger.buying_power, ledger.starting_balance - ledger.used_margin());
+        ledger.used_margin()
+    };
+
+    let unleveraged = open(1);
+    assert_eq!(unleveraged, 100 * 10002);
+    assert_eq!(open(10), unleveraged / 10);
+}
+
+/// Positions shouldn't be opened after the open cutoff, but ones opened before it should still be closable.
+#[test]
+fn open_cutoff() {
+    let mut settings = SimBrokerSettings::default();
+    settings.open_cutoff_timestamp = 100;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10002));
+    sim.timestamp = 50;
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, None);
+
+    sim.timestamp = 150;
+    let past_cutoff = Err(BrokerError::PastOpenCutoff{symbol: String::from("TEST"), cutoff: 100});
+    assert_eq!(sim.market_open(account_uuid, ix, true, 1, None, None, None), past_cutoff);
+    assert_eq!(
+        sim.place_order(account_uuid, ix, 9000, true, 1, None, None, TimeInForce::GoodTillCancelled),
+        past_cutoff
+    );
+    match sim.market_close(account_uuid, pos_uuid, 1, true) {
+        Ok(BrokerMessage::PositionClosed{position_id, ..}) => assert_eq!(position_id, pos_uuid),
+        res => panic!("Unexpected result while closing position after the cutoff: {:?}", res),
+    }
+
+    // the cutoff can be disabled per symbol
+    sim.set_open_cutoff(&String::from("TEST"), 0).unwrap();
+    assert!(sim.market_open(account_uuid, ix, true, 1, None, None, None).is_ok());
+}
+
+/// Margin calls should liquidate the position with the largest loss first and stop once the account is back above
+/// the margin call level.
+#[test]
+fn margin_call_largest_loss_first() {
+    let mut settings = SimBrokerSettings::default();
+    settings.margin_call_level = 100;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10000));
+    // 4,000,000 of the 5,000,000 balance is used as margin
+    let (small_uuid, large_uuid) = {
+        let mut open = |size: usize| open_position(&mut sim, account_uuid, ix, true, size, None);
+        let small_uuid = open(100);
+        (small_uuid, open(300))
+    };
+
+    // a loss of 1,400,000 puts equity at 3,600,000, below the used margin; closing the larger position brings
+    // the used margin down to 1,000,000
+    let msgs = tick_price(&mut sim, ix, (6500, 6500));
+    let closed: Vec<Uuid> = msgs.iter().filter_map(|msg| match msg {
+        &Ok(BrokerMessage::PositionClosed{position_id, reason: PositionClosureReason::MarginCall, ..}) => {
+            Some(position_id)
+        },
+        _ => None,
+    }).collect();
+    assert_eq!(closed, vec![large_uuid]);
+
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert!(ledger.open_positions.contains_key(&small_uuid));
+    assert_eq!(sim.margin_summary(account_uuid).unwrap().equity, 3600000);
+}
+
+/// With partial close reporting enabled, partially closing a position should report the PnL realized on the closed
+/// units separately from the unrealized PnL of the units that remain open.
+#[test]
+fn partial_close_pnl_split() {
+    let mut settings = SimBrokerSettings::default();
+    settings.report_partial_closes = true;
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(settings, (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 10, None);
+
+    // bought at 10002 and closed at 10100, so each unit is worth 98
+    sim.symbols[ix].price = (10100, 10102);
+    match sim.market_close(account_uuid, pos_uuid, 4, false) {
+        Ok(BrokerMessage::PositionPartiallyClosed{position_id, position, closed_size, realized_pnl, unrealized_pnl, ..})
+        => {
+            assert_eq!(position_id, pos_uuid);
+            assert_eq!(position.size, 6);
+            assert_eq!(closed_size, 4);
+            assert_eq!(realized_pnl, 392);
+            assert_eq!(unrealized_pnl, 588);
+        },
+        res => panic!("Unexpected result while partially closing position: {:?}", res),
+    }
+
+    // closing the rest is reported as a normal close
+    match sim.market_close(account_uuid, pos_uuid, 6, false) {
+        Ok(BrokerMessage::PositionClosed{realized_pnl, ..}) => assert_eq!(realized_pnl, 588),
+        res => panic!("Unexpected result while closing position: {:?}", res),
+    }
+}
+
+/// The stop of a position with a trailing stop should follow the best price it reaches and close the position
+/// once the price pulls back by the trailing distance.
+#[test]
+fn trailing_stop() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, Some(9900));
+    assert_eq!(sim.set_trailing_stop(account_uuid, Uuid::new_v4(), 50), Err(BrokerError::NoSuchPosition));
+    match sim.set_trailing_stop(account_uuid, pos_uuid, 50) {
+        Ok(BrokerMessage::PositionModified{ref position, ..}) => {
+            assert_eq!(position.stop, Some(9950));
+            assert_eq!(position.best_price, Some(10000));
+        },
+        res => panic!("Unexpected result while setting trailing stop: {:?}", res),
+    }
+
+    // the stop ratchets up as the price rises
+    for &(bid, expected_stop) in &[(10100, 10050), (10200, 10150)] {
+        let msgs = tick_price(&mut sim, ix, (bid, bid + 2));
+        assert_eq!(msgs.len(), 1);
+        match msgs[0] {
+            Ok(BrokerMessage::PositionModified{ref position, position_id, ..}) => {
+                assert_eq!(position_id, pos_uuid);
+                assert_eq!(position.stop, Some(expected_stop));
+                assert_eq!(position.best_price, Some(bid));
+            },
+            ref res => panic!("Unexpected push message while the price rose: {:?}", res),
+        }
+    }
+
+    // but not back down when the price falls
+    assert_eq!(tick_price(&mut sim, ix, (10180, 10182)), Vec::new());
+    let ledger = sim.get_ledger_clone(account_uuid).unwrap();
+    assert_eq!(ledger.open_positions[&pos_uuid].stop, Some(10150));
+
+    // pulling back by the trailing distance from the high stops the position out
+    let msgs = tick_price(&mut sim, ix, (10140, 10142));
+    assert!(msgs.iter().any(|msg| match msg {
+        &Ok(BrokerMessage::PositionClosed{position_id, reason: PositionClosureReason::StopLoss, ..}) => {
+            position_id == pos_uuid
+        },
+        _ => false,
+    }));
+}
+
+/// Manually setting the stop of a position with a trailing stop should stop it from trailing so that a looser manual
+/// stop isn't overwritten by the next favorable tick.
+#[test]
+fn manual_stop_disables_trailing() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, Some(9900));
+    sim.set_trailing_stop(account_uuid, pos_uuid, 50).unwrap();
+    match sim.modify_position(account_uuid, pos_uuid, Some(Some(9800)), None) {
+        Ok(BrokerMessage::PositionModified{ref position, ..}) => {
+            assert_eq!(position.stop, Some(9800));
+            assert_eq!(position.trailing_stop, None);
+            assert_eq!(position.best_price, None);
+        },
+        res => panic!("Unexpected result while modifying position: {:?}", res),
+    }
+
+    assert_eq!(tick_price(&mut sim, ix, (10200, 10202)), Vec::new());
+    let stop = sim.get_ledger_clone(account_uuid).unwrap().open_positions[&pos_uuid].stop;
+    assert_eq!(stop, Some(9800));
+}
+
+/// A position with both a break-even automation and a trailing stop should keep both while the trail moves its
+/// stop, move the stop to its entry price once the threshold is reached, and keep trailing after that.
+#[test]
+fn breakeven_with_trailing_stop() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, 1, Some(9900));
+    sim.set_breakeven_stop(account_uuid, pos_uuid, 20).unwrap();
+    sim.set_trailing_stop(account_uuid, pos_uuid, 100).unwrap();
+
+    // the trail moves the stop without removing the break-even automation
+    let stops = |msgs: Vec<BrokerResult>| -> Vec<Option<usize>> {
+        msgs.into_iter().map(|msg| match msg {
+            Ok(BrokerMessage::PositionModified{position, ..}) => position.stop,
+            res => panic!("Unexpected push message: {:?}", res),
+        }).collect()
+    };
+    assert_eq!(stops(tick_price(&mut sim, ix, (10010, 10012))), vec![Some(9910)]);
+    assert!(sim.breakevens.contains_key(&pos_uuid));
+
+    // the break-even threshold is reached while the trail is still below the entry price
+    assert_eq!(stops(tick_price(&mut sim, ix, (10030, 10032))), vec![Some(10002)]);
+    assert!(!sim.breakevens.contains_key(&pos_uuid));
+
+    // and the trail takes over once it passes the entry price
+    assert_eq!(stops(tick_price(&mut sim, ix, (10200, 10202))), vec![Some(10100)]);
+}
+
+/// The size computed for an amount of risk should lose that amount when the position is stopped out.
+#[test]
+fn units_for_risk() {
+    let (mut sim, ix, account_uuid) = init_oneshot_sim(SimBrokerSettings::default(), (10000, 10002));
+    let name = String::from("TEST");
+    assert_eq!(sim.units_for_risk(Uuid::new_v4(), &name, 10002, 9902, 5000), Err(BrokerError::NoSuchAccount));
+    assert_eq!(
+        sim.units_for_risk(account_uuid, &String::from("NOPE"), 10002, 9902, 5000),
+        Err(BrokerError::NoSuchSymbol)
+    );
+    assert_eq!(sim.units_for_risk(account_uuid, &name, 10002, 10002, 5000), Err(BrokerError::InvalidStopValue));
+
+    // 5,000 / 100 per unit; shorts with their stops above the entry are sized the same way
+    assert_eq!(sim.units_for_risk(account_uuid, &name, 10000, 10100, 5000), Ok(50));
+    // 5,050 / 100 per unit is rounded down to whole units
+    let size = sim.units_for_risk(account_uuid, &name, 10002, 9902, 5050).unwrap();
+    assert_eq!(size, 50);
+
+    let pos_uuid = open_position(&mut sim, account_uuid, ix, true, size, Some(9902));
+    let msgs = tick_price(&mut sim, ix, (9902, 9904));
+    let realized: Vec<i64> = msgs.into_iter().filter_map(|msg| match msg {
+        Ok(BrokerMessage::PositionClosed{position_id, realized_pnl, ..}) => {
+            assert_eq!(position_id, pos_uuid);
+            Some(realized_pnl)
+        },
+        _ => None,
+    }).collect();
+    assert_eq!(realized, vec![-5000]);
+}
@@ -0,0 +1,51 @@
+//! Synthetic volume for symbols whose ticks don't carry any.  Volumes are assigned to ticks deterministically as
+//! they're processed by the broker so that volume-dependent calculations such as VWAP can be simulated.
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use tickgrinder_util::trading::tick::Tick;
+
+/// Determines the volume assigned to each tick of a symbol.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VolumeModel {
+    /// Every tick has the same volume
+    Constant(usize),
+    /// Volumes are drawn uniformly from `[min, max)` by a PRNG seeded with `seed`
+    Random{seed: u32, min: usize, max: usize},
+    /// Volumes are inversely proportional to the spread: `scale / spread`, treating zero spreads as one pip
+    SpreadInverse{scale: usize},
+}
+
+/// Assigns volumes to ticks according to a `VolumeModel`.
+pub struct VolumeGenerator {
+    model: VolumeModel,
+    rng: XorShiftRng,
+}
+
+impl VolumeGenerator {
+    pub fn new(model: VolumeModel) -> VolumeGenerator {
+        let seed = match model {
+            VolumeModel::Random{seed, ..} => seed,
+            _ => 0,
+        };
+        // the xorshift generator can't be seeded with all zeros
+        let rng = XorShiftRng::from_seed([seed, seed ^ 0x9E3779B9, 0x243F6A88, 0xB7E15162]);
+
+        VolumeGenerator {
+            model: model,
+            rng: rng,
+        }
+    }
+
+    /// Returns the volume of the next tick.
+    pub fn volume(&mut self, tick: &Tick) -> usize {
+        match self.model {
+            VolumeModel::Constant(volume) => volume,
+            VolumeModel::Random{min, max, ..} => self.rng.gen_range(min, max),
+            VolumeModel::SpreadInverse{scale} => {
+                let spread = if tick.ask > tick.bid { tick.ask - tick.bid } else { 1 };
+                scale / spread
+            },
+        }
+    }
+}
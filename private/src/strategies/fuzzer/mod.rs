@@ -11,7 +11,7 @@ use uuid::Uuid;
 
 use tickgrinder_util::strategies::{ManagedStrategy, Helper, StrategyAction, Tickstream, Merged};
 use tickgrinder_util::trading::broker::{Broker, BrokerResult};
-use tickgrinder_util::trading::objects::{BrokerAction, BrokerMessage, Account, Ledger};
+use tickgrinder_util::trading::objects::{BrokerAction, BrokerMessage, Account, Ledger, TimeInForce};
 use tickgrinder_util::trading::tick::{Tick, GenTick};
 use tickgrinder_util::trading::trading_condition::TradingAction;
 use tickgrinder_util::transport::textlog::get_logger_handle;
@@ -170,6 +170,7 @@ pub fn get_action(state: &mut FuzzerState, t: &Tick, rng: *mut c_void) -> Option
                 stop: if random_bool(rng) { Some(price + unsafe { rand_int_range(rng, 0, 5) as usize }) } else { None },
                 take_profit: if random_bool(rng) { Some(price + unsafe { rand_int_range(rng, 0, 5) as usize }) } else { None },
                 entry_price: price,
+                time_in_force: TimeInForce::GoodTillCancelled,
             };
 
             Some(StrategyAction::BrokerAction(BrokerAction::TradingAction{
@@ -195,6 +196,7 @@ pub fn get_action(state: &mut FuzzerState, t: &Tick, rng: *mut c_void) -> Option
                         action: TradingAction::MarketClose{
                             uuid: *uuid,
                             size: unsafe { rand_int_range(rng, 0, pos.size as i32 + 2) } as usize,
+                            reduce_only: false,
                         }
                     }));
                 }
@@ -291,12 +293,13 @@ pub fn handle_pushstream(state: &mut FuzzerState, msg: &BrokerResult, _: *mut c_
                     let _ = ledger.pending_positions.remove(position_id);
                     ledger.open_positions.insert(*position_id, position.clone());
                 },
-                &BrokerMessage::PositionModified{position_id, ref position, timestamp: _} => {
+                &BrokerMessage::PositionModified{position_id, ref position, timestamp: _} |
+                &BrokerMessage::PositionPartiallyClosed{position_id, ref position, ..} => {
                     let ledger = state.get_ledger();
                     assert!(ledger.open_positions.get(&position_id).is_some());
                     ledger.open_positions.insert(position_id, position.clone());
                 },
-                &BrokerMessage::PositionClosed{position_id, ref position, reason: _, timestamp: _} => {
+                &BrokerMessage::PositionClosed{position_id, ref position, ..} => {
                     let ledger = state.get_ledger();
                     ledger.open_positions.remove(&position_id).unwrap();
                     ledger.closed_positions.insert(position_id, position.clone());
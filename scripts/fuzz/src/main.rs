@@ -29,6 +29,9 @@ impl SimbrokerDriver {
             // manually drive progress on the inner event loop by abusing our custom message functionality
             client_msg_count = manager.helper.broker.tick_sim_loop(client_res_count, &mut buffer);
             client_res_count = 0;
+            if manager.helper.broker.is_finished() {
+                break;
+            }
 
             for i in 0..client_msg_count {
                 let response = match &buffer[i] {
@@ -1,6 +1,7 @@
 //! Holds definitions of the internal representations of trading objects and
 //! abstractions for messages sent and received to brokers.
 
+use std::cmp;
 use std::collections::HashMap;
 
 use uuid::Uuid;
@@ -9,7 +10,7 @@ use trading::trading_condition::{TradingAction};
 use trading::broker::*;
 
 /// An account
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Account {
     pub uuid: Uuid,
     pub ledger: Ledger,
@@ -35,7 +36,9 @@ pub enum BrokerAction {
 pub enum BrokerMessage {
     Success,
     Failure,
-    Notice,
+    Notice{message: String},
+    /// All of the push messages generated at a single timestamp, in the order they were generated
+    Batch(Vec<BrokerResult>),
     LedgerBalanceChange{
         account_uuid: Uuid,
         new_buying_power: usize,
@@ -64,6 +67,8 @@ pub enum BrokerMessage {
         position_id: Uuid,
         position: Position,
         reason: PositionClosureReason,
+        /// The profit or loss realized by the close, not including fees
+        realized_pnl: i64,
         timestamp: u64,
     },
     PositionModified{
@@ -71,6 +76,17 @@ pub enum BrokerMessage {
         position: Position,
         timestamp: u64,
     },
+    /// Part of a position was closed; `position` is what remains open
+    PositionPartiallyClosed{
+        position_id: Uuid,
+        position: Position,
+        closed_size: usize,
+        /// The profit or loss realized on the closed units, not including fees
+        realized_pnl: i64,
+        /// The profit or loss of the remaining units at the price of the close
+        unrealized_pnl: i64,
+        timestamp: u64,
+    },
     Pong{time_received: u64},
     AccountListing{accounts: Vec<Account>},
     Ledger{ledger: Ledger},
@@ -87,12 +103,53 @@ pub enum BrokerError {
     InvalidModificationAmount,
     InvalidStopValue,
     InvalidTakeProfitValue,
+    /// The position's take profit is on the same side of its stop as a loss would be
+    InvertedBracket{stop: usize, take_profit: usize},
     ExitWithoutEntry,
     MissingExecutionData,
     MissingExitData,
     InvalidExecutionTime,
     InvalidExitTime,
     NoDataAvailable,
+    /// Trading in the symbol is halted
+    SymbolHalted{symbol: String},
+    /// The spread of the symbol is wider than the broker allows orders to be executed at
+    SpreadTooWide{symbol: String, spread: usize, max_spread: usize},
+    /// The symbol hasn't received enough data for positions to be opened in it yet
+    WarmingUp{symbol: String},
+    /// The action took longer than the broker allows to be executed
+    StaleAction{delay_ns: u64},
+    /// The simulation has run out of data or the client has disconnected so no more actions can be executed
+    SimulationEnded,
+    /// The price moved too far between the submission and execution of a market order; contains the new price
+    Requote{price: usize},
+    /// Short positions can't be opened in the symbol
+    ShortingDisabled{symbol: String},
+    /// The last price of the symbol needed for a currency conversion is older than the broker allows
+    StaleRate{symbol: String, age_ns: u64},
+    /// A size computed for the symbol isn't a whole number of lots
+    FractionalLot{symbol: String},
+    /// The account already has as many open positions and pending orders as the broker allows
+    MaxPositionsReached{max_positions: usize},
+    /// The account already has as many pending orders as the broker allows
+    MaxPendingOrdersReached{max_pending_orders: usize},
+    /// The action was submitted too soon after the previous one; contains how much longer to wait before retrying
+    RateLimited{wait_ns: u64},
+    /// A position UUID is present in more than one of a ledger's position maps
+    AmbiguousPosition{uuid: Uuid},
+    /// The order is larger than the available liquidity of the symbol allows; contains the largest size that does
+    InsufficientLiquidity{symbol: String, max_size: usize},
+    /// The position would hold more units than the broker allows for a single position in the symbol
+    PositionTooLarge{symbol: String, max_position_size: usize},
+    /// The position has already been closed, for example by its stop before the action reached the broker
+    PositionAlreadyClosed,
+    /// Trading has been disabled for the account; positions can still be closed but not opened or modified
+    TradingDisabled{account_uuid: Uuid},
+    /// The action is dated before the current time of the simulation, meaning that it was decided on with knowledge
+    /// of data that wouldn't have been available yet
+    LookaheadBias{action_timestamp: u64, current_timestamp: u64},
+    /// New positions can't be opened in the symbol after its cutoff time; existing ones can still be managed
+    PastOpenCutoff{symbol: String, cutoff: u64},
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -107,12 +164,18 @@ pub enum PositionClosureReason {
 
 /// The platform's internal representation of the current state of an account.
 /// Contains information about past trades as well as current positions.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Ledger {
     pub buying_power: usize,
     pub pending_positions: HashMap<Uuid, Position>,
     pub open_positions: HashMap<Uuid, Position>,
     pub closed_positions: HashMap<Uuid, Position>,
+    /// The buying power the ledger was created with
+    pub starting_balance: usize,
+    /// The net profit or loss that has been realized including all fees charged
+    pub realized_pnl: i64,
+    /// The funds reserved by each open position and pending order
+    pub reserved_margin: HashMap<Uuid, usize>,
 }
 
 impl Ledger {
@@ -122,15 +185,103 @@ impl Ledger {
             pending_positions: HashMap::new(),
             open_positions: HashMap::new(),
             closed_positions: HashMap::new(),
+            starting_balance: starting_balance,
+            realized_pnl: 0,
+            reserved_margin: HashMap::new(),
+        }
+    }
+
+    /// Returns the total margin reserved by the ledger's open positions and pending orders.  The ledger's buying
+    /// power is its free margin.
+    pub fn used_margin(&self) -> usize {
+        self.reserved_margin.values().sum()
+    }
+
+    /// Recomputes what the buying power should be from the starting balance, the realized profit and loss, and the
+    /// margin reserved by open positions and pending orders.  Returns an error describing the difference if it
+    /// doesn't match the stored buying power.
+    pub fn verify_balance(&self) -> Result<(), String> {
+        let reserved = self.used_margin();
+        let expected = self.starting_balance as i64 + self.realized_pnl - reserved as i64;
+        if expected != self.buying_power as i64 {
+            return Err(format!(
+                "Buying power is {} but should be {} (starting balance: {}, realized PnL: {}, reserved margin: {})",
+                self.buying_power, expected, self.starting_balance, self.realized_pnl, reserved
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the error for an action on an open position with the supplied UUID that isn't open:
+    /// `PositionAlreadyClosed` if it has been closed and `NoSuchPosition` otherwise.
+    pub fn missing_position_error(&self, uuid: Uuid) -> BrokerError {
+        if self.closed_positions.contains_key(&uuid) {
+            BrokerError::PositionAlreadyClosed
+        } else {
+            BrokerError::NoSuchPosition
         }
     }
 
+    /// Looks up a position by UUID in all of the ledger's position maps, returning an error if it's missing or if
+    /// it's present in more than one of them.
+    pub fn find_position(&self, uuid: Uuid) -> Result<&Position, BrokerError> {
+        let mut found = None;
+        for map in &[&self.pending_positions, &self.open_positions, &self.closed_positions] {
+            if let Some(pos) = map.get(&uuid) {
+                if found.is_some() {
+                    return Err(BrokerError::AmbiguousPosition{uuid: uuid});
+                }
+                found = Some(pos);
+            }
+        }
+
+        match found {
+            Some(pos) => Ok(pos),
+            None => Err(BrokerError::NoSuchPosition),
+        }
+    }
+
+    /// Makes sure that no position UUID is present in more than one of the pending, open, and closed maps.
+    pub fn verify_positions(&self) -> Result<(), BrokerError> {
+        for uuid in self.pending_positions.keys().chain(self.open_positions.keys()) {
+            self.find_position(*uuid)?;
+        }
+
+        Ok(())
+    }
+
+    /// Deducts funds reserved as margin for the position or order with the supplied UUID from the buying power,
+    /// flooring it at zero.  Returns the amount that was actually reserved.
+    pub fn reserve_margin(&mut self, uuid: Uuid, amount: usize) -> usize {
+        let reserved = cmp::min(amount, self.buying_power);
+        self.buying_power -= reserved;
+        *self.reserved_margin.entry(uuid).or_insert(0) += reserved;
+        reserved
+    }
+
+    /// Deducts a fee from the buying power, flooring it at zero.  Returns the amount that was actually charged.
+    pub fn charge_fee(&mut self, fee: usize) -> usize {
+        let charged = cmp::min(fee, self.buying_power);
+        self.buying_power -= charged;
+        self.realized_pnl -= charged as i64;
+        charged
+    }
+
+    /// Adds profit or loss realized without closing any positions, such as by daily settlement, to the buying
+    /// power, flooring it at zero.
+    pub fn realize_pnl(&mut self, pnl: i64) {
+        let new_buying_power = cmp::max(self.buying_power as i64 + pnl, 0) as usize;
+        self.realized_pnl += new_buying_power as i64 - self.buying_power as i64;
+        self.buying_power = new_buying_power;
+    }
+
     /// Attempts to open a pending position in the ledger with the supplied position.
     pub fn place_order(&mut self, pos: Position, position_value: usize, uuid: Uuid) -> BrokerResult {
         if position_value > self.buying_power {
             return Err(BrokerError::InsufficientBuyingPower)
         }
-        self.buying_power -= position_value;
+        self.reserve_margin(uuid, position_value);
         self.pending_positions.insert(uuid, pos.clone());
         let creation_time = pos.creation_time;
         Ok(BrokerMessage::OrderPlaced{
@@ -167,11 +318,17 @@ impl Ledger {
     pub fn cancel_order(&mut self, uuid: Uuid, timestamp: u64) -> BrokerResult {
         // try to remove the pending order from the pending `HashMap`
         match self.pending_positions.remove(&uuid) {
-            Some(order) => Ok(BrokerMessage::OrderCancelled{
-                order: order,
-                order_id: uuid,
-                timestamp: timestamp,
-            }),
+            Some(order) => {
+                // release the funds that were reserved for the order
+                if let Some(margin) = self.reserved_margin.remove(&uuid) {
+                    self.buying_power += margin;
+                }
+                Ok(BrokerMessage::OrderCancelled{
+                    order: order,
+                    order_id: uuid,
+                    timestamp: timestamp,
+                })
+            },
             None => Err(BrokerError::NoSuchPosition),
         }
     }
@@ -187,6 +344,7 @@ impl Ledger {
         }
 
         self.open_positions.insert(uuid, pos.clone());
+        debug_assert_eq!(self.verify_positions(), Ok(()));
         Ok(BrokerMessage::PositionOpened{
             position_id: uuid,
             position: pos,
@@ -196,24 +354,27 @@ impl Ledger {
 
     /// Completely closes the specified condition at the given price, crediting the account the
     /// funds yielded.  Timestamp is the time the order was submitted + any simulated delays.
+    /// `realized_pnl` is only reported in the closure message; it should already be included in `position_value`.
     pub fn close_position(
-        &mut self, uuid: Uuid, position_value: usize, timestamp: u64, reason: PositionClosureReason
+        &mut self, uuid: Uuid, position_value: usize, realized_pnl: i64, timestamp: u64, reason: PositionClosureReason
     ) -> BrokerResult {
-        let pos_opt = self.open_positions.remove(&uuid);
-        match pos_opt {
-            Some(ref pos) => {
-                self.closed_positions.insert(uuid, pos.clone());
-            },
-            None => {
-                return Err(BrokerError::NoSuchPosition)
-            },
-        }
+        let mut pos = match self.open_positions.remove(&uuid) {
+            Some(pos) => pos,
+            None => return Err(self.missing_position_error(uuid)),
+        };
+        pos.realized_pnl = Some(pos.realized_pnl.unwrap_or(0) + realized_pnl);
+        self.closed_positions.insert(uuid, pos.clone());
+        debug_assert_eq!(self.verify_positions(), Ok(()));
+        // whatever is credited beyond the margin that was reserved for the position is profit and vice versa
+        let released = self.reserved_margin.remove(&uuid).unwrap_or(0);
+        self.realized_pnl += position_value as i64 - released as i64;
         self.buying_power += position_value;
 
         Ok(BrokerMessage::PositionClosed{
-            position: pos_opt.unwrap(),
+            position: pos,
             position_id: uuid,
             reason: reason,
+            realized_pnl: realized_pnl,
             timestamp: timestamp,
         })
     }
@@ -221,24 +382,45 @@ impl Ledger {
     /// Increases or decreases the size of the specified position by the given amount.  Returns errors
     /// if the account doesn't have enough buying power to execute the action or if a position with
     /// the specified UUID doesn't exist.
-    pub fn resize_position(&mut self, uuid: Uuid, units: isize, modification_cost: usize, timestamp: u64) -> BrokerResult {
-        let mut pos = self.open_positions.remove(&uuid)
-            .expect("No position found with that UUID; should have caught this earlier.");
+    ///
+    /// If the position is shrunk, `modification_cost` is the value freed by the removed units and is credited
+    /// to the account.  If it grows, `modification_cost` is the value of the added units and is debited.
+    /// `realized_pnl` is the profit or loss realized by removed units, reported if the position is fully closed and
+    /// added to the position's `realized_pnl` either way.
+    pub fn resize_position(
+        &mut self, uuid: Uuid, units: isize, modification_cost: usize, realized_pnl: i64, timestamp: u64
+    ) -> BrokerResult {
+        // leave the position in the `HashMap` until we know the modification is valid
+        let mut pos = match self.open_positions.get(&uuid) {
+            Some(pos) => pos.clone(),
+            None => return Err(self.missing_position_error(uuid)),
+        };
 
         let unit_diff = units + (pos.size as isize);
         if unit_diff < 0 {
             return Err(BrokerError::InvalidModificationAmount);
         } else if unit_diff == 0 {
-            return self.close_position(uuid, modification_cost, timestamp, PositionClosureReason::MarketClose);
+            return self.close_position(uuid, modification_cost, realized_pnl, timestamp, PositionClosureReason::MarketClose);
         }
 
-        if self.buying_power < modification_cost {
+        if units < 0 {
+            // release the removed units' share of the position's reserved margin
+            let reserved = self.reserved_margin.get(&uuid).cloned().unwrap_or(0);
+            let released = reserved - reserved * (unit_diff as usize) / pos.size;
+            if reserved != 0 {
+                self.reserved_margin.insert(uuid, reserved - released);
+            }
+            self.realized_pnl += modification_cost as i64 - released as i64;
+            self.buying_power += modification_cost;
+            pos.realized_pnl = Some(pos.realized_pnl.unwrap_or(0) + realized_pnl);
+        } else if self.buying_power < modification_cost {
             return Err(BrokerError::InsufficientBuyingPower);
+        } else {
+            self.reserve_margin(uuid, modification_cost);
         }
 
         // everything seems to be in order, so do the modification
-        pos.size = ((pos.size as isize) + units) as usize;
-        self.buying_power -= modification_cost;
+        pos.size = unit_diff as usize;
         self.open_positions.insert(uuid, pos.clone());
 
         Ok(BrokerMessage::PositionModified{
@@ -252,29 +434,39 @@ impl Ledger {
     pub fn modify_position(
         &mut self, pos_uuid: Uuid, sl: Option<Option<usize>>, tp: Option<Option<usize>>, timestamp: u64
     ) -> BrokerResult {
-        match self.open_positions.get_mut(&pos_uuid) {
-            Some(pos) => {
-                if sl.is_some() {
-                    pos.stop = sl.unwrap();
-                }
-                if tp.is_some() {
-                    pos.take_profit = tp.unwrap();
-                }
-                Ok(BrokerMessage::PositionModified{
-                    position: pos.clone(),
-                    position_id: pos_uuid,
-                    timestamp: timestamp,
-                })
-            },
-            None => {
-                Err(BrokerError::NoSuchPosition)
-            },
+        // the position is borrowed for the whole `if let`, so the error is built after it
+        if let Some(pos) = self.open_positions.get_mut(&pos_uuid) {
+            if sl.is_some() {
+                pos.stop = sl.unwrap();
+            }
+            if tp.is_some() {
+                pos.take_profit = tp.unwrap();
+            }
+            return Ok(BrokerMessage::PositionModified{
+                position: pos.clone(),
+                position_id: pos_uuid,
+                timestamp: timestamp,
+            });
         }
+
+        Err(self.missing_position_error(pos_uuid))
     }
 }
 
+/// How long a pending order remains active before it is cancelled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeInForce {
+    /// Fill whatever can be filled immediately and cancel the rest (IOC).  The SimBroker always fills marketable
+    /// orders in full, so there it's all-or-nothing: the whole order is either filled or cancelled.
+    ImmediateOrCancel,
+    /// Rest on the book until filled or manually cancelled (GTC)
+    GoodTillCancelled,
+    /// Rest on the book until filled or the contained timestamp is reached (GTD)
+    GoodTillDate(u64),
+}
+
 /// Represents an opened, closed, or pending position on a broker.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Position {
     pub creation_time: u64,
     pub symbol_id: usize,
@@ -291,9 +483,27 @@ pub struct Position {
     pub exit_price: Option<usize>,
     /// the time the position was actually closed
     pub exit_time: Option<u64>,
+    /// how long the position remains pending before being cancelled if it isn't filled
+    pub time_in_force: TimeInForce,
+    /// trading costs such as commission that have been charged for the position in units of base currency
+    pub fees: usize,
+    /// the profit or loss realized by the units of the position that have been closed so far as it was reported,
+    /// not including fees.  `None` until some of the position has been closed.
+    pub realized_pnl: Option<i64>,
+    /// the distance behind the best price reached that the stop follows the market at, if it trails
+    pub trailing_stop: Option<usize>,
+    /// the best price the position could have been closed at since its stop started trailing; the high-water mark
+    /// of long positions and the low-water mark of short ones
+    pub best_price: Option<usize>,
 }
 
 impl Position {
+    /// Returns the profit or loss realized by closing the position or part of it, not including fees, or `None` if
+    /// none of it has been closed.
+    pub fn pnl(&self) -> Option<i64> {
+        self.realized_pnl
+    }
+
     /// Returns the price the position would execute at if the prices are at levels such that the position
     /// can open, else returns None.
     pub fn is_open_satisfied(&self, bid: usize, ask: usize) -> Option<usize> {
@@ -304,7 +514,7 @@ impl Position {
 
         if self.long && ask <= self.price.unwrap() {
             return Some(ask);
-        } else if bid >= self.price.unwrap() {
+        } else if !self.long && bid >= self.price.unwrap() {
             return Some(bid);
         }
 
@@ -313,22 +523,34 @@ impl Position {
 
     /// Returns the price the position would execute at if the position meets
     /// the conditions for closure and the reason for its closure, else returns None.
+    ///
+    /// If `touch_triggers` is set, prices that are exactly equal to the stop or take profit trigger them.
+    /// Otherwise, prices must strictly cross them.
     #[allow(collapsible_if)]
-    pub fn is_close_satisfied(&self, bid: usize, ask: usize) -> Option<(usize, PositionClosureReason)> {
+    pub fn is_close_satisfied(&self, bid: usize, ask: usize, touch_triggers: bool) -> Option<(usize, PositionClosureReason)> {
         // only meant to be used for open positions
         assert!(self.execution_price.is_some());
         assert!(self.exit_price.is_none());
 
+        // returns true if `price` has reached `level` from above if `falling` is set or from below otherwise
+        let reached = |price: usize, level: usize, falling: bool| {
+            if price == level {
+                touch_triggers
+            } else {
+                (price < level) == falling
+            }
+        };
+
         if self.long {
-            if self.stop.is_some() && self.stop.unwrap() >= bid {
+            if self.stop.is_some() && reached(bid, self.stop.unwrap(), true) {
                 return Some( (bid, PositionClosureReason::StopLoss) );
-            } else if self.take_profit.is_some() && self.take_profit.unwrap() <= ask {
-                return Some( (ask, PositionClosureReason::StopLoss) );
+            } else if self.take_profit.is_some() && reached(ask, self.take_profit.unwrap(), false) {
+                return Some( (ask, PositionClosureReason::TakeProfit) );
             }
         } else {
-            if self.stop.is_some() && self.stop.unwrap() <= ask {
-                return Some( (ask, PositionClosureReason::TakeProfit) );
-            } else if self.take_profit.is_some() && self.take_profit.unwrap() >= bid {
+            if self.stop.is_some() && reached(ask, self.stop.unwrap(), false) {
+                return Some( (ask, PositionClosureReason::StopLoss) );
+            } else if self.take_profit.is_some() && reached(bid, self.take_profit.unwrap(), true) {
                 return Some( (bid, PositionClosureReason::TakeProfit) );
             }
         }
@@ -336,10 +558,31 @@ impl Position {
         None
     }
 
+    /// Returns `true` if the position is a pending order whose time in force has elapsed as of the supplied timestamp.
+    pub fn is_expired(&self, timestamp: u64) -> bool {
+        match self.time_in_force {
+            TimeInForce::GoodTillDate(expiry) => self.execution_price.is_none() && timestamp >= expiry,
+            _ => false,
+        }
+    }
+
     /// Verifies the values of a position to make sure that they make sense.  For example, the stop should
     /// not be larger than the entry price if we're long, there should be no exit price if there's no entry
     /// price, etc.
     pub fn check_sanity(&self) -> Result<(), BrokerError> {
+        self.check_bracket()?;
+        self.check_execution_data()
+    }
+
+    /// Verifies that the stop and take profit of the position are on the correct sides of its entry price and
+    /// each other: stop < entry < take profit for longs and the reverse for shorts.
+    pub fn check_bracket(&self) -> Result<(), BrokerError> {
+        if let (Some(stop), Some(tp)) = (self.stop, self.take_profit) {
+            if (self.long && tp <= stop) || (!self.long && tp >= stop) {
+                return Err(BrokerError::InvertedBracket{stop: stop, take_profit: tp});
+            }
+        }
+
         // check validity of stop/take profit values if they exist.
         if self.price.is_some() {
             let price = *self.price.as_ref().unwrap();
@@ -366,6 +609,11 @@ impl Position {
             };
         }
 
+        Ok(())
+    }
+
+    /// Verifies that the execution and exit data of the position are consistent with each other.
+    pub fn check_execution_data(&self) -> Result<(), BrokerError> {
         // make sure that the position doesn't have an exit price unless it has an execution price.
         if self.execution_price.is_none() && self.exit_price.is_some() {
             return Err(BrokerError::ExitWithoutEntry);
@@ -404,3 +652,35 @@ impl Position {
         Ok(())
     }
 }
+
+/// Stops and take profits of both long and short positions should close them at the correct price and with the
+/// correct reason.
+#[test]
+fn close_reasons() {
+    let pos = |long: bool| Position {
+        creation_time: 0,
+        symbol_id: 0,
+        size: 1,
+        price: Some(10000),
+        long: long,
+        stop: Some(if long { 9900 } else { 10100 }),
+        take_profit: Some(if long { 10100 } else { 9900 }),
+        execution_time: Some(0),
+        execution_price: Some(10000),
+        exit_price: None,
+        exit_time: None,
+        time_in_force: TimeInForce::GoodTillCancelled,
+        fees: 0,
+        realized_pnl: None,
+        trailing_stop: None,
+        best_price: None,
+    };
+    let long = pos(true);
+    let short = pos(false);
+
+    assert_eq!(long.is_close_satisfied(9950, 9952, false), None);
+    assert_eq!(long.is_close_satisfied(9899, 9901, false), Some((9899, PositionClosureReason::StopLoss)));
+    assert_eq!(long.is_close_satisfied(10099, 10101, false), Some((10101, PositionClosureReason::TakeProfit)));
+    assert_eq!(short.is_close_satisfied(10099, 10101, false), Some((10101, PositionClosureReason::StopLoss)));
+    assert_eq!(short.is_close_satisfied(9899, 9901, false), Some((9899, PositionClosureReason::TakeProfit)));
+}
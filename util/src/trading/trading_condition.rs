@@ -4,6 +4,7 @@
 use uuid::Uuid;
 
 use trading::tick::Tick;
+use trading::objects::TimeInForce;
 
 pub trait TradingCondition {
     /// Evaulate a new Tick with the condition.  Returns a TradingAction to take or None.
@@ -17,13 +18,34 @@ pub enum TradingAction {
         symbol: String, long: bool, size: usize, stop: Option<usize>,
         take_profit: Option<usize>, max_range: Option<usize>,
     },
-    /// Opens an order at a price equal or better to `entry_price` as soon as possible.
+    /// Opens an order at market price +-max_range pips with a size that uses `pct` percent of the account's
+    /// buying power.
+    MarketOrderPct {
+        symbol: String, long: bool, pct: f64, stop: Option<usize>,
+        take_profit: Option<usize>, max_range: Option<usize>,
+    },
+    /// Opens an order at market price +-max_range pips with a size given as an `amount` of the symbol's quote
+    /// currency rather than as a number of units.  `amount` is expressed in the same terms as position values, so
+    /// one unit of a symbol is worth its price times its lot size or contract multiplier.
+    MarketOrderQuote {
+        symbol: String, long: bool, amount: usize, stop: Option<usize>,
+        take_profit: Option<usize>, max_range: Option<usize>,
+    },
+    /// Opens an order at a price equal or better to `entry_price` as soon as possible.  The order stays
+    /// pending for as long as `time_in_force` allows.
     LimitOrder{
         symbol: String, long: bool, size: usize, stop: Option<usize>,
-        take_profit: Option<usize>, entry_price: usize,
+        take_profit: Option<usize>, entry_price: usize, time_in_force: TimeInForce,
     },
-    /// Closes `size` units of a position with the specified UUID at the current market rate.
-    MarketClose{ uuid: Uuid, size: usize, },
+    /// Places an order whose trigger price is pegged `offset` pips from the market and follows it as it moves in
+    /// the order's favor: above the lowest ask for buys and below the highest bid for sells.  It's filled at
+    /// market once the market moves back through the trigger price.
+    PeggedOrder{ symbol: String, long: bool, size: usize, offset: usize },
+    /// Closes `size` units of a position with the specified UUID at the current market rate.  If `reduce_only`
+    /// is set, a `size` larger than the position is clamped to the position's size instead of being rejected.
+    MarketClose{ uuid: Uuid, size: usize, reduce_only: bool },
+    /// Closes `pct` percent of the units of a position with the specified UUID at the current market rate.
+    MarketClosePct{ uuid: Uuid, pct: f64 },
     /// Places an order to close `size` units of a position with the specified UUID.
     LimitClose{ uuid: Uuid, size: usize, exit_price: usize, },
     /// Modifies an order without taking any trading action
@@ -32,4 +54,10 @@ pub enum TradingAction {
     ModifyPosition{ uuid: Uuid, stop: Option<usize>, take_profit: Option<usize> },
     /// Attempts to cancel an order
     CancelOrder{ uuid: Uuid },
+    /// Opens a position as of a past `timestamp` at the market price at that time.  Used to import existing trades
+    /// into simulations; only supported by the SimBroker.
+    BackfillOrder {
+        symbol: String, long: bool, size: usize, stop: Option<usize>,
+        take_profit: Option<usize>, timestamp: u64,
+    },
 }
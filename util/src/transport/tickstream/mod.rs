@@ -7,8 +7,9 @@ use std::sync::atomic::{Ordering, AtomicBool};
 #[allow(unused_imports)]
 use test;
 
+use futures::Stream;
 use futures::sync::mpsc::UnboundedReceiver;
-use futures::stream::BoxStream;
+use futures::stream::{self, BoxStream};
 
 use trading::tick::Tick;
 use transport::redis::get_client as get_redis_client;
@@ -176,6 +177,46 @@ pub fn spawn_listener_thread(
     });
 }
 
+/// Replays recorded ticks through a callback without a broker, for quickly validating signal logic.  The callback is
+/// invoked once per tick in order with a mutable reference to `state`, which is returned once the ticks run out.
+pub fn replay_ticks<S, F>(ticks: Vec<Tick>, state: S, f: F) -> S where F: FnMut(&mut S, &Tick) {
+    let strm = stream::iter(ticks.into_iter().map(|t| Ok::<Tick, ()>(t))).boxed();
+    // the stream can't produce errors
+    replay_tickstream(strm, state, f).unwrap()
+}
+
+/// Same as `replay_ticks` but reads the ticks from a tickstream such as one returned by `TickGenerator::get_raw`,
+/// blocking until it ends.  Returns an error if the stream does.
+pub fn replay_tickstream<S, F>(
+    strm: BoxStream<Tick, ()>, mut state: S, mut f: F
+) -> Result<S, ()> where F: FnMut(&mut S, &Tick) {
+    for tick_res in strm.wait() {
+        let tick = tick_res?;
+        f(&mut state, &tick);
+    }
+
+    Ok(state)
+}
+
+/// Computes a simple moving average of tick midpoints by replaying ticks through a callback.
+#[test]
+fn replay_ticks_sma() {
+    let ticks: Vec<Tick> = [10, 20, 30, 40, 50].iter().enumerate()
+        .map(|(i, &price)| Tick {bid: price - 1, ask: price + 1, timestamp: i as u64})
+        .collect();
+
+    let period = 3;
+    let (_, smas) = replay_ticks(ticks, (Vec::new(), Vec::new()), |state: &mut (Vec<usize>, Vec<usize>), t| {
+        state.0.push(t.mid());
+        if state.0.len() >= period {
+            let window = &state.0[state.0.len() - period..];
+            state.1.push(window.iter().sum::<usize>() / period);
+        }
+    });
+
+    assert_eq!(smas, vec![20, 30, 40]);
+}
+
 /// See how fast we can check the value of the atomic bool
 #[bench]
 fn mail_check_no_messages(b: &mut test::Bencher) {